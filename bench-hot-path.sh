@@ -0,0 +1,14 @@
+#!/bin/bash
+
+export RUSTFLAGS="-Awarnings"
+
+# Exits with a failure status if the per-thread access path of ThreadMap regresses relative to ThreadLocal.
+# Set FEATURES=parking_lot to benchmark the parking_lot backend.
+
+echo "----- Per-thread access path benchmark -- Started: `date +"%Y-%m-%d at %H:%M:%S"` -----"
+echo
+
+cargo bench --bench hot_path_bench --target-dir target/bench-target --features "$FEATURES"
+
+echo
+echo "Finished at: `date +"%H:%M:%S"`"
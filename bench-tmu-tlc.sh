@@ -3,11 +3,12 @@
 export RUSTFLAGS="-Awarnings"
 
 # $1: number of repetitions 
+# Set FEATURES=parking_lot to benchmark the parking_lot backend.
 
 echo "----- ThreadMap to ThreadLocal comparison -- Started: `date +"%Y-%m-%d at %H:%M:%S"` -----"
 echo
 
-cargo bench --bench tmu_tlc_bench --target-dir target/bench-target
+cargo bench --bench tmu_tlc_bench --target-dir target/bench-target --features "$FEATURES"
 
 echo
 echo "Finished at: `date +"%H:%M:%S"`"
@@ -0,0 +1,101 @@
+//! Benchmark of the latency of the per-thread access path, i.e., `with_mut` on a value that is already registered,
+//! relative to `thread_local::ThreadLocal`. Exits with a failure status if the ratio of the latency of `ThreadMap` to
+//! that of `ThreadLocal` exceeds `MAX_RATIO`, so that large regressions of the per-thread access path are caught.
+//! Smaller regressions are found by comparing the printed ratios with those recorded in the crate documentation.
+//!
+//! Latencies are the minimum, over `NREPEATS` repetitions, of the mean latency of `NITER` calls, which filters out
+//! most of the noise from other processes. Ratios rather than absolute latencies are checked, so that the check does
+//! not depend on the speed of the machine.
+
+use std::{hint::black_box, process, sync::Mutex, thread, time::Instant};
+use thread_local::ThreadLocal;
+use thread_map::{AdaptiveThreadMap, ThreadMap, ThreadMapApi, ThreadMapX};
+
+const NTHREADS: u32 = 5;
+const NITER: u32 = 1_000_000;
+const NREPEATS: u32 = 40;
+
+/// Maximum ratio of the latency of `ThreadMap` to that of `ThreadLocal`, single-threaded and with `NTHREADS` threads.
+/// The measured ratios are 1.3-1.5, so the bound leaves room for the noise of shared machines while still failing if,
+/// e.g., the current thread's value is again looked up by hashing its `ThreadId` on every access.
+const MAX_RATIO: f64 = 2.0;
+
+type Tl = ThreadLocal<Mutex<(i32, i32)>>;
+
+fn update_value((i0, v0): &mut (i32, i32)) {
+    *i0 = black_box(1);
+    *v0 += black_box(1);
+}
+
+fn tl_with_mut(tl: &Tl) {
+    let cell = tl.get_or(|| Mutex::new((0, 0)));
+    update_value(&mut cell.lock().unwrap());
+}
+
+/// Returns the mean latency in nanoseconds of `f` called `NITER` times on each of `nthreads` threads.
+fn mean_latency(nthreads: u32, f: &(impl Fn() + Sync)) -> f64 {
+    let per_thread = || {
+        for _ in 0..NITER / nthreads {
+            f();
+        }
+    };
+    let start = Instant::now();
+    if nthreads == 1 {
+        per_thread();
+    } else {
+        thread::scope(|s| {
+            for _ in 0..nthreads {
+                s.spawn(per_thread);
+            }
+        });
+    }
+    start.elapsed().as_nanos() as f64 / NITER as f64
+}
+
+/// Returns the minimum latencies in nanoseconds of `f1` and `f2`, whose measurements are interleaved so that both are
+/// equally affected by changes in the load of the machine.
+fn min_latencies(nthreads: u32, f1: impl Fn() + Sync, f2: impl Fn() + Sync) -> (f64, f64) {
+    let (mut l1, mut l2) = (f64::MAX, f64::MAX);
+    for _ in 0..NREPEATS {
+        l1 = l1.min(mean_latency(nthreads, &f1));
+        l2 = l2.min(mean_latency(nthreads, &f2));
+    }
+    (l1, l2)
+}
+
+/// Prints the latencies of `tm` and `ThreadLocal` and returns their ratio.
+fn compare(type_name: &str, nthreads: u32, tm: impl ThreadMapApi<(i32, i32)> + Sync) -> f64 {
+    let tl = Tl::new();
+    let (l_tm, l_tl) = min_latencies(nthreads, || tm.with_mut(update_value), || tl_with_mut(&tl));
+    let ratio = l_tm / l_tl;
+    println!(
+        "nthreads={nthreads}: {type_name}={l_tm:.1}ns, ThreadLocal={l_tl:.1}ns, ratio={ratio:.2}"
+    );
+    ratio
+}
+
+fn main() {
+    println!(
+        "Params: NTHREADS={NTHREADS}, NITER={NITER}, NREPEATS={NREPEATS}, MAX_RATIO={MAX_RATIO}"
+    );
+    println!();
+
+    let mut regressions = Vec::new();
+    for nthreads in [1, NTHREADS] {
+        let ratio = compare("ThreadMap", nthreads, ThreadMap::default());
+        if ratio > MAX_RATIO {
+            regressions.push(format!("nthreads={nthreads}: ratio={ratio:.2}"));
+        }
+        compare("ThreadMapX", nthreads, ThreadMapX::default());
+        compare("AdaptiveThreadMap", nthreads, AdaptiveThreadMap::default());
+        println!();
+    }
+
+    if !regressions.is_empty() {
+        eprintln!(
+            "ThreadMap/ThreadLocal latency ratio exceeds MAX_RATIO={MAX_RATIO}: {}",
+            regressions.join("; ")
+        );
+        process::exit(1);
+    }
+}
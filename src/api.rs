@@ -0,0 +1,155 @@
+use crate::{ShardedThreadMap, SlotStrategy, ThreadMapBase, ThreadMapLockError, ValueKey};
+use lock_api::{RawMutex, RawRwLock};
+use std::{collections::HashMap, hash::BuildHasher, thread::ThreadId};
+
+/// Common API implemented by [`ThreadMap`](crate::ThreadMap), [`ThreadMapX`](crate::ThreadMapX), [`AdaptiveThreadMap`](crate::AdaptiveThreadMap), [`ShardedThreadMap`], and [`LockFreeThreadMap`](crate::LockFreeThreadMap), enabling
+/// code that is generic over these types.
+/// Applications can then choose the implementation that best fits their sweep pattern (see [`ThreadMapX`](crate::ThreadMapX)), or
+/// let [`AdaptiveThreadMap`](crate::AdaptiveThreadMap) choose at runtime.
+///
+/// The methods of this trait have the same semantics as the identically named inherent methods of the implementing
+/// types.
+///
+/// # Example
+///
+/// ```rust
+/// use std::thread;
+/// use thread_map::{
+///     AdaptiveThreadMap, LockFreeThreadMap, ShardedThreadMap, ThreadMap, ThreadMapApi, ThreadMapX,
+/// };
+///
+/// fn count_in_threads(tm: &(impl ThreadMapApi<i32> + Sync)) -> i32 {
+///     thread::scope(|s| {
+///         for _ in 0..4 {
+///             s.spawn(|| tm.with_mut(|v| *v += 1));
+///         }
+///     });
+///     tm.fold_values(0, |z, v| z + v).unwrap()
+/// }
+///
+/// assert_eq!(4, count_in_threads(&ThreadMap::default()));
+/// assert_eq!(4, count_in_threads(&ThreadMapX::default()));
+/// assert_eq!(4, count_in_threads(&AdaptiveThreadMap::default()));
+/// assert_eq!(4, count_in_threads(&ShardedThreadMap::default()));
+/// assert_eq!(4, count_in_threads(&LockFreeThreadMap::default()));
+/// ```
+pub trait ThreadMapApi<V> {
+    /// Invokes `f` mutably on the value associated with the [`ThreadId`] of the current thread and returns the
+    /// invocation result, initializing the value if needed.
+    ///
+    /// # Panics
+    /// - If an internal lock is poisoned.
+    fn with_mut<W>(&self, f: impl FnOnce(&mut V) -> W) -> W;
+
+    /// Invokes `f` on the value associated with the [`ThreadId`] of the current thread and returns the invocation
+    /// result, initializing the value if needed.
+    ///
+    /// # Panics
+    /// - If an internal lock is poisoned.
+    fn with<W>(&self, f: impl FnOnce(&V) -> W) -> W;
+
+    /// Returns a clone of the value associated with the current thread.
+    ///
+    /// # Panics
+    /// - If an internal lock is poisoned.
+    fn get(&self) -> V
+    where
+        V: Clone;
+
+    /// Sets the value associated with the current thread to `v`.
+    ///
+    /// # Panics
+    /// - If an internal lock is poisoned.
+    fn set(&self, v: V);
+
+    /// Returns a [`HashMap`] with the values associated with each [`ThreadId`] key and clears `self`'s state.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if an internal lock is poisoned.
+    fn drain(&self) -> Result<HashMap<ThreadId, V>, ThreadMapLockError>;
+
+    /// Folds every association in `self` into an accumulator (with initial value `z`) by applying an operation `f`,
+    /// returning the final result. The accumulated value of the retired threads, if any, is folded with the key
+    /// [`ValueKey::Retired`].
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if an internal lock is poisoned.
+    fn fold<W>(&self, z: W, f: impl FnMut(W, (ValueKey, &V)) -> W)
+    -> Result<W, ThreadMapLockError>;
+
+    /// Folds every value in `self` into an accumulator (with initial value `z`) by applying an operation `f`,
+    /// returning the final result.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if an internal lock is poisoned.
+    fn fold_values<W>(&self, z: W, f: impl FnMut(W, &V) -> W) -> Result<W, ThreadMapLockError>;
+
+    /// Returns a [`HashMap`] with clones of the values associated with each [`ThreadId`] key, and of the accumulated
+    /// value of the retired threads, if any, with the key [`ValueKey::Retired`], at the time the probe was executed.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if an internal lock is poisoned.
+    fn probe(&self) -> Result<HashMap<ValueKey, V>, ThreadMapLockError>
+    where
+        V: Clone;
+}
+
+/// Implements [`ThreadMapApi`] for a map type by delegating to its identically named inherent methods. The generic
+/// parameters of the implementation other than `V` are given in brackets before the type.
+macro_rules! impl_thread_map_api {
+    ([$($generics:tt)*] $map:ty) => {
+        impl<V, $($generics)*> ThreadMapApi<V> for $map {
+            fn with_mut<W>(&self, f: impl FnOnce(&mut V) -> W) -> W {
+                self.with_mut(f)
+            }
+
+            fn with<W>(&self, f: impl FnOnce(&V) -> W) -> W {
+                self.with(f)
+            }
+
+            fn get(&self) -> V
+            where
+                V: Clone,
+            {
+                self.get()
+            }
+
+            fn set(&self, v: V) {
+                self.set(v);
+            }
+
+            fn drain(&self) -> Result<HashMap<ThreadId, V>, ThreadMapLockError> {
+                self.drain()
+            }
+
+            fn fold<W>(
+                &self,
+                z: W,
+                f: impl FnMut(W, (ValueKey, &V)) -> W,
+            ) -> Result<W, ThreadMapLockError> {
+                self.fold(z, f)
+            }
+
+            fn fold_values<W>(
+                &self,
+                z: W,
+                f: impl FnMut(W, &V) -> W,
+            ) -> Result<W, ThreadMapLockError> {
+                self.fold_values(z, f)
+            }
+
+            fn probe(&self) -> Result<HashMap<ValueKey, V>, ThreadMapLockError>
+            where
+                V: Clone,
+            {
+                self.probe()
+            }
+        }
+    };
+}
+
+impl_thread_map_api!(
+    [T: SlotStrategy, S: BuildHasher, R: RawRwLock, M: RawMutex]
+    ThreadMapBase<V, T, S, R, M>
+);
+impl_thread_map_api!([] ShardedThreadMap<V>);
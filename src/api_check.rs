@@ -1,32 +1,123 @@
-//! This private module defines the common API for [`ThreadMap`] and [`ThreadMapX`] and ensures both implement the API.
+//! This private module defines the parts of the common API for [`ThreadMap`], [`ThreadMapX`], [`AdaptiveThreadMap`],
+//! [`ShardedThreadMap`], and [`LockFreeThreadMap`] that are not covered by [`ThreadMapApi`] and ensures all of them
+//! implement the API. [`AdaptiveThreadMap`] and [`LockFreeThreadMap`] share their implementation with [`ThreadMap`] and
+//! [`ThreadMapX`] through [`ThreadMapBase`](crate::ThreadMapBase), but are checked explicitly so that a method added only for some slot
+//! strategies is caught.
 
-use crate::{ThreadMap, ThreadMapLockError, ThreadMapX};
-use std::{collections::HashMap, thread::ThreadId};
+use crate::{
+    AdaptiveThreadMap, LockFreeThreadMap, ShardedThreadMap, ThreadInfo, ThreadMap, ThreadMapApi,
+    ThreadMapLockError, ThreadMapX, ValueKey,
+};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    ops::{Deref, DerefMut},
+    thread::ThreadId,
+    time::Duration,
+};
 
 #[allow(unused)]
-trait ApiCheck<V> {
+trait ApiCheck<V>: ThreadMapApi<V> {
     fn new(value_init: fn() -> V) -> Self;
 
-    fn with_mut<W>(&self, f: impl FnOnce(&mut V) -> W) -> W;
+    fn from_fn(value_init: impl Fn() -> V + Send + Sync + 'static) -> Self;
 
-    fn with<W>(&self, f: impl FnOnce(&V) -> W) -> W;
+    fn from_thread_fn(value_init: impl Fn(&ThreadInfo) -> V + Send + Sync + 'static) -> Self;
 
-    fn get(&self) -> V
+    fn on_thread_exit(self, f: impl Fn(ThreadId, V) + Send + Sync + 'static) -> Self
+    where
+        V: Send + 'static;
+
+    fn remove_on_thread_exit(self) -> Self
+    where
+        V: Send + 'static;
+
+    fn retire_on_thread_exit(self, merge: impl Fn(&mut V, V) + Send + Sync + 'static) -> Self
+    where
+        V: Send + 'static;
+
+    fn retired(&self) -> Result<Option<V>, ThreadMapLockError>
     where
         V: Clone;
 
-    fn set(&self, v: V);
+    fn take_retired(&self) -> Result<Option<V>, ThreadMapLockError>;
 
-    fn drain(&self) -> Result<HashMap<ThreadId, V>, ThreadMapLockError>;
+    fn try_with_mut<W>(&self, f: impl FnOnce(&mut V) -> W) -> Result<W, ThreadMapLockError>;
 
-    fn fold<W>(&self, z: W, f: impl FnMut(W, (ThreadId, &V)) -> W)
-        -> Result<W, ThreadMapLockError>;
+    fn try_with<W>(&self, f: impl FnOnce(&V) -> W) -> Result<W, ThreadMapLockError>;
 
-    fn fold_values<W>(&self, z: W, f: impl FnMut(W, &V) -> W) -> Result<W, ThreadMapLockError>;
+    fn try_with_mut_timeout<W>(
+        &self,
+        timeout: Duration,
+        f: impl FnOnce(&mut V) -> W,
+    ) -> Result<W, ThreadMapLockError>;
 
-    fn probe(&self) -> Result<HashMap<ThreadId, V>, ThreadMapLockError>
+    fn try_with_timeout<W>(
+        &self,
+        timeout: Duration,
+        f: impl FnOnce(&V) -> W,
+    ) -> Result<W, ThreadMapLockError>;
+
+    fn try_with_mut_nonblocking<W>(
+        &self,
+        f: impl FnOnce(&mut V) -> W,
+    ) -> Result<W, ThreadMapLockError>;
+
+    fn try_with_nonblocking<W>(&self, f: impl FnOnce(&V) -> W) -> Result<W, ThreadMapLockError>;
+
+    fn try_get(&self) -> Result<V, ThreadMapLockError>
     where
         V: Clone;
+
+    fn try_set(&self, v: V) -> Result<(), ThreadMapLockError>;
+
+    fn current(&self) -> impl Deref<Target = V> + '_;
+
+    fn try_current(&self) -> Result<impl Deref<Target = V> + '_, ThreadMapLockError>;
+
+    fn current_mut(&self) -> impl DerefMut<Target = V> + '_;
+
+    fn try_current_mut(&self) -> Result<impl DerefMut<Target = V> + '_, ThreadMapLockError>;
+
+    fn take(&self) -> Option<V>;
+
+    fn try_take(&self) -> Result<Option<V>, ThreadMapLockError>;
+
+    fn replace(&self, v: V) -> V;
+
+    fn try_replace(&self, v: V) -> Result<V, ThreadMapLockError>;
+
+    fn remove(&self);
+
+    fn try_remove(&self) -> Result<(), ThreadMapLockError>;
+
+    fn reset(&self);
+
+    fn try_reset(&self) -> Result<(), ThreadMapLockError>;
+
+    fn with_thread_mut<W>(
+        &self,
+        tid: ThreadId,
+        f: impl FnOnce(&mut V) -> W,
+    ) -> Result<Option<W>, ThreadMapLockError>;
+
+    fn with_thread<W>(
+        &self,
+        tid: ThreadId,
+        f: impl FnOnce(&V) -> W,
+    ) -> Result<Option<W>, ThreadMapLockError>;
+
+    fn contains(&self, tid: ThreadId) -> Result<bool, ThreadMapLockError>;
+
+    fn debug_snapshot(&self) -> Result<impl Debug + '_, ThreadMapLockError>
+    where
+        V: Debug;
+
+    fn is_poisoned(&self) -> Result<bool, ThreadMapLockError>;
+
+    fn clear_poison(&self) -> Result<(), ThreadMapLockError>;
+
+    fn drain_poisoned(&self) -> Result<HashMap<ValueKey, V>, ThreadMapLockError>;
 }
 
 impl<V> ApiCheck<V> for ThreadMap<V> {
@@ -34,46 +125,177 @@ impl<V> ApiCheck<V> for ThreadMap<V> {
         Self::new(value_init)
     }
 
-    fn with_mut<W>(&self, f: impl FnOnce(&mut V) -> W) -> W {
-        self.with_mut(f)
+    fn from_fn(value_init: impl Fn() -> V + Send + Sync + 'static) -> Self {
+        Self::from_fn(value_init)
+    }
+
+    fn from_thread_fn(value_init: impl Fn(&ThreadInfo) -> V + Send + Sync + 'static) -> Self {
+        Self::from_thread_fn(value_init)
+    }
+
+    fn on_thread_exit(self, f: impl Fn(ThreadId, V) + Send + Sync + 'static) -> Self
+    where
+        V: Send + 'static,
+    {
+        self.on_thread_exit(f)
+    }
+
+    fn remove_on_thread_exit(self) -> Self
+    where
+        V: Send + 'static,
+    {
+        self.remove_on_thread_exit()
     }
 
-    fn with<W>(&self, f: impl FnOnce(&V) -> W) -> W {
-        self.with(f)
+    fn retire_on_thread_exit(self, merge: impl Fn(&mut V, V) + Send + Sync + 'static) -> Self
+    where
+        V: Send + 'static,
+    {
+        self.retire_on_thread_exit(merge)
     }
 
-    fn get(&self) -> V
+    fn retired(&self) -> Result<Option<V>, ThreadMapLockError>
     where
         V: Clone,
     {
-        self.get()
+        self.retired()
+    }
+
+    fn take_retired(&self) -> Result<Option<V>, ThreadMapLockError> {
+        self.take_retired()
+    }
+
+    fn try_with_mut<W>(&self, f: impl FnOnce(&mut V) -> W) -> Result<W, ThreadMapLockError> {
+        self.try_with_mut(f)
     }
 
-    fn set(&self, v: V) {
-        self.set(v);
+    fn try_with<W>(&self, f: impl FnOnce(&V) -> W) -> Result<W, ThreadMapLockError> {
+        self.try_with(f)
     }
 
-    fn drain(&self) -> Result<HashMap<ThreadId, V>, ThreadMapLockError> {
-        self.drain()
+    fn try_with_mut_timeout<W>(
+        &self,
+        timeout: Duration,
+        f: impl FnOnce(&mut V) -> W,
+    ) -> Result<W, ThreadMapLockError> {
+        self.try_with_mut_timeout(timeout, f)
+    }
+
+    fn try_with_timeout<W>(
+        &self,
+        timeout: Duration,
+        f: impl FnOnce(&V) -> W,
+    ) -> Result<W, ThreadMapLockError> {
+        self.try_with_timeout(timeout, f)
     }
 
-    fn fold<W>(
+    fn try_with_mut_nonblocking<W>(
         &self,
-        z: W,
-        f: impl FnMut(W, (ThreadId, &V)) -> W,
+        f: impl FnOnce(&mut V) -> W,
     ) -> Result<W, ThreadMapLockError> {
-        self.fold(z, f)
+        self.try_with_mut_nonblocking(f)
     }
 
-    fn fold_values<W>(&self, z: W, f: impl FnMut(W, &V) -> W) -> Result<W, ThreadMapLockError> {
-        self.fold_values(z, f)
+    fn try_with_nonblocking<W>(&self, f: impl FnOnce(&V) -> W) -> Result<W, ThreadMapLockError> {
+        self.try_with_nonblocking(f)
     }
 
-    fn probe(&self) -> Result<HashMap<ThreadId, V>, ThreadMapLockError>
+    fn try_get(&self) -> Result<V, ThreadMapLockError>
     where
         V: Clone,
     {
-        self.probe()
+        self.try_get()
+    }
+
+    fn try_set(&self, v: V) -> Result<(), ThreadMapLockError> {
+        self.try_set(v)
+    }
+
+    fn current(&self) -> impl Deref<Target = V> + '_ {
+        self.current()
+    }
+
+    fn try_current(&self) -> Result<impl Deref<Target = V> + '_, ThreadMapLockError> {
+        self.try_current()
+    }
+
+    fn current_mut(&self) -> impl DerefMut<Target = V> + '_ {
+        self.current_mut()
+    }
+
+    fn try_current_mut(&self) -> Result<impl DerefMut<Target = V> + '_, ThreadMapLockError> {
+        self.try_current_mut()
+    }
+
+    fn take(&self) -> Option<V> {
+        self.take()
+    }
+
+    fn try_take(&self) -> Result<Option<V>, ThreadMapLockError> {
+        self.try_take()
+    }
+
+    fn replace(&self, v: V) -> V {
+        self.replace(v)
+    }
+
+    fn try_replace(&self, v: V) -> Result<V, ThreadMapLockError> {
+        self.try_replace(v)
+    }
+
+    fn remove(&self) {
+        self.remove();
+    }
+
+    fn try_remove(&self) -> Result<(), ThreadMapLockError> {
+        self.try_remove()
+    }
+
+    fn reset(&self) {
+        self.reset();
+    }
+
+    fn try_reset(&self) -> Result<(), ThreadMapLockError> {
+        self.try_reset()
+    }
+
+    fn with_thread_mut<W>(
+        &self,
+        tid: ThreadId,
+        f: impl FnOnce(&mut V) -> W,
+    ) -> Result<Option<W>, ThreadMapLockError> {
+        self.with_thread_mut(tid, f)
+    }
+
+    fn with_thread<W>(
+        &self,
+        tid: ThreadId,
+        f: impl FnOnce(&V) -> W,
+    ) -> Result<Option<W>, ThreadMapLockError> {
+        self.with_thread(tid, f)
+    }
+
+    fn contains(&self, tid: ThreadId) -> Result<bool, ThreadMapLockError> {
+        self.contains(tid)
+    }
+
+    fn debug_snapshot(&self) -> Result<impl Debug + '_, ThreadMapLockError>
+    where
+        V: Debug,
+    {
+        self.debug_snapshot()
+    }
+
+    fn is_poisoned(&self) -> Result<bool, ThreadMapLockError> {
+        self.is_poisoned()
+    }
+
+    fn clear_poison(&self) -> Result<(), ThreadMapLockError> {
+        self.clear_poison()
+    }
+
+    fn drain_poisoned(&self) -> Result<HashMap<ValueKey, V>, ThreadMapLockError> {
+        self.drain_poisoned()
     }
 }
 
@@ -82,45 +304,713 @@ impl<V> ApiCheck<V> for ThreadMapX<V> {
         Self::new(value_init)
     }
 
-    fn with_mut<W>(&self, f: impl FnOnce(&mut V) -> W) -> W {
-        self.with_mut(f)
+    fn from_fn(value_init: impl Fn() -> V + Send + Sync + 'static) -> Self {
+        Self::from_fn(value_init)
+    }
+
+    fn from_thread_fn(value_init: impl Fn(&ThreadInfo) -> V + Send + Sync + 'static) -> Self {
+        Self::from_thread_fn(value_init)
+    }
+
+    fn on_thread_exit(self, f: impl Fn(ThreadId, V) + Send + Sync + 'static) -> Self
+    where
+        V: Send + 'static,
+    {
+        self.on_thread_exit(f)
+    }
+
+    fn remove_on_thread_exit(self) -> Self
+    where
+        V: Send + 'static,
+    {
+        self.remove_on_thread_exit()
+    }
+
+    fn retire_on_thread_exit(self, merge: impl Fn(&mut V, V) + Send + Sync + 'static) -> Self
+    where
+        V: Send + 'static,
+    {
+        self.retire_on_thread_exit(merge)
+    }
+
+    fn retired(&self) -> Result<Option<V>, ThreadMapLockError>
+    where
+        V: Clone,
+    {
+        self.retired()
+    }
+
+    fn take_retired(&self) -> Result<Option<V>, ThreadMapLockError> {
+        self.take_retired()
+    }
+
+    fn try_with_mut<W>(&self, f: impl FnOnce(&mut V) -> W) -> Result<W, ThreadMapLockError> {
+        self.try_with_mut(f)
+    }
+
+    fn try_with<W>(&self, f: impl FnOnce(&V) -> W) -> Result<W, ThreadMapLockError> {
+        self.try_with(f)
+    }
+
+    fn try_with_mut_timeout<W>(
+        &self,
+        timeout: Duration,
+        f: impl FnOnce(&mut V) -> W,
+    ) -> Result<W, ThreadMapLockError> {
+        self.try_with_mut_timeout(timeout, f)
+    }
+
+    fn try_with_timeout<W>(
+        &self,
+        timeout: Duration,
+        f: impl FnOnce(&V) -> W,
+    ) -> Result<W, ThreadMapLockError> {
+        self.try_with_timeout(timeout, f)
+    }
+
+    fn try_with_mut_nonblocking<W>(
+        &self,
+        f: impl FnOnce(&mut V) -> W,
+    ) -> Result<W, ThreadMapLockError> {
+        self.try_with_mut_nonblocking(f)
+    }
+
+    fn try_with_nonblocking<W>(&self, f: impl FnOnce(&V) -> W) -> Result<W, ThreadMapLockError> {
+        self.try_with_nonblocking(f)
+    }
+
+    fn try_get(&self) -> Result<V, ThreadMapLockError>
+    where
+        V: Clone,
+    {
+        self.try_get()
+    }
+
+    fn try_set(&self, v: V) -> Result<(), ThreadMapLockError> {
+        self.try_set(v)
+    }
+
+    fn current(&self) -> impl Deref<Target = V> + '_ {
+        self.current()
+    }
+
+    fn try_current(&self) -> Result<impl Deref<Target = V> + '_, ThreadMapLockError> {
+        self.try_current()
+    }
+
+    fn current_mut(&self) -> impl DerefMut<Target = V> + '_ {
+        self.current_mut()
+    }
+
+    fn try_current_mut(&self) -> Result<impl DerefMut<Target = V> + '_, ThreadMapLockError> {
+        self.try_current_mut()
+    }
+
+    fn take(&self) -> Option<V> {
+        self.take()
+    }
+
+    fn try_take(&self) -> Result<Option<V>, ThreadMapLockError> {
+        self.try_take()
+    }
+
+    fn replace(&self, v: V) -> V {
+        self.replace(v)
+    }
+
+    fn try_replace(&self, v: V) -> Result<V, ThreadMapLockError> {
+        self.try_replace(v)
+    }
+
+    fn remove(&self) {
+        self.remove();
+    }
+
+    fn try_remove(&self) -> Result<(), ThreadMapLockError> {
+        self.try_remove()
+    }
+
+    fn reset(&self) {
+        self.reset();
+    }
+
+    fn try_reset(&self) -> Result<(), ThreadMapLockError> {
+        self.try_reset()
+    }
+
+    fn with_thread_mut<W>(
+        &self,
+        tid: ThreadId,
+        f: impl FnOnce(&mut V) -> W,
+    ) -> Result<Option<W>, ThreadMapLockError> {
+        self.with_thread_mut(tid, f)
+    }
+
+    fn with_thread<W>(
+        &self,
+        tid: ThreadId,
+        f: impl FnOnce(&V) -> W,
+    ) -> Result<Option<W>, ThreadMapLockError> {
+        self.with_thread(tid, f)
+    }
+
+    fn contains(&self, tid: ThreadId) -> Result<bool, ThreadMapLockError> {
+        self.contains(tid)
+    }
+
+    fn debug_snapshot(&self) -> Result<impl Debug + '_, ThreadMapLockError>
+    where
+        V: Debug,
+    {
+        self.debug_snapshot()
+    }
+
+    fn is_poisoned(&self) -> Result<bool, ThreadMapLockError> {
+        self.is_poisoned()
+    }
+
+    fn clear_poison(&self) -> Result<(), ThreadMapLockError> {
+        self.clear_poison()
+    }
+
+    fn drain_poisoned(&self) -> Result<HashMap<ValueKey, V>, ThreadMapLockError> {
+        self.drain_poisoned()
+    }
+}
+
+impl<V> ApiCheck<V> for AdaptiveThreadMap<V> {
+    fn new(value_init: fn() -> V) -> Self {
+        Self::new(value_init)
+    }
+
+    fn from_fn(value_init: impl Fn() -> V + Send + Sync + 'static) -> Self {
+        Self::from_fn(value_init)
+    }
+
+    fn from_thread_fn(value_init: impl Fn(&ThreadInfo) -> V + Send + Sync + 'static) -> Self {
+        Self::from_thread_fn(value_init)
+    }
+
+    fn on_thread_exit(self, f: impl Fn(ThreadId, V) + Send + Sync + 'static) -> Self
+    where
+        V: Send + 'static,
+    {
+        self.on_thread_exit(f)
+    }
+
+    fn remove_on_thread_exit(self) -> Self
+    where
+        V: Send + 'static,
+    {
+        self.remove_on_thread_exit()
+    }
+
+    fn retire_on_thread_exit(self, merge: impl Fn(&mut V, V) + Send + Sync + 'static) -> Self
+    where
+        V: Send + 'static,
+    {
+        self.retire_on_thread_exit(merge)
+    }
+
+    fn retired(&self) -> Result<Option<V>, ThreadMapLockError>
+    where
+        V: Clone,
+    {
+        self.retired()
+    }
+
+    fn take_retired(&self) -> Result<Option<V>, ThreadMapLockError> {
+        self.take_retired()
+    }
+
+    fn try_with_mut<W>(&self, f: impl FnOnce(&mut V) -> W) -> Result<W, ThreadMapLockError> {
+        self.try_with_mut(f)
+    }
+
+    fn try_with<W>(&self, f: impl FnOnce(&V) -> W) -> Result<W, ThreadMapLockError> {
+        self.try_with(f)
+    }
+
+    fn try_with_mut_timeout<W>(
+        &self,
+        timeout: Duration,
+        f: impl FnOnce(&mut V) -> W,
+    ) -> Result<W, ThreadMapLockError> {
+        self.try_with_mut_timeout(timeout, f)
+    }
+
+    fn try_with_timeout<W>(
+        &self,
+        timeout: Duration,
+        f: impl FnOnce(&V) -> W,
+    ) -> Result<W, ThreadMapLockError> {
+        self.try_with_timeout(timeout, f)
+    }
+
+    fn try_with_mut_nonblocking<W>(
+        &self,
+        f: impl FnOnce(&mut V) -> W,
+    ) -> Result<W, ThreadMapLockError> {
+        self.try_with_mut_nonblocking(f)
+    }
+
+    fn try_with_nonblocking<W>(&self, f: impl FnOnce(&V) -> W) -> Result<W, ThreadMapLockError> {
+        self.try_with_nonblocking(f)
+    }
+
+    fn try_get(&self) -> Result<V, ThreadMapLockError>
+    where
+        V: Clone,
+    {
+        self.try_get()
+    }
+
+    fn try_set(&self, v: V) -> Result<(), ThreadMapLockError> {
+        self.try_set(v)
+    }
+
+    fn current(&self) -> impl Deref<Target = V> + '_ {
+        self.current()
+    }
+
+    fn try_current(&self) -> Result<impl Deref<Target = V> + '_, ThreadMapLockError> {
+        self.try_current()
+    }
+
+    fn current_mut(&self) -> impl DerefMut<Target = V> + '_ {
+        self.current_mut()
+    }
+
+    fn try_current_mut(&self) -> Result<impl DerefMut<Target = V> + '_, ThreadMapLockError> {
+        self.try_current_mut()
+    }
+
+    fn take(&self) -> Option<V> {
+        self.take()
+    }
+
+    fn try_take(&self) -> Result<Option<V>, ThreadMapLockError> {
+        self.try_take()
+    }
+
+    fn replace(&self, v: V) -> V {
+        self.replace(v)
+    }
+
+    fn try_replace(&self, v: V) -> Result<V, ThreadMapLockError> {
+        self.try_replace(v)
+    }
+
+    fn remove(&self) {
+        self.remove();
+    }
+
+    fn try_remove(&self) -> Result<(), ThreadMapLockError> {
+        self.try_remove()
+    }
+
+    fn reset(&self) {
+        self.reset();
+    }
+
+    fn try_reset(&self) -> Result<(), ThreadMapLockError> {
+        self.try_reset()
+    }
+
+    fn with_thread_mut<W>(
+        &self,
+        tid: ThreadId,
+        f: impl FnOnce(&mut V) -> W,
+    ) -> Result<Option<W>, ThreadMapLockError> {
+        self.with_thread_mut(tid, f)
+    }
+
+    fn with_thread<W>(
+        &self,
+        tid: ThreadId,
+        f: impl FnOnce(&V) -> W,
+    ) -> Result<Option<W>, ThreadMapLockError> {
+        self.with_thread(tid, f)
+    }
+
+    fn contains(&self, tid: ThreadId) -> Result<bool, ThreadMapLockError> {
+        self.contains(tid)
+    }
+
+    fn debug_snapshot(&self) -> Result<impl Debug + '_, ThreadMapLockError>
+    where
+        V: Debug,
+    {
+        self.debug_snapshot()
+    }
+
+    fn is_poisoned(&self) -> Result<bool, ThreadMapLockError> {
+        self.is_poisoned()
+    }
+
+    fn clear_poison(&self) -> Result<(), ThreadMapLockError> {
+        self.clear_poison()
+    }
+
+    fn drain_poisoned(&self) -> Result<HashMap<ValueKey, V>, ThreadMapLockError> {
+        self.drain_poisoned()
+    }
+}
+
+impl<V> ApiCheck<V> for ShardedThreadMap<V> {
+    fn new(value_init: fn() -> V) -> Self {
+        Self::new(value_init)
+    }
+
+    fn from_fn(value_init: impl Fn() -> V + Send + Sync + 'static) -> Self {
+        Self::from_fn(value_init)
+    }
+
+    fn from_thread_fn(value_init: impl Fn(&ThreadInfo) -> V + Send + Sync + 'static) -> Self {
+        Self::from_thread_fn(value_init)
+    }
+
+    fn on_thread_exit(self, f: impl Fn(ThreadId, V) + Send + Sync + 'static) -> Self
+    where
+        V: Send + 'static,
+    {
+        self.on_thread_exit(f)
+    }
+
+    fn remove_on_thread_exit(self) -> Self
+    where
+        V: Send + 'static,
+    {
+        self.remove_on_thread_exit()
+    }
+
+    fn retire_on_thread_exit(self, merge: impl Fn(&mut V, V) + Send + Sync + 'static) -> Self
+    where
+        V: Send + 'static,
+    {
+        self.retire_on_thread_exit(merge)
+    }
+
+    fn retired(&self) -> Result<Option<V>, ThreadMapLockError>
+    where
+        V: Clone,
+    {
+        self.retired()
+    }
+
+    fn take_retired(&self) -> Result<Option<V>, ThreadMapLockError> {
+        self.take_retired()
+    }
+
+    fn try_with_mut<W>(&self, f: impl FnOnce(&mut V) -> W) -> Result<W, ThreadMapLockError> {
+        self.try_with_mut(f)
+    }
+
+    fn try_with<W>(&self, f: impl FnOnce(&V) -> W) -> Result<W, ThreadMapLockError> {
+        self.try_with(f)
+    }
+
+    fn try_with_mut_timeout<W>(
+        &self,
+        timeout: Duration,
+        f: impl FnOnce(&mut V) -> W,
+    ) -> Result<W, ThreadMapLockError> {
+        self.try_with_mut_timeout(timeout, f)
+    }
+
+    fn try_with_timeout<W>(
+        &self,
+        timeout: Duration,
+        f: impl FnOnce(&V) -> W,
+    ) -> Result<W, ThreadMapLockError> {
+        self.try_with_timeout(timeout, f)
+    }
+
+    fn try_with_mut_nonblocking<W>(
+        &self,
+        f: impl FnOnce(&mut V) -> W,
+    ) -> Result<W, ThreadMapLockError> {
+        self.try_with_mut_nonblocking(f)
+    }
+
+    fn try_with_nonblocking<W>(&self, f: impl FnOnce(&V) -> W) -> Result<W, ThreadMapLockError> {
+        self.try_with_nonblocking(f)
+    }
+
+    fn try_get(&self) -> Result<V, ThreadMapLockError>
+    where
+        V: Clone,
+    {
+        self.try_get()
+    }
+
+    fn try_set(&self, v: V) -> Result<(), ThreadMapLockError> {
+        self.try_set(v)
+    }
+
+    fn current(&self) -> impl Deref<Target = V> + '_ {
+        self.current()
+    }
+
+    fn try_current(&self) -> Result<impl Deref<Target = V> + '_, ThreadMapLockError> {
+        self.try_current()
+    }
+
+    fn current_mut(&self) -> impl DerefMut<Target = V> + '_ {
+        self.current_mut()
+    }
+
+    fn try_current_mut(&self) -> Result<impl DerefMut<Target = V> + '_, ThreadMapLockError> {
+        self.try_current_mut()
+    }
+
+    fn take(&self) -> Option<V> {
+        self.take()
+    }
+
+    fn try_take(&self) -> Result<Option<V>, ThreadMapLockError> {
+        self.try_take()
+    }
+
+    fn replace(&self, v: V) -> V {
+        self.replace(v)
+    }
+
+    fn try_replace(&self, v: V) -> Result<V, ThreadMapLockError> {
+        self.try_replace(v)
+    }
+
+    fn remove(&self) {
+        self.remove();
+    }
+
+    fn try_remove(&self) -> Result<(), ThreadMapLockError> {
+        self.try_remove()
+    }
+
+    fn reset(&self) {
+        self.reset();
+    }
+
+    fn try_reset(&self) -> Result<(), ThreadMapLockError> {
+        self.try_reset()
+    }
+
+    fn with_thread_mut<W>(
+        &self,
+        tid: ThreadId,
+        f: impl FnOnce(&mut V) -> W,
+    ) -> Result<Option<W>, ThreadMapLockError> {
+        self.with_thread_mut(tid, f)
+    }
+
+    fn with_thread<W>(
+        &self,
+        tid: ThreadId,
+        f: impl FnOnce(&V) -> W,
+    ) -> Result<Option<W>, ThreadMapLockError> {
+        self.with_thread(tid, f)
+    }
+
+    fn contains(&self, tid: ThreadId) -> Result<bool, ThreadMapLockError> {
+        self.contains(tid)
+    }
+
+    fn debug_snapshot(&self) -> Result<impl Debug + '_, ThreadMapLockError>
+    where
+        V: Debug,
+    {
+        self.debug_snapshot()
+    }
+
+    fn is_poisoned(&self) -> Result<bool, ThreadMapLockError> {
+        self.is_poisoned()
+    }
+
+    fn clear_poison(&self) -> Result<(), ThreadMapLockError> {
+        self.clear_poison()
+    }
+
+    fn drain_poisoned(&self) -> Result<HashMap<ValueKey, V>, ThreadMapLockError> {
+        self.drain_poisoned()
+    }
+}
+
+impl<V> ApiCheck<V> for LockFreeThreadMap<V> {
+    fn new(value_init: fn() -> V) -> Self {
+        Self::new(value_init)
+    }
+
+    fn from_fn(value_init: impl Fn() -> V + Send + Sync + 'static) -> Self {
+        Self::from_fn(value_init)
+    }
+
+    fn from_thread_fn(value_init: impl Fn(&ThreadInfo) -> V + Send + Sync + 'static) -> Self {
+        Self::from_thread_fn(value_init)
+    }
+
+    fn on_thread_exit(self, f: impl Fn(ThreadId, V) + Send + Sync + 'static) -> Self
+    where
+        V: Send + 'static,
+    {
+        self.on_thread_exit(f)
     }
 
-    fn with<W>(&self, f: impl FnOnce(&V) -> W) -> W {
-        self.with(f)
+    fn remove_on_thread_exit(self) -> Self
+    where
+        V: Send + 'static,
+    {
+        self.remove_on_thread_exit()
     }
 
-    fn get(&self) -> V
+    fn retire_on_thread_exit(self, merge: impl Fn(&mut V, V) + Send + Sync + 'static) -> Self
+    where
+        V: Send + 'static,
+    {
+        self.retire_on_thread_exit(merge)
+    }
+
+    fn retired(&self) -> Result<Option<V>, ThreadMapLockError>
     where
         V: Clone,
     {
-        self.get()
+        self.retired()
+    }
+
+    fn take_retired(&self) -> Result<Option<V>, ThreadMapLockError> {
+        self.take_retired()
+    }
+
+    fn try_with_mut<W>(&self, f: impl FnOnce(&mut V) -> W) -> Result<W, ThreadMapLockError> {
+        self.try_with_mut(f)
+    }
+
+    fn try_with<W>(&self, f: impl FnOnce(&V) -> W) -> Result<W, ThreadMapLockError> {
+        self.try_with(f)
     }
 
-    fn set(&self, v: V) {
-        self.set(v);
+    fn try_with_mut_timeout<W>(
+        &self,
+        timeout: Duration,
+        f: impl FnOnce(&mut V) -> W,
+    ) -> Result<W, ThreadMapLockError> {
+        self.try_with_mut_timeout(timeout, f)
     }
 
-    fn drain(&self) -> Result<HashMap<ThreadId, V>, ThreadMapLockError> {
-        self.drain()
+    fn try_with_timeout<W>(
+        &self,
+        timeout: Duration,
+        f: impl FnOnce(&V) -> W,
+    ) -> Result<W, ThreadMapLockError> {
+        self.try_with_timeout(timeout, f)
     }
 
-    fn fold<W>(
+    fn try_with_mut_nonblocking<W>(
         &self,
-        z: W,
-        f: impl FnMut(W, (ThreadId, &V)) -> W,
+        f: impl FnOnce(&mut V) -> W,
     ) -> Result<W, ThreadMapLockError> {
-        self.fold(z, f)
+        self.try_with_mut_nonblocking(f)
     }
 
-    fn fold_values<W>(&self, z: W, f: impl FnMut(W, &V) -> W) -> Result<W, ThreadMapLockError> {
-        self.fold_values(z, f)
+    fn try_with_nonblocking<W>(&self, f: impl FnOnce(&V) -> W) -> Result<W, ThreadMapLockError> {
+        self.try_with_nonblocking(f)
     }
 
-    fn probe(&self) -> Result<HashMap<ThreadId, V>, ThreadMapLockError>
+    fn try_get(&self) -> Result<V, ThreadMapLockError>
     where
         V: Clone,
     {
-        self.probe()
+        self.try_get()
+    }
+
+    fn try_set(&self, v: V) -> Result<(), ThreadMapLockError> {
+        self.try_set(v)
+    }
+
+    fn current(&self) -> impl Deref<Target = V> + '_ {
+        self.current()
+    }
+
+    fn try_current(&self) -> Result<impl Deref<Target = V> + '_, ThreadMapLockError> {
+        self.try_current()
+    }
+
+    fn current_mut(&self) -> impl DerefMut<Target = V> + '_ {
+        self.current_mut()
+    }
+
+    fn try_current_mut(&self) -> Result<impl DerefMut<Target = V> + '_, ThreadMapLockError> {
+        self.try_current_mut()
+    }
+
+    fn take(&self) -> Option<V> {
+        self.take()
+    }
+
+    fn try_take(&self) -> Result<Option<V>, ThreadMapLockError> {
+        self.try_take()
+    }
+
+    fn replace(&self, v: V) -> V {
+        self.replace(v)
+    }
+
+    fn try_replace(&self, v: V) -> Result<V, ThreadMapLockError> {
+        self.try_replace(v)
+    }
+
+    fn remove(&self) {
+        self.remove();
+    }
+
+    fn try_remove(&self) -> Result<(), ThreadMapLockError> {
+        self.try_remove()
+    }
+
+    fn reset(&self) {
+        self.reset();
+    }
+
+    fn try_reset(&self) -> Result<(), ThreadMapLockError> {
+        self.try_reset()
+    }
+
+    fn with_thread_mut<W>(
+        &self,
+        tid: ThreadId,
+        f: impl FnOnce(&mut V) -> W,
+    ) -> Result<Option<W>, ThreadMapLockError> {
+        self.with_thread_mut(tid, f)
+    }
+
+    fn with_thread<W>(
+        &self,
+        tid: ThreadId,
+        f: impl FnOnce(&V) -> W,
+    ) -> Result<Option<W>, ThreadMapLockError> {
+        self.with_thread(tid, f)
+    }
+
+    fn contains(&self, tid: ThreadId) -> Result<bool, ThreadMapLockError> {
+        self.contains(tid)
+    }
+
+    fn debug_snapshot(&self) -> Result<impl Debug + '_, ThreadMapLockError>
+    where
+        V: Debug,
+    {
+        self.debug_snapshot()
+    }
+
+    fn is_poisoned(&self) -> Result<bool, ThreadMapLockError> {
+        self.is_poisoned()
+    }
+
+    fn clear_poison(&self) -> Result<(), ThreadMapLockError> {
+        self.clear_poison()
+    }
+
+    fn drain_poisoned(&self) -> Result<HashMap<ValueKey, V>, ThreadMapLockError> {
+        self.drain_poisoned()
     }
 }
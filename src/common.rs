@@ -1,27 +1,138 @@
 use std::{
     error::Error,
     fmt::{Debug, Display},
-    sync::PoisonError,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread::{self, Thread, ThreadId},
 };
 
-pub(crate) const POISONED_OBJECT_RW_LOCK: &str = "poisoned object RwLock";
-pub(crate) const POISONED_THREAD_LOCK: &str = "poisoned thread lock";
-
-/// Error emitted by some [`ThreadMap`](crate::ThreadMap) and [`ThreadMapX`](crate::ThreadMapX) methods when
-/// the object-level internal lock is poisoned.
-#[derive(Debug)]
-pub struct ThreadMapLockError;
+/// Error emitted by the fallible [`ThreadMap`](crate::ThreadMap) and [`ThreadMapX`](crate::ThreadMapX) methods when
+/// an internal lock cannot be acquired. The [`Display`] output identifies the lock involved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ThreadMapLockError {
+    /// The object-level lock was poisoned by the thread with the given [`ThreadId`], which panicked while holding it.
+    PoisonedObjectLock(ThreadId),
+    /// The per-thread lock associated with the given [`ThreadId`] is poisoned.
+    PoisonedThreadLock(ThreadId),
+    /// The lock of the accumulator of retired values (see
+    /// [`ThreadMap::retire_on_thread_exit`](crate::ThreadMap::retire_on_thread_exit)) is poisoned.
+    PoisonedRetiredLock,
+    /// A lock could not be acquired within the timeout passed to a method such as
+    /// [`ThreadMap::try_with_mut_timeout`](crate::ThreadMap::try_with_mut_timeout).
+    Timeout,
+    /// A lock could not be acquired without blocking by a method such as
+    /// [`ThreadMap::try_with_mut_nonblocking`](crate::ThreadMap::try_with_mut_nonblocking).
+    WouldBlock,
+    /// The current thread called a method of a map from within a closure passed to, or while holding a value returned
+    /// by, a method of the same map. Acquiring the map's locks again would deadlock or alias a mutable reference.
+    Reentrant,
+}
 
 impl Display for ThreadMapLockError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        (self as &dyn Debug).fmt(f)
+        match self {
+            Self::PoisonedObjectLock(tid) => write!(f, "object RwLock poisoned by {tid:?}"),
+            Self::PoisonedThreadLock(tid) => write!(f, "poisoned thread lock for {tid:?}"),
+            Self::PoisonedRetiredLock => f.write_str("poisoned lock of retired values"),
+            Self::Timeout => f.write_str("timed out acquiring lock"),
+            Self::WouldBlock => f.write_str("lock acquisition would block"),
+            Self::Reentrant => f.write_str("reentrant access to map from the same thread"),
+        }
     }
 }
 
 impl Error for ThreadMapLockError {}
 
-impl<T> From<PoisonError<T>> for ThreadMapLockError {
-    fn from(_: PoisonError<T>) -> Self {
-        Self
+/// Key of a value visited by [`ThreadMap::fold`](crate::ThreadMap::fold) or returned by
+/// [`ThreadMap::probe`](crate::ThreadMap::probe): either the [`ThreadId`] of the thread associated with the value, or
+/// the accumulator of the values of the threads retired by
+/// [`ThreadMap::retire_on_thread_exit`](crate::ThreadMap::retire_on_thread_exit).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ValueKey {
+    /// The value associated with the thread with the given [`ThreadId`].
+    Thread(ThreadId),
+    /// The accumulated value of the retired threads.
+    Retired,
+}
+
+impl ValueKey {
+    /// Returns the [`ThreadId`] of the thread associated with the value, or `None` for the accumulated value of the
+    /// retired threads.
+    pub fn thread_id(self) -> Option<ThreadId> {
+        match self {
+            Self::Thread(tid) => Some(tid),
+            Self::Retired => None,
+        }
+    }
+}
+
+/// Information about the thread for which an initial value is being created, passed to the initializer of
+/// [`ThreadMap::from_thread_fn`](crate::ThreadMap::from_thread_fn) and
+/// [`ThreadMapX::from_thread_fn`](crate::ThreadMapX::from_thread_fn).
+#[derive(Debug, Clone)]
+pub struct ThreadInfo {
+    thread: Thread,
+    index: usize,
+}
+
+impl ThreadInfo {
+    /// The [`ThreadId`] of the thread being initialized.
+    pub fn id(&self) -> ThreadId {
+        self.thread.id()
+    }
+
+    /// The handle of the thread being initialized, which provides access to the thread's name.
+    pub fn thread(&self) -> &Thread {
+        &self.thread
+    }
+
+    /// The dense registration index of the thread being initialized: `0` for the first thread to access the map,
+    /// `1` for the second, and so on.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+}
+
+/// Initializer used by [`ThreadMap`](crate::ThreadMap) and [`ThreadMapX`](crate::ThreadMapX) to create the
+/// initial value for each thread.
+pub(crate) enum ValueInit<V> {
+    /// Plain function pointer, as passed to the `new` constructors.
+    Fn(fn() -> V),
+    /// Closure that may capture state, as passed to the `from_fn` constructors.
+    Closure(Box<dyn Fn() -> V + Send + Sync>),
+    /// Closure that receives the identity of the thread being initialized, as passed to the `from_thread_fn`
+    /// constructors.
+    ThreadAware(Box<dyn Fn(&ThreadInfo) -> V + Send + Sync>),
+    /// Initializer shared by several maps, e.g., the shards of a [`ShardedThreadMap`](crate::ShardedThreadMap),
+    /// together with the counter of registrations across the maps, which replaces the index of each map.
+    Shared(Arc<ValueInit<V>>, Arc<AtomicUsize>),
+}
+
+impl<V> ValueInit<V> {
+    /// Creates a new initial value for the current thread, whose registration index is `index`.
+    pub(crate) fn call(&self, index: usize) -> V {
+        match self {
+            Self::Fn(f) => f(),
+            Self::Closure(f) => f(),
+            Self::ThreadAware(f) => f(&ThreadInfo {
+                thread: thread::current(),
+                index,
+            }),
+            Self::Shared(f, next_index) => f.call(next_index.fetch_add(1, Ordering::Relaxed)),
+        }
+    }
+}
+
+impl<V> Debug for ValueInit<V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Fn(_) => f.write_str("ValueInit::Fn"),
+            Self::Closure(_) => f.write_str("ValueInit::Closure"),
+            Self::ThreadAware(_) => f.write_str("ValueInit::ThreadAware"),
+            Self::Shared(init, _) => f.debug_tuple("ValueInit::Shared").field(init).finish(),
+        }
     }
 }
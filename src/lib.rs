@@ -10,13 +10,35 @@
 #[cfg(test)]
 mod api_check;
 
+mod api;
 mod common;
+mod raw_lock;
+mod reentrancy;
+mod slot_cache;
+mod sync;
+mod thread_exit;
+mod thread_id_hasher;
+mod thread_map_adaptive;
+mod thread_map_base;
+mod thread_map_lock_free;
+mod thread_map_sharded;
 mod thread_map_u;
 mod thread_map_x;
 
+pub use api::*;
 pub use common::*;
-pub use thread_map_u::*;
-pub use thread_map_x::*;
+pub use raw_lock::*;
+pub use thread_id_hasher::*;
+pub use thread_map_adaptive::{
+    AdaptiveSlots, AdaptiveThreadMap, AdaptiveThreadMapRef, AdaptiveThreadMapRefMut,
+};
+pub use thread_map_base::*;
+pub use thread_map_lock_free::{
+    LockFreeSlots, LockFreeThreadMap, LockFreeThreadMapRef, LockFreeThreadMapRefMut,
+};
+pub use thread_map_sharded::*;
+pub use thread_map_u::{CellSlots, ThreadMap, ThreadMapRef, ThreadMapRefMut};
+pub use thread_map_x::{MutexSlots, ThreadMapX, ThreadMapXRef, ThreadMapXRefMut};
 
 /// For backward compatibility only and eventually may be deprecated. The library's structs are now available
 /// directly at top level.
@@ -0,0 +1,492 @@
+use lock_api::{GuardSend, RawMutex, RawRwLock};
+use std::sync::{
+    Condvar, Mutex, PoisonError,
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+/// Raw mutex used by default by the maps. It is [`StdRawMutex`], or
+/// [`parking_lot::RawMutex`](https://docs.rs/parking_lot/latest/parking_lot/struct.RawMutex.html) with the
+/// `parking_lot` feature.
+#[cfg(not(feature = "parking_lot"))]
+pub type DefaultRawMutex = StdRawMutex;
+
+/// Raw mutex used by default by the maps. It is [`StdRawMutex`], or
+/// [`parking_lot::RawMutex`](https://docs.rs/parking_lot/latest/parking_lot/struct.RawMutex.html) with the
+/// `parking_lot` feature.
+#[cfg(feature = "parking_lot")]
+pub type DefaultRawMutex = parking_lot::RawMutex;
+
+/// Raw reader-writer lock used by default by the maps. It is [`StdRawRwLock`], or
+/// [`parking_lot::RawRwLock`](https://docs.rs/parking_lot/latest/parking_lot/struct.RawRwLock.html) with the
+/// `parking_lot` feature.
+#[cfg(not(feature = "parking_lot"))]
+pub type DefaultRawRwLock = StdRawRwLock;
+
+/// Raw reader-writer lock used by default by the maps. It is [`StdRawRwLock`], or
+/// [`parking_lot::RawRwLock`](https://docs.rs/parking_lot/latest/parking_lot/struct.RawRwLock.html) with the
+/// `parking_lot` feature.
+#[cfg(feature = "parking_lot")]
+pub type DefaultRawRwLock = parking_lot::RawRwLock;
+
+/// Implementation of [`lock_api::RawMutex`] based on the [`std::sync`] primitives. The lock is acquired with an
+/// atomic compare-and-swap when uncontended; contended threads block on a [`Condvar`].
+#[derive(Debug)]
+pub struct StdRawMutex {
+    locked: AtomicBool,
+    waiters: AtomicUsize,
+    mutex: Mutex<()>,
+    condvar: Condvar,
+}
+
+// SAFETY: the lock is held by at most one thread at a time, as it is only acquired by a successful compare-and-swap
+// of `locked` from `false` to `true`, and is released by storing `false`.
+unsafe impl RawMutex for StdRawMutex {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: Self = Self {
+        locked: AtomicBool::new(false),
+        waiters: AtomicUsize::new(0),
+        mutex: Mutex::new(()),
+        condvar: Condvar::new(),
+    };
+
+    type GuardMarker = GuardSend;
+
+    #[inline]
+    fn lock(&self) {
+        if self.try_lock() {
+            return;
+        }
+        let mut guard = self.mutex.lock().unwrap_or_else(PoisonError::into_inner);
+        // The count is incremented before the lock is retried, so a thread that releases the lock after the retry
+        // fails observes the count and notifies `condvar` once `guard` is released by `wait`.
+        self.waiters.fetch_add(1, Ordering::SeqCst);
+        while !self.try_lock() {
+            guard = self
+                .condvar
+                .wait(guard)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        self.waiters.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    #[inline]
+    fn try_lock(&self) -> bool {
+        self.locked
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    }
+
+    #[inline]
+    unsafe fn unlock(&self) {
+        self.locked.store(false, Ordering::SeqCst);
+        if self.waiters.load(Ordering::SeqCst) > 0 {
+            let _guard = self.mutex.lock().unwrap_or_else(PoisonError::into_inner);
+            self.condvar.notify_one();
+        }
+    }
+
+    fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+}
+
+/// Bit of [`StdRawRwLock::state`] that is set while the lock is held exclusively.
+const WRITER: usize = 1;
+
+/// Increment of [`StdRawRwLock::state`] for each shared holder of the lock.
+const READER: usize = 2;
+
+/// Implementation of [`lock_api::RawRwLock`] based on the [`std::sync`] primitives. The lock is acquired with
+/// atomic compare-and-swaps when uncontended; contended threads block on a [`Condvar`]. Threads waiting for an
+/// exclusive lock take precedence over new shared holders, so writers are not starved by a stream of readers. As with
+/// [`std::sync::RwLock`], a thread that acquires a shared lock while already holding one may therefore deadlock.
+#[derive(Debug)]
+pub struct StdRawRwLock {
+    /// [`WRITER`] if held exclusively, otherwise the number of shared holders times [`READER`].
+    state: AtomicUsize,
+    waiting_writers: AtomicUsize,
+    waiters: AtomicUsize,
+    mutex: Mutex<()>,
+    condvar: Condvar,
+}
+
+impl StdRawRwLock {
+    /// Blocks until `try_lock` succeeds. `writer` is `true` if `try_lock` acquires the lock exclusively.
+    fn lock_slow(&self, writer: bool, try_lock: impl Fn() -> bool) {
+        let mut guard = self.mutex.lock().unwrap_or_else(PoisonError::into_inner);
+        // See `StdRawMutex::lock`.
+        self.waiters.fetch_add(1, Ordering::SeqCst);
+        if writer {
+            self.waiting_writers.fetch_add(1, Ordering::SeqCst);
+        }
+        while !try_lock() {
+            guard = self
+                .condvar
+                .wait(guard)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        if writer {
+            self.waiting_writers.fetch_sub(1, Ordering::SeqCst);
+        }
+        self.waiters.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Wakes up the waiting threads, if any, after the lock has been released.
+    fn notify(&self) {
+        if self.waiters.load(Ordering::SeqCst) > 0 {
+            let _guard = self.mutex.lock().unwrap_or_else(PoisonError::into_inner);
+            self.condvar.notify_all();
+        }
+    }
+}
+
+// SAFETY: the lock is held either exclusively by one thread or shared by any number of threads, as `state` is only
+// changed by compare-and-swaps from `0` to `WRITER` and from a value without `WRITER` to that value plus `READER`,
+// and by the corresponding releases.
+unsafe impl RawRwLock for StdRawRwLock {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: Self = Self {
+        state: AtomicUsize::new(0),
+        waiting_writers: AtomicUsize::new(0),
+        waiters: AtomicUsize::new(0),
+        mutex: Mutex::new(()),
+        condvar: Condvar::new(),
+    };
+
+    type GuardMarker = GuardSend;
+
+    #[inline]
+    fn lock_shared(&self) {
+        if !self.try_lock_shared() {
+            self.lock_slow(false, || self.try_lock_shared());
+        }
+    }
+
+    #[inline]
+    fn try_lock_shared(&self) -> bool {
+        if self.waiting_writers.load(Ordering::SeqCst) > 0 {
+            return false;
+        }
+        let mut state = self.state.load(Ordering::SeqCst);
+        while state & WRITER == 0 {
+            match self.state.compare_exchange_weak(
+                state,
+                state + READER,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => return true,
+                Err(s) => state = s,
+            }
+        }
+        false
+    }
+
+    #[inline]
+    unsafe fn unlock_shared(&self) {
+        // Only the release by the last shared holder can unblock a waiting thread.
+        if self.state.fetch_sub(READER, Ordering::SeqCst) == READER {
+            self.notify();
+        }
+    }
+
+    #[inline]
+    fn lock_exclusive(&self) {
+        if !self.try_lock_exclusive() {
+            self.lock_slow(true, || self.try_lock_exclusive());
+        }
+    }
+
+    #[inline]
+    fn try_lock_exclusive(&self) -> bool {
+        self.state
+            .compare_exchange(0, WRITER, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    }
+
+    #[inline]
+    unsafe fn unlock_exclusive(&self) {
+        self.state.store(0, Ordering::SeqCst);
+        self.notify();
+    }
+
+    fn is_locked(&self) -> bool {
+        self.state.load(Ordering::Relaxed) != 0
+    }
+
+    fn is_locked_exclusive(&self) -> bool {
+        self.state.load(Ordering::Relaxed) == WRITER
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::{StdRawMutex, StdRawRwLock};
+    use lock_api::{GuardSend, RawMutex, RawRwLock};
+    use std::{
+        cell::UnsafeCell,
+        sync::atomic::{AtomicBool, AtomicIsize, AtomicUsize, Ordering},
+        thread,
+    };
+
+    const NTHREADS: usize = 8;
+    const NITER: usize = 1000;
+
+    /// Counter whose increments are only synchronized by the lock under test.
+    struct Counter(UnsafeCell<usize>);
+
+    // SAFETY: the tests only access the counter while holding the lock under test.
+    unsafe impl Sync for Counter {}
+
+    #[test]
+    fn test_raw_mutex() {
+        let mutex = lock_api::Mutex::<StdRawMutex, _>::new(0);
+
+        thread::scope(|s| {
+            for _ in 0..NTHREADS {
+                s.spawn(|| {
+                    for _ in 0..NITER {
+                        *mutex.lock() += 1;
+                    }
+                });
+            }
+        });
+
+        assert_eq!(NTHREADS * NITER, *mutex.lock());
+        assert!(mutex.try_lock().is_some());
+        let guard = mutex.lock();
+        assert!(mutex.is_locked());
+        assert!(mutex.try_lock().is_none());
+        drop(guard);
+        assert!(!mutex.is_locked());
+    }
+
+    #[test]
+    fn test_raw_rw_lock() {
+        let lock = lock_api::RwLock::<StdRawRwLock, _>::new(());
+        let counter = Counter(UnsafeCell::new(0));
+
+        thread::scope(|s| {
+            for i in 0..NTHREADS {
+                let (lock, counter) = (&lock, &counter);
+                s.spawn(move || {
+                    for _ in 0..NITER {
+                        if i % 2 == 0 {
+                            let _guard = lock.write();
+                            // SAFETY: the exclusive lock is held.
+                            unsafe { *counter.0.get() += 1 };
+                        } else {
+                            let _guard = lock.read();
+                            // SAFETY: the shared lock is held, so there are no concurrent writes.
+                            let n = unsafe { *counter.0.get() };
+                            assert!(n <= NTHREADS / 2 * NITER);
+                        }
+                    }
+                });
+            }
+        });
+
+        let _guard = lock.read();
+        // SAFETY: the shared lock is held.
+        assert_eq!(NTHREADS / 2 * NITER, unsafe { *counter.0.get() });
+        assert!(lock.try_read().is_some());
+        assert!(lock.try_write().is_none());
+        assert!(lock.is_locked());
+        assert!(!lock.is_locked_exclusive());
+    }
+
+    #[cfg(feature = "parking_lot")]
+    #[test]
+    fn test_default_backend() {
+        use crate::ThreadMapX;
+        use std::{any::TypeId, hash::RandomState};
+
+        assert_eq!(
+            TypeId::of::<parking_lot::RawMutex>(),
+            TypeId::of::<super::DefaultRawMutex>()
+        );
+        assert_eq!(
+            TypeId::of::<parking_lot::RawRwLock>(),
+            TypeId::of::<super::DefaultRawRwLock>()
+        );
+        // The maps use the parking_lot raw locks by default.
+        let _: ThreadMapX<i32, RandomState, parking_lot::RawRwLock, parking_lot::RawMutex> =
+            ThreadMapX::default();
+    }
+
+    #[cfg(not(feature = "parking_lot"))]
+    #[test]
+    fn test_default_backend() {
+        use crate::ThreadMapX;
+        use std::{any::TypeId, hash::RandomState};
+
+        assert_eq!(
+            TypeId::of::<StdRawMutex>(),
+            TypeId::of::<super::DefaultRawMutex>()
+        );
+        assert_eq!(
+            TypeId::of::<StdRawRwLock>(),
+            TypeId::of::<super::DefaultRawRwLock>()
+        );
+        // The maps use the std-based raw locks by default.
+        let _: ThreadMapX<i32, RandomState, StdRawRwLock, StdRawMutex> = ThreadMapX::default();
+    }
+
+    /// Number of iterations of each thread in the stress tests, which mix blocking and non-blocking acquisitions.
+    const STRESS_NITER: usize = 20_000;
+
+    #[test]
+    fn test_raw_mutex_stress() {
+        let mutex = StdRawMutex::INIT;
+        // Set while a thread holds `mutex`.
+        let held = AtomicBool::new(false);
+        let acquired = AtomicUsize::new(0);
+
+        thread::scope(|s| {
+            for i in 0..2 * NTHREADS {
+                let (mutex, held, acquired) = (&mutex, &held, &acquired);
+                s.spawn(move || {
+                    for j in 0..STRESS_NITER {
+                        if (i + j) % 3 == 0 {
+                            if !mutex.try_lock() {
+                                continue;
+                            }
+                        } else {
+                            mutex.lock();
+                        }
+                        assert!(!held.swap(true, Ordering::SeqCst));
+                        acquired.fetch_add(1, Ordering::Relaxed);
+                        held.store(false, Ordering::SeqCst);
+                        // SAFETY: the lock is held by this thread.
+                        unsafe { mutex.unlock() };
+                    }
+                });
+            }
+        });
+
+        assert!(acquired.load(Ordering::Relaxed) >= 2 * NTHREADS * STRESS_NITER / 2);
+        assert!(!mutex.is_locked());
+    }
+
+    #[test]
+    fn test_raw_rw_lock_stress() {
+        let lock = StdRawRwLock::INIT;
+        // Number of shared holders of `lock`, or -1 while it is held exclusively.
+        let holders = AtomicIsize::new(0);
+        let writers_done = AtomicUsize::new(0);
+
+        thread::scope(|s| {
+            for i in 0..2 * NTHREADS {
+                let (lock, holders, writers_done) = (&lock, &holders, &writers_done);
+                s.spawn(move || {
+                    if i % 4 == 0 {
+                        for j in 0..STRESS_NITER / 10 {
+                            if j % 2 == 0 {
+                                lock.lock_exclusive();
+                            } else if !lock.try_lock_exclusive() {
+                                continue;
+                            }
+                            assert_eq!(
+                                Ok(0),
+                                holders.compare_exchange(0, -1, Ordering::SeqCst, Ordering::SeqCst)
+                            );
+                            holders.store(0, Ordering::SeqCst);
+                            // SAFETY: the lock is held exclusively by this thread.
+                            unsafe { lock.unlock_exclusive() };
+                        }
+                        writers_done.fetch_add(1, Ordering::SeqCst);
+                    } else {
+                        // Readers keep acquiring the lock until all writers are done, so the test would not terminate
+                        // if the writers were starved.
+                        let mut j = 0;
+                        while writers_done.load(Ordering::SeqCst) < NTHREADS / 2 {
+                            j += 1;
+                            if j % 3 == 0 {
+                                if !lock.try_lock_shared() {
+                                    continue;
+                                }
+                            } else {
+                                lock.lock_shared();
+                            }
+                            assert!(holders.fetch_add(1, Ordering::SeqCst) >= 0);
+                            holders.fetch_sub(1, Ordering::SeqCst);
+                            // SAFETY: the lock is held shared by this thread.
+                            unsafe { lock.unlock_shared() };
+                        }
+                    }
+                });
+            }
+        });
+
+        assert_eq!(0, holders.load(Ordering::SeqCst));
+        assert!(!lock.is_locked());
+    }
+
+    /// Number of acquisitions of [`CountingRawMutex`]es.
+    pub(crate) static MUTEX_LOCKS: AtomicUsize = AtomicUsize::new(0);
+
+    /// Number of acquisitions of [`CountingRawRwLock`]s.
+    pub(crate) static RW_LOCKS: AtomicUsize = AtomicUsize::new(0);
+
+    /// Instrumented raw mutex that counts its acquisitions in [`MUTEX_LOCKS`].
+    pub(crate) struct CountingRawMutex(StdRawMutex);
+
+    unsafe impl RawMutex for CountingRawMutex {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const INIT: Self = Self(StdRawMutex::INIT);
+
+        type GuardMarker = GuardSend;
+
+        fn lock(&self) {
+            MUTEX_LOCKS.fetch_add(1, Ordering::Relaxed);
+            self.0.lock();
+        }
+
+        fn try_lock(&self) -> bool {
+            MUTEX_LOCKS.fetch_add(1, Ordering::Relaxed);
+            self.0.try_lock()
+        }
+
+        unsafe fn unlock(&self) {
+            unsafe { self.0.unlock() }
+        }
+    }
+
+    /// Instrumented raw reader-writer lock that counts its acquisitions in [`RW_LOCKS`].
+    pub(crate) struct CountingRawRwLock(StdRawRwLock);
+
+    unsafe impl RawRwLock for CountingRawRwLock {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const INIT: Self = Self(StdRawRwLock::INIT);
+
+        type GuardMarker = GuardSend;
+
+        fn lock_shared(&self) {
+            RW_LOCKS.fetch_add(1, Ordering::Relaxed);
+            self.0.lock_shared();
+        }
+
+        fn try_lock_shared(&self) -> bool {
+            RW_LOCKS.fetch_add(1, Ordering::Relaxed);
+            self.0.try_lock_shared()
+        }
+
+        unsafe fn unlock_shared(&self) {
+            unsafe { self.0.unlock_shared() }
+        }
+
+        fn lock_exclusive(&self) {
+            RW_LOCKS.fetch_add(1, Ordering::Relaxed);
+            self.0.lock_exclusive();
+        }
+
+        fn try_lock_exclusive(&self) -> bool {
+            RW_LOCKS.fetch_add(1, Ordering::Relaxed);
+            self.0.try_lock_exclusive()
+        }
+
+        unsafe fn unlock_exclusive(&self) {
+            unsafe { self.0.unlock_exclusive() }
+        }
+    }
+}
@@ -0,0 +1,52 @@
+//! Detection of reentrant access to a map from the same thread, e.g., calling a map method from within a closure
+//! passed to another method of the same map. Such access would alias a mutable reference or deadlock.
+
+use crate::ThreadMapLockError;
+use std::{cell::RefCell, marker::PhantomData};
+
+thread_local! {
+    /// Ids of the maps currently being accessed by the current thread.
+    static ACTIVE_MAP_IDS: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Marks the map whose id is `map_id` as being accessed by the current thread until dropped.
+pub(crate) struct Entered {
+    map_id: u64,
+    /// Makes this type `!Send` as it refers to thread-local state.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for Entered {
+    #[inline]
+    fn drop(&mut self) {
+        let _ = ACTIVE_MAP_IDS.try_with(|ids| {
+            let mut ids = ids.borrow_mut();
+            if let Some(i) = ids.iter().rposition(|id| *id == self.map_id) {
+                ids.swap_remove(i);
+            }
+        });
+    }
+}
+
+/// Marks the map whose id is `map_id` as being accessed by the current thread until the returned value is dropped.
+///
+/// # Errors
+/// - [`ThreadMapLockError::Reentrant`] if the current thread is already accessing the map.
+#[inline]
+pub(crate) fn enter(map_id: u64) -> Result<Entered, ThreadMapLockError> {
+    ACTIVE_MAP_IDS
+        .try_with(|ids| {
+            let mut ids = ids.borrow_mut();
+            if ids.contains(&map_id) {
+                return Err(ThreadMapLockError::Reentrant);
+            }
+            ids.push(map_id);
+            Ok(())
+        })
+        // If thread-local storage has been destroyed, the thread is exiting and cannot be within a map method.
+        .unwrap_or(Ok(()))?;
+    Ok(Entered {
+        map_id,
+        _not_send: PhantomData,
+    })
+}
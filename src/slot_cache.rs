@@ -0,0 +1,82 @@
+//! Per-thread cache of the locations of the current thread's values in maps, which allows a map to find the
+//! current thread's value without obtaining the current thread's [`ThreadId`](std::thread::ThreadId) and looking it
+//! up in its `HashMap`.
+//!
+//! An entry is valid only while the map's generation is the one recorded with the entry. A map must change its
+//! generation whenever it removes values of threads other than the current one (e.g., with `drain`), and must
+//! [`invalidate`] the entry when it removes the value of the current thread. A map that validates the cached locations
+//! itself, by keeping its cells alive and checking the ticket of the value found there, records the ticket instead of
+//! the generation and looks up entries with [`lookup`].
+
+use std::cell::RefCell;
+
+/// Maximum number of maps whose entries are cached by a thread. When the cache is full, the least recently cached
+/// entry is evicted.
+const CAPACITY: usize = 8;
+
+struct Entry {
+    map_id: u64,
+    generation: u64,
+    slot: *const (),
+}
+
+thread_local! {
+    static SLOT_CACHE: RefCell<Vec<Entry>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Returns the cached location of the current thread's value in the map whose id is `map_id`, if the entry was
+/// cached when the map's generation was `generation`.
+#[inline]
+pub(crate) fn get(map_id: u64, generation: u64) -> Option<*const ()> {
+    SLOT_CACHE
+        .try_with(|entries| {
+            entries
+                .borrow()
+                .iter()
+                .find(|e| e.map_id == map_id && e.generation == generation)
+                .map(|e| e.slot)
+        })
+        .ok()
+        .flatten()
+}
+
+/// Returns the cached location of the current thread's value in the map whose id is `map_id`, if any, together with
+/// the generation or ticket recorded with the entry.
+#[inline]
+pub(crate) fn lookup(map_id: u64) -> Option<(u64, *const ())> {
+    SLOT_CACHE
+        .try_with(|entries| {
+            entries
+                .borrow()
+                .iter()
+                .find(|e| e.map_id == map_id)
+                .map(|e| (e.generation, e.slot))
+        })
+        .ok()
+        .flatten()
+}
+
+/// Caches `slot` as the location of the current thread's value in the map whose id is `map_id`, whose generation is
+/// `generation`.
+pub(crate) fn put(map_id: u64, generation: u64, slot: *const ()) {
+    let _ = SLOT_CACHE.try_with(|entries| {
+        let mut entries = entries.borrow_mut();
+        if let Some(i) = entries.iter().position(|e| e.map_id == map_id) {
+            entries.remove(i);
+        } else if entries.len() == CAPACITY {
+            entries.remove(0);
+        }
+        entries.push(Entry {
+            map_id,
+            generation,
+            slot,
+        });
+    });
+}
+
+/// Removes the cached location of the current thread's value in the map whose id is `map_id`, if any.
+pub(crate) fn invalidate(map_id: u64) {
+    let _ = SLOT_CACHE.try_with(|entries| {
+        entries.borrow_mut().retain(|e| e.map_id != map_id);
+    });
+}
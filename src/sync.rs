@@ -0,0 +1,348 @@
+//! Locks used internally by the maps. These are thin wrappers of the [`lock_api`] locks, generic over the raw lock
+//! types, that expose the subset of the [`std::sync`] API used by the maps, including poisoning: as with the
+//! [`std::sync`] locks, a [`Mutex`] or [`RwLock`] is poisoned when a thread panics while holding it exclusively.
+//! With the `parking_lot` feature, locks are never poisoned. Unlike the [`std::sync`] locks, a poisoned lock records
+//! the [`ThreadId`] of the thread that poisoned it, and locks can be acquired with a limit on the wait (see [`Wait`]).
+
+use crate::{DefaultRawMutex, DefaultRawRwLock};
+use lock_api::{RawMutex, RawRwLock};
+use std::{
+    fmt::Debug,
+    ops::{Deref, DerefMut},
+    sync::{
+        LockResult, PoisonError, TryLockError, TryLockResult,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, ThreadId},
+    time::Instant,
+};
+
+/// Whether locks are poisoned when a thread panics while holding them.
+const POISONING: bool = cfg!(not(feature = "parking_lot"));
+
+/// Poisoned state of a lock.
+struct Poison {
+    poisoned: AtomicBool,
+    /// The last thread that poisoned the lock. It is recorded before `poisoned` is set, and not cleared with it.
+    poisoner: std::sync::Mutex<Option<ThreadId>>,
+}
+
+impl Poison {
+    const fn new() -> Self {
+        Self {
+            poisoned: AtomicBool::new(false),
+            poisoner: std::sync::Mutex::new(None),
+        }
+    }
+
+    #[inline]
+    fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Ordering::Acquire)
+    }
+
+    fn poison(&self) {
+        *self.poisoner.lock().unwrap_or_else(PoisonError::into_inner) =
+            Some(thread::current().id());
+        self.poisoned.store(true, Ordering::Release);
+    }
+
+    fn clear(&self) {
+        self.poisoned.store(false, Ordering::Relaxed);
+    }
+
+    fn poisoner(&self) -> Option<ThreadId> {
+        *self.poisoner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Poisons a lock if it is dropped while its thread is panicking, unless the thread was already panicking when it
+/// acquired the lock.
+struct PoisonOnPanic<'a>(Option<&'a Poison>);
+
+impl<'a> PoisonOnPanic<'a> {
+    #[inline]
+    fn new(poison: &'a Poison) -> Self {
+        Self((POISONING && !thread::panicking()).then_some(poison))
+    }
+}
+
+impl Drop for PoisonOnPanic<'_> {
+    #[inline]
+    fn drop(&mut self) {
+        if let Some(poison) = self.0
+            && thread::panicking()
+        {
+            poison.poison();
+        }
+    }
+}
+
+/// Returns `guard`, or a [`PoisonError`] wrapping it if the lock is poisoned.
+#[inline]
+fn poison_result<G>(poison: &Poison, guard: G) -> LockResult<G> {
+    if poison.is_poisoned() {
+        Err(PoisonError::new(guard))
+    } else {
+        Ok(guard)
+    }
+}
+
+/// Returns the result of a non-blocking lock acquisition.
+fn try_result<G>(poison: &Poison, guard: Option<G>) -> TryLockResult<G> {
+    let guard = guard.ok_or(TryLockError::WouldBlock)?;
+    poison_result(poison, guard).map_err(TryLockError::Poisoned)
+}
+
+/// Discards the guard of a failed lock acquisition.
+pub(crate) fn discard_guard<G>(e: TryLockError<G>) -> TryLockError<()> {
+    match e {
+        TryLockError::Poisoned(_) => TryLockError::Poisoned(PoisonError::new(())),
+        TryLockError::WouldBlock => TryLockError::WouldBlock,
+    }
+}
+
+/// How long a lock acquisition waits for the lock to become available. It is public, though not reachable from outside
+/// the crate, as it appears in the signatures of the sealed slot strategy trait.
+#[derive(Debug, Clone, Copy)]
+pub enum Wait {
+    /// Until the lock is acquired.
+    Forever,
+    /// Not at all.
+    Never,
+    /// Until the given instant. The lock is polled, as the raw lock traits do not support timed acquisition.
+    Until(Instant),
+}
+
+impl Wait {
+    /// Acquires a lock with `lock` or, unless waiting forever, with `try_lock`. Returns `None` if the lock is not
+    /// acquired in time.
+    #[inline]
+    fn acquire<G>(self, try_lock: impl Fn() -> Option<G>, lock: impl FnOnce() -> G) -> Option<G> {
+        match self {
+            Self::Forever => Some(lock()),
+            Self::Never => try_lock(),
+            Self::Until(deadline) => loop {
+                if let Some(guard) = try_lock() {
+                    return Some(guard);
+                }
+                if Instant::now() >= deadline {
+                    return None;
+                }
+                thread::yield_now();
+            },
+        }
+    }
+}
+
+/// [`lock_api::Mutex`] with the [`std::sync::Mutex`] API.
+pub(crate) struct Mutex<T, R = DefaultRawMutex> {
+    inner: lock_api::Mutex<R, T>,
+    poison: Poison,
+}
+
+impl<T, R: RawMutex> Mutex<T, R> {
+    pub(crate) const fn new(value: T) -> Self {
+        Self {
+            inner: lock_api::Mutex::new(value),
+            poison: Poison::new(),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn lock(&self) -> LockResult<MutexGuard<'_, T, R>> {
+        let guard = self.inner.lock();
+        poison_result(&self.poison, self.guard(guard))
+    }
+
+    /// Acquires the lock, waiting as specified by `wait`. Returns [`TryLockError::WouldBlock`] if the lock is not
+    /// acquired in time.
+    #[inline]
+    pub(crate) fn lock_wait(&self, wait: Wait) -> TryLockResult<MutexGuard<'_, T, R>> {
+        let guard = wait.acquire(|| self.inner.try_lock(), || self.inner.lock());
+        try_result(&self.poison, guard.map(|g| self.guard(g)))
+    }
+
+    /// Acquires the lock to read the value. As with a shared lock of a [`RwLock`], a panic while the returned guard
+    /// is held does not poison the lock, as the value cannot have been left in an inconsistent state.
+    pub(crate) fn lock_read(&self) -> LockResult<MutexReadGuard<'_, T, R>> {
+        poison_result(&self.poison, MutexReadGuard(self.inner.lock()))
+    }
+
+    #[inline]
+    fn guard<'a>(&'a self, guard: lock_api::MutexGuard<'a, R, T>) -> MutexGuard<'a, T, R> {
+        MutexGuard {
+            _poison: PoisonOnPanic::new(&self.poison),
+            guard,
+        }
+    }
+
+    /// Returns a raw pointer to the value, which may only be dereferenced while the caller otherwise guarantees
+    /// exclusive access to it, e.g., while no guard exists and none can be created.
+    pub(crate) fn data_ptr(&self) -> *mut T {
+        self.inner.data_ptr()
+    }
+
+    pub(crate) fn into_inner(self) -> LockResult<T> {
+        let poisoned = self.poison.is_poisoned();
+        let value = self.inner.into_inner();
+        if poisoned {
+            Err(PoisonError::new(value))
+        } else {
+            Ok(value)
+        }
+    }
+
+    pub(crate) fn is_poisoned(&self) -> bool {
+        self.poison.is_poisoned()
+    }
+
+    pub(crate) fn clear_poison(&self) {
+        self.poison.clear();
+    }
+}
+
+impl<T: Debug, R: RawMutex> Debug for Mutex<T, R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Mutex")
+            .field("inner", &self.inner)
+            .field("poisoned", &self.is_poisoned())
+            .finish()
+    }
+}
+
+/// Guard of a [`Mutex`].
+pub(crate) struct MutexGuard<'a, T, R: RawMutex = DefaultRawMutex> {
+    /// Declared before `guard` so that the lock is poisoned before it is released.
+    _poison: PoisonOnPanic<'a>,
+    guard: lock_api::MutexGuard<'a, R, T>,
+}
+
+impl<T, R: RawMutex> Deref for MutexGuard<'_, T, R> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T, R: RawMutex> DerefMut for MutexGuard<'_, T, R> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+/// Guard of a [`Mutex`] acquired with [`Mutex::lock_read`].
+pub(crate) struct MutexReadGuard<'a, T, R: RawMutex = DefaultRawMutex>(
+    lock_api::MutexGuard<'a, R, T>,
+);
+
+impl<T, R: RawMutex> Deref for MutexReadGuard<'_, T, R> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+/// [`lock_api::RwLock`] with the [`std::sync::RwLock`] API.
+pub(crate) struct RwLock<T, R = DefaultRawRwLock> {
+    inner: lock_api::RwLock<R, T>,
+    poison: Poison,
+}
+
+impl<T, R: RawRwLock> RwLock<T, R> {
+    pub(crate) const fn new(value: T) -> Self {
+        Self {
+            inner: lock_api::RwLock::new(value),
+            poison: Poison::new(),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn read(&self) -> LockResult<RwLockReadGuard<'_, T, R>> {
+        poison_result(&self.poison, self.inner.read())
+    }
+
+    pub(crate) fn try_read(&self) -> TryLockResult<RwLockReadGuard<'_, T, R>> {
+        try_result(&self.poison, self.inner.try_read())
+    }
+
+    /// Acquires a shared lock, waiting as specified by `wait`. Returns [`TryLockError::WouldBlock`] if the lock is
+    /// not acquired in time.
+    #[inline]
+    pub(crate) fn read_wait(&self, wait: Wait) -> TryLockResult<RwLockReadGuard<'_, T, R>> {
+        let guard = wait.acquire(|| self.inner.try_read(), || self.inner.read());
+        try_result(&self.poison, guard)
+    }
+
+    pub(crate) fn write(&self) -> LockResult<RwLockWriteGuard<'_, T, R>> {
+        let guard = self.write_guard(self.inner.write());
+        poison_result(&self.poison, guard)
+    }
+
+    /// Acquires an exclusive lock, waiting as specified by `wait`. Returns [`TryLockError::WouldBlock`] if the lock
+    /// is not acquired in time.
+    pub(crate) fn write_wait(&self, wait: Wait) -> TryLockResult<RwLockWriteGuard<'_, T, R>> {
+        let guard = wait.acquire(|| self.inner.try_write(), || self.inner.write());
+        try_result(&self.poison, guard.map(|g| self.write_guard(g)))
+    }
+
+    fn write_guard<'a>(
+        &'a self,
+        guard: lock_api::RwLockWriteGuard<'a, R, T>,
+    ) -> RwLockWriteGuard<'a, T, R> {
+        RwLockWriteGuard {
+            _poison: PoisonOnPanic::new(&self.poison),
+            guard,
+        }
+    }
+
+    pub(crate) fn is_poisoned(&self) -> bool {
+        self.poison.is_poisoned()
+    }
+
+    /// Returns the [`ThreadId`] of the last thread that poisoned the lock, or `None` if it has never been poisoned.
+    /// Once an acquisition has reported the lock as poisoned, the result is `Some`, even if the poisoned state has
+    /// been cleared since.
+    pub(crate) fn poisoner(&self) -> Option<ThreadId> {
+        self.poison.poisoner()
+    }
+
+    pub(crate) fn clear_poison(&self) {
+        self.poison.clear();
+    }
+}
+
+impl<T: Debug, R: RawRwLock> Debug for RwLock<T, R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RwLock")
+            .field("inner", &self.inner)
+            .field("poisoned", &self.is_poisoned())
+            .finish()
+    }
+}
+
+/// Guard of a shared lock of a [`RwLock`]. As with [`std::sync::RwLock`], a panic while a shared lock is held does not
+/// poison the lock.
+pub(crate) type RwLockReadGuard<'a, T, R = DefaultRawRwLock> = lock_api::RwLockReadGuard<'a, R, T>;
+
+/// Guard of an exclusive lock of a [`RwLock`].
+pub(crate) struct RwLockWriteGuard<'a, T, R: RawRwLock = DefaultRawRwLock> {
+    /// Declared before `guard` so that the lock is poisoned before it is released.
+    _poison: PoisonOnPanic<'a>,
+    guard: lock_api::RwLockWriteGuard<'a, R, T>,
+}
+
+impl<T, R: RawRwLock> Deref for RwLockWriteGuard<'_, T, R> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T, R: RawRwLock> DerefMut for RwLockWriteGuard<'_, T, R> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
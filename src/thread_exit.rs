@@ -0,0 +1,70 @@
+//! Support for running actions when a thread exits.
+
+use std::{
+    cell::RefCell,
+    fmt::Debug,
+    sync::atomic::{AtomicU64, Ordering},
+    thread::ThreadId,
+};
+
+/// Returns a process-wide unique identifier for a map instance.
+pub(crate) fn next_map_id() -> u64 {
+    static NEXT_MAP_ID: AtomicU64 = AtomicU64::new(0);
+    NEXT_MAP_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Actions registered by the current thread, keyed by map id, to be run when the thread exits.
+struct ExitActions(RefCell<Vec<(u64, Box<dyn FnOnce()>)>>);
+
+impl Drop for ExitActions {
+    fn drop(&mut self) {
+        for (_, action) in self.0.take() {
+            action();
+        }
+    }
+}
+
+thread_local! {
+    static EXIT_ACTIONS: ExitActions = const { ExitActions(RefCell::new(Vec::new())) };
+}
+
+/// Registers `action` to be run when the current thread exits, unless an action has already been registered by the
+/// current thread for the map identified by `map_id`. Registration is silently skipped if the current thread is
+/// already being torn down.
+fn register_exit_action(map_id: u64, action: impl FnOnce() + 'static) {
+    let _ = EXIT_ACTIONS.try_with(|actions| {
+        let mut actions = actions.0.borrow_mut();
+        if actions.iter().all(|(id, _)| *id != map_id) {
+            actions.push((map_id, Box::new(action)));
+        }
+    });
+}
+
+/// Hook invoked by a map when a thread registers, which arranges for the thread's entry to be handled when the
+/// thread exits.
+pub(crate) struct ExitHook(Box<dyn Fn(ThreadId) + Send + Sync>);
+
+impl ExitHook {
+    /// Creates a hook for the map identified by `map_id`. When a registered thread exits, `action` is called with the
+    /// thread's [`ThreadId`] on the exiting thread.
+    pub(crate) fn new(
+        map_id: u64,
+        action: impl Fn(ThreadId) + Clone + Send + Sync + 'static,
+    ) -> Self {
+        Self(Box::new(move |tid| {
+            let action = action.clone();
+            register_exit_action(map_id, move || action(tid));
+        }))
+    }
+
+    /// Called by the map when the current thread, with [`ThreadId`] `tid`, registers.
+    pub(crate) fn on_register(&self, tid: ThreadId) {
+        (self.0)(tid);
+    }
+}
+
+impl Debug for ExitHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ExitHook")
+    }
+}
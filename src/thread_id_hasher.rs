@@ -0,0 +1,69 @@
+use std::hash::{BuildHasherDefault, Hasher};
+
+/// Multiplier that spreads the bits of sequential keys, such as [`ThreadId`](std::thread::ThreadId)s, over the whole
+/// hash, as [`HashMap`](std::collections::HashMap) uses both the low and the high bits of the hash.
+const SEED: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// Fast [`Hasher`] for [`ThreadId`](std::thread::ThreadId) keys. Unlike the default SipHash hasher of
+/// [`HashMap`](std::collections::HashMap), it does not resist collision attacks, which is unnecessary for
+/// [`ThreadId`](std::thread::ThreadId)s as they are unique and not chosen by callers. It should not be used for keys
+/// that can be controlled by an attacker.
+///
+/// Use it with [`ThreadMap::with_hasher`](crate::ThreadMap::with_hasher) or
+/// [`ThreadMapX::with_hasher`](crate::ThreadMapX::with_hasher) through [`ThreadIdBuildHasher`]:
+///
+/// ```rust
+/// use thread_map::{ThreadIdBuildHasher, ThreadMap};
+///
+/// let tm = ThreadMap::with_hasher(|| 0, ThreadIdBuildHasher::default());
+/// tm.set(1);
+/// assert_eq!(1, tm.get());
+/// ```
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ThreadIdHasher(u64);
+
+impl Hasher for ThreadIdHasher {
+    fn finish(&self) -> u64 {
+        self.0.wrapping_mul(SEED)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.write_u64(u64::from(b));
+        }
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        // A [`ThreadId`](std::thread::ThreadId) is hashed as a single `u64`, which is kept as is.
+        self.0 = self.0.rotate_left(5) ^ n;
+    }
+}
+
+/// [`BuildHasher`](std::hash::BuildHasher) of [`ThreadIdHasher`]s.
+pub type ThreadIdBuildHasher = BuildHasherDefault<ThreadIdHasher>;
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod test {
+    use super::ThreadIdBuildHasher;
+    use std::{collections::HashSet, hash::BuildHasher, thread};
+
+    #[test]
+    fn test_thread_id_hashes() {
+        let tids = (0..20)
+            .map(|_| thread::spawn(|| thread::current().id()).join().unwrap())
+            .collect::<Vec<_>>();
+
+        let hasher = ThreadIdBuildHasher::default();
+        let hashes = tids
+            .iter()
+            .map(|tid| hasher.hash_one(tid))
+            .collect::<HashSet<_>>();
+        assert_eq!(tids.len(), hashes.len());
+        assert_eq!(hasher.hash_one(tids[0]), hasher.hash_one(tids[0]));
+
+        // Sequential ids differ in the high bits of their hashes.
+        let high_bits = hashes.iter().map(|h| h >> 57).collect::<HashSet<_>>();
+        assert!(high_bits.len() > 1);
+    }
+}
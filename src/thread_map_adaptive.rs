@@ -0,0 +1,534 @@
+use crate::{
+    DefaultRawMutex, DefaultRawRwLock,
+    sync::{Mutex, MutexGuard, Wait, discard_guard},
+    thread_map_base::{
+        SlotStrategy, Slots, ThreadMapBase, ThreadMapBaseRef, ThreadMapBaseRefMut,
+        strategy::{SlotAccess, StateGuard, Strategy},
+    },
+};
+use lock_api::{RawMutex, RawRwLock};
+use std::{
+    hash::{BuildHasher, RandomState},
+    sync::{
+        Arc, PoisonError, TryLockError,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+};
+
+/// Default average number of per-thread accesses between consecutive sweeps below which the per-thread locks are used,
+/// so that sweeps do not block the per-thread methods. It is a rough estimate of where the sweeps of
+/// [`ThreadMap`](crate::ThreadMap) start to cost more than the per-thread locks of [`ThreadMapX`](crate::ThreadMapX),
+/// which depends on the machine and the workload; see [`AdaptiveThreadMap::with_switch_thresholds`].
+const SLOT_LOCKS_BELOW: u64 = 120;
+
+/// Default average number of per-thread accesses between consecutive sweeps above which the object-level write lock is
+/// used for sweeps, so that the per-thread methods do not acquire the per-thread locks. The gap with
+/// [`SLOT_LOCKS_BELOW`] keeps workloads near the threshold from switching back and forth.
+const COARSE_ABOVE: u64 = 180;
+
+/// Returns the exponential moving average, with weight 1/4, of the number of per-thread accesses between consecutive
+/// sweeps, given its previous value `avg` and the number of accesses since the previous sweep.
+fn moving_average(avg: u64, accesses: u64) -> u64 {
+    (avg - avg / 4).saturating_add(accesses / 4)
+}
+
+/// Cell holding the value of a thread under its own lock, which is only acquired in per-slot mode, together with the
+/// number of accesses to the value since the last sweep.
+pub struct AdaptiveCell<V, M> {
+    value: Mutex<V, M>,
+    accesses: AtomicU64,
+}
+
+/// Locking mode of an [`AdaptiveThreadMap`] and the statistics and thresholds it is chosen from.
+#[derive(Debug)]
+pub struct AdaptiveState {
+    slot_locks: AtomicBool,
+    /// Moving average of the number of per-thread accesses between consecutive sweeps.
+    accesses_per_sweep: AtomicU64,
+    slot_locks_below: u64,
+    coarse_above: u64,
+}
+
+impl AdaptiveState {
+    /// Returns `true` in per-slot mode and `false` in coarse mode.
+    pub(crate) fn slot_locks(&self) -> bool {
+        self.slot_locks.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for AdaptiveState {
+    /// Starts in coarse mode, as [`ThreadMap`](crate::ThreadMap).
+    fn default() -> Self {
+        Self {
+            slot_locks: AtomicBool::new(false),
+            accesses_per_sweep: AtomicU64::new(COARSE_ABOVE),
+            slot_locks_below: SLOT_LOCKS_BELOW,
+            coarse_above: COARSE_ABOVE,
+        }
+    }
+}
+
+/// Slot strategy of [`AdaptiveThreadMap`], which stores each value in a cell with its own lock and switches between
+/// two modes. In coarse mode, as with [`CellSlots`](crate::CellSlots), the per-thread locks are not acquired and sweeps
+/// hold the object-level write lock. In per-slot mode, as with [`MutexSlots`](crate::MutexSlots), every access holds
+/// the per-thread lock and sweeps only hold the object-level read lock. In both modes, the per-thread methods hold the
+/// object-level read lock, so that the mode can be switched safely under the object-level write lock.
+#[derive(Debug)]
+pub struct AdaptiveSlots;
+
+impl Strategy for AdaptiveSlots {
+    const NAME: &'static str = "AdaptiveThreadMap";
+
+    const LOCKED_SLOTS: bool = true;
+
+    type State = AdaptiveState;
+
+    type Cell<V, M: RawMutex> = AdaptiveCell<V, M>;
+
+    type Access<'a, V: 'a, S: 'a, R: RawRwLock + 'a, M: RawMutex + 'a> =
+        AdaptiveAccess<'a, V, S, R, M>;
+
+    fn exclusive_sweeps(state: &AdaptiveState) -> bool {
+        !state.slot_locks()
+    }
+
+    fn on_sweep<V, S, M: RawMutex>(
+        state: &AdaptiveState,
+        slots: &Slots<V, Self, S, M>,
+    ) -> Option<bool> {
+        let accesses = slots
+            .values()
+            .map(|cell| cell.accesses.swap(0, Ordering::Relaxed))
+            .sum();
+        // Concurrent sweeps in per-slot mode may interleave their updates, which only makes the average less precise.
+        let avg = moving_average(state.accesses_per_sweep.load(Ordering::Relaxed), accesses);
+        state.accesses_per_sweep.store(avg, Ordering::Relaxed);
+        match state.slot_locks() {
+            true if avg > state.coarse_above => Some(true),
+            false if avg < state.slot_locks_below => Some(false),
+            _ => None,
+        }
+    }
+
+    fn set_exclusive_sweeps<V, S, M: RawMutex>(
+        state: &AdaptiveState,
+        exclusive: bool,
+        _slots: &mut Slots<V, Self, S, M>,
+    ) {
+        state.slot_locks.store(!exclusive, Ordering::Relaxed);
+    }
+
+    fn new_cell<V, M: RawMutex>(v: V, _ticket: u64) -> AdaptiveCell<V, M> {
+        AdaptiveCell {
+            value: Mutex::new(v),
+            accesses: AtomicU64::new(0),
+        }
+    }
+
+    fn into_value<V, M: RawMutex>(cell: Arc<AdaptiveCell<V, M>>) -> Option<V> {
+        // The cell is never shared outside the map, so this is its only reference.
+        Arc::into_inner(cell).map(|c| c.value.into_inner().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    fn is_poisoned<V, M: RawMutex>(cell: &AdaptiveCell<V, M>) -> bool {
+        cell.value.is_poisoned()
+    }
+
+    fn clear_poison<V, M: RawMutex>(cell: &AdaptiveCell<V, M>) {
+        cell.value.clear_poison();
+    }
+
+    #[inline]
+    unsafe fn access<'a, V, S, R: RawRwLock, M: RawMutex>(
+        lock: StateGuard<'a, V, Self, S, R, M>,
+        cell: *const AdaptiveCell<V, M>,
+        wait: Wait,
+    ) -> Result<Option<AdaptiveAccess<'a, V, S, R, M>>, TryLockError<()>> {
+        // SAFETY: the cell is in the map guarded by `lock`, so it is alive while `lock` is held, and `lock` is moved
+        // into the returned value, where it is dropped after the guard.
+        let cell = unsafe { &*cell };
+        if cell.value.is_poisoned() {
+            return Err(TryLockError::Poisoned(PoisonError::new(())));
+        }
+        cell.accesses.fetch_add(1, Ordering::Relaxed);
+        let mut guard = if Self::exclusive_sweeps(lock.strategy()) {
+            None
+        } else {
+            Some(cell.value.lock_wait(wait).map_err(discard_guard)?)
+        };
+        let value = match &mut guard {
+            Some(guard) => &mut **guard as *mut V,
+            None => cell.value.data_ptr(),
+        };
+        Ok(Some(AdaptiveAccess {
+            value,
+            _guard: guard,
+            _lock: lock,
+        }))
+    }
+
+    unsafe fn fold_value<V, M: RawMutex, W>(
+        cell: &AdaptiveCell<V, M>,
+        _ticket: u64,
+        w: W,
+        f: impl FnOnce(W, &V) -> W,
+    ) -> Result<W, PoisonError<()>> {
+        // The per-thread lock is uncontended in coarse mode, where it is only acquired by sweeps, which are exclusive.
+        let v = cell.value.lock().map_err(|_| PoisonError::new(()))?;
+        Ok(f(w, &v))
+    }
+}
+
+impl SlotStrategy for AdaptiveSlots {}
+
+/// Access to the value of a thread in an [`AdaptiveThreadMap`], holding the object-level lock and, in per-slot mode,
+/// the thread's lock.
+pub struct AdaptiveAccess<'a, V: 'a, S: 'a, R: RawRwLock + 'a, M: RawMutex + 'a> {
+    value: *mut V,
+    /// Declared before `_lock` so that it is dropped first, as the mode cannot change while `_lock` is held.
+    _guard: Option<MutexGuard<'a, V, M>>,
+    _lock: StateGuard<'a, V, AdaptiveSlots, S, R, M>,
+}
+
+impl<V, S, R: RawRwLock, M: RawMutex> SlotAccess<V> for AdaptiveAccess<'_, V, S, R, M> {
+    #[inline]
+    fn value(&self) -> &V {
+        // SAFETY: in per-slot mode, `self` holds the thread's lock. In coarse mode, `self` holds an object-level read
+        // lock and the cell belongs to the current thread, or `self` holds the object-level write lock, and all other
+        // accesses to the cell are done under the object-level write lock.
+        unsafe { &*self.value }
+    }
+
+    #[inline]
+    fn value_mut(&mut self) -> &mut V {
+        // SAFETY: as in `Self::value`. `self` is not aliased as the map's reentrancy marker is held while it is used.
+        unsafe { &mut *self.value }
+    }
+}
+
+/// Like [`ThreadMap`](crate::ThreadMap) and [`ThreadMapX`](crate::ThreadMapX),
+/// this type encapsulates the association of [`ThreadId`](std::thread::ThreadId)s to values of type `V`, with the same API.
+/// Rather than fixing the locking discipline at compile time, it picks it at runtime from the observed ratio of
+/// per-thread calls ([`Self::with`], [`Self::with_mut`], [`Self::get`], [`Self::set`], [`Self::current`], etc.) to
+/// sweeps ([`Self::fold`], [`Self::fold_values`], and [`Self::probe`]).
+/// It is an alias of [`ThreadMapBase`] with the [`AdaptiveSlots`] strategy.
+///
+/// Each value has its own [`Mutex`](lock_api::Mutex), and the map is in one of two modes:
+/// - In coarse mode, as with [`ThreadMap`](crate::ThreadMap), the per-thread methods only hold the object-level read
+///   lock, and the methods that access the values of other threads ([`Self::fold`], [`Self::fold_values`],
+///   [`Self::probe`], [`Self::with_thread_mut`], and [`Self::debug_snapshot`]) hold the object-level write lock. This
+///   makes the per-thread methods cheaper when sweeps are rare.
+/// - In per-slot mode, as with [`ThreadMapX`](crate::ThreadMapX), the per-thread methods also hold the per-thread lock,
+///   and the methods that access the values of other threads hold the object-level read lock and the per-thread locks,
+///   so that sweeps do not block the per-thread methods of other threads when they are frequent.
+///
+/// A new map is in coarse mode. After each sweep, the map updates a moving average of the number of per-thread calls
+/// since the previous sweep, and switches to per-slot mode when it falls below a threshold, 120 by default, or back to
+/// coarse mode when it rises above a higher threshold, 180 by default, so that workloads near the boundary do not
+/// switch back and forth. The defaults are rough estimates of where [`ThreadMapX`](crate::ThreadMapX) overtakes
+/// [`ThreadMap`](crate::ThreadMap), which depends on the machine, the number of threads, and the cost of the closures,
+/// so they can be tuned with [`Self::with_switch_thresholds`], e.g., by comparing the two types on the target workload
+/// as the `tmu_tmx_bench` benchmark does. Switching modes takes the object-level write lock.
+///
+/// Unlike [`ThreadMapX`](crate::ThreadMapX), the per-thread methods hold the object-level read lock while their
+/// closures run in both modes, so a thread performing a long operation on its value blocks other threads from
+/// registering their values.
+///
+/// # Example
+///
+/// ```rust
+/// use thread_map::AdaptiveThreadMap;
+/// use std::thread;
+///
+/// let tm = AdaptiveThreadMap::new(|| 0);
+/// thread::scope(|s| {
+///     for i in 0..4 {
+///         let tm = &tm;
+///         s.spawn(move || {
+///             for _ in 0..100 {
+///                 tm.with_mut(|v| *v += i);
+///             }
+///         });
+///     }
+///     let _ = tm.fold_values(0, |z, v| z + v);
+/// });
+/// assert_eq!(600, tm.fold_values(0, |z, v| z + v).unwrap());
+/// ```
+///
+/// # Reentrancy
+///
+/// As with [`ThreadMap`](crate::ThreadMap#reentrancy), reentrant calls are detected: fallible methods return
+/// [`ThreadMapLockError::Reentrant`](crate::ThreadMapLockError::Reentrant), and the other methods panic.
+///
+/// # Thread Safety
+///
+/// `AdaptiveThreadMap<V>` is [`Send`] and [`Sync`] if and only if `V` is [`Send`], the same contract as
+/// [`Mutex`](std::sync::Mutex):
+///
+/// ```rust
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<thread_map::AdaptiveThreadMap<std::cell::Cell<i32>>>();
+/// ```
+///
+/// ```compile_fail
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<thread_map::AdaptiveThreadMap<std::rc::Rc<i32>>>();
+/// ```
+///
+/// # Hashing and Locks
+///
+/// As with [`ThreadMapX`](crate::ThreadMapX), the hasher of the internal [`HashMap`](std::collections::HashMap) is
+/// built by `S`, the object-level lock is a [`lock_api::RwLock`] whose raw lock is `R`, and the per-thread locks are
+/// [`lock_api::Mutex`]es whose raw lock is `M`. See [`Self::with_hasher`] and [`Self::with_raw_locks`].
+pub type AdaptiveThreadMap<V, S = RandomState, R = DefaultRawRwLock, M = DefaultRawMutex> =
+    ThreadMapBase<V, AdaptiveSlots, S, R, M>;
+
+impl<V, S: BuildHasher, R: RawRwLock, M: RawMutex> ThreadMapBase<V, AdaptiveSlots, S, R, M> {
+    /// Configures `self` to switch to per-slot mode when the average number of per-thread calls between consecutive
+    /// sweeps falls below `slot_locks_below`, and back to coarse mode when it rises above `coarse_above`, instead of
+    /// the default thresholds (see [`AdaptiveThreadMap`]). The average starts at `coarse_above`.
+    ///
+    /// # Panics
+    /// - If `slot_locks_below` is greater than `coarse_above`.
+    pub fn with_switch_thresholds(mut self, slot_locks_below: u64, coarse_above: u64) -> Self {
+        assert!(
+            slot_locks_below <= coarse_above,
+            "slot_locks_below ({slot_locks_below}) must not exceed coarse_above ({coarse_above})"
+        );
+        let state = self.strategy_state_mut();
+        state.slot_locks_below = slot_locks_below;
+        state.coarse_above = coarse_above;
+        *state.accesses_per_sweep.get_mut() = coarse_above;
+        self
+    }
+}
+
+/// Guard returned by [`AdaptiveThreadMap::current`] that dereferences to the value associated with the current thread.
+/// It holds the map's object-level read lock, and in per-slot mode the current thread's lock, until dropped. It is not
+/// [`Send`], so it cannot leave the current thread:
+///
+/// ```compile_fail
+/// use std::thread;
+/// use thread_map::AdaptiveThreadMap;
+///
+/// let tm = AdaptiveThreadMap::new(|| 0);
+/// let guard = tm.current();
+/// thread::scope(|s| {
+///     s.spawn(move || *guard);
+/// });
+/// ```
+pub type AdaptiveThreadMapRef<'a, V, S = RandomState, R = DefaultRawRwLock, M = DefaultRawMutex> =
+    ThreadMapBaseRef<'a, V, AdaptiveSlots, S, R, M>;
+
+/// Guard returned by [`AdaptiveThreadMap::current_mut`] that dereferences mutably to the value associated with the
+/// current thread. It holds the same locks as [`AdaptiveThreadMapRef`]. It is not [`Send`], so it cannot leave the
+/// current thread.
+pub type AdaptiveThreadMapRefMut<
+    'a,
+    V,
+    S = RandomState,
+    R = DefaultRawRwLock,
+    M = DefaultRawMutex,
+> = ThreadMapBaseRefMut<'a, V, AdaptiveSlots, S, R, M>;
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod test {
+    use super::{AdaptiveThreadMap, COARSE_ABOVE, SLOT_LOCKS_BELOW};
+    use crate::{ThreadMapLockError, ValueKey};
+    use std::{
+        collections::HashMap,
+        panic::{AssertUnwindSafe, catch_unwind},
+        sync::{Arc, Barrier},
+        thread,
+    };
+
+    const NTHREADS: i32 = 20;
+    const NITER: i32 = 10;
+
+    /// Runs `sweeps` sweeps, each preceded by `accesses` per-thread accesses.
+    fn run(tm: &AdaptiveThreadMap<i32>, sweeps: u64, accesses: u64) {
+        for _ in 0..sweeps {
+            for _ in 0..accesses {
+                tm.with_mut(|v| *v += 1);
+            }
+            tm.fold_values(0, |z, v| z + v).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_lifecycle() {
+        let tm: AdaptiveThreadMap<i32> = AdaptiveThreadMap::default();
+
+        thread::scope(|s| {
+            let tm = &tm;
+            for i in 0..NTHREADS {
+                s.spawn(move || {
+                    for _ in 0..NITER {
+                        tm.with_mut(|v| *v += i);
+                        // Frequent sweeps switch the map to per-slot mode while the other threads run.
+                        tm.probe().unwrap();
+                    }
+                    assert_eq!(i * NITER, tm.get());
+                });
+            }
+        });
+
+        let expected = (0..NTHREADS).map(|i| i * NITER).sum::<i32>();
+        assert_eq!(expected, tm.fold_values(0, |z, v| z + v).unwrap());
+        let mut drained = tm.drain().unwrap().into_values().collect::<Vec<_>>();
+        drained.sort();
+        assert_eq!(
+            (0..NTHREADS).map(|i| i * NITER).collect::<Vec<_>>(),
+            drained
+        );
+    }
+
+    #[test]
+    fn test_mode_switch() {
+        let tm: AdaptiveThreadMap<i32> = AdaptiveThreadMap::default();
+        assert!(!tm.strategy_state().slot_locks());
+
+        run(&tm, 4, COARSE_ABOVE);
+        assert!(!tm.strategy_state().slot_locks());
+
+        run(&tm, 10, 1);
+        assert!(tm.strategy_state().slot_locks());
+
+        // Within the hysteresis band, the mode does not change.
+        run(&tm, 10, (SLOT_LOCKS_BELOW + COARSE_ABOVE) / 2);
+        assert!(tm.strategy_state().slot_locks());
+
+        run(&tm, 10, 2 * COARSE_ABOVE);
+        assert!(!tm.strategy_state().slot_locks());
+
+        let expected = 4 * COARSE_ABOVE
+            + 10
+            + 10 * ((SLOT_LOCKS_BELOW + COARSE_ABOVE) / 2)
+            + 20 * COARSE_ABOVE;
+        assert_eq!(expected as i32, tm.get());
+    }
+
+    #[test]
+    fn test_switch_thresholds() {
+        let tm: AdaptiveThreadMap<i32> =
+            AdaptiveThreadMap::default().with_switch_thresholds(1_000, 2_000);
+
+        // Frequent enough for the custom thresholds but not for the default ones.
+        run(&tm, 10, 4 * COARSE_ABOVE);
+        assert!(tm.strategy_state().slot_locks());
+
+        run(&tm, 10, 4_000);
+        assert!(!tm.strategy_state().slot_locks());
+
+        let res = catch_unwind(|| AdaptiveThreadMap::<i32>::default().with_switch_thresholds(2, 1));
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_slot_mode_not_blocked() {
+        let tm: AdaptiveThreadMap<i32> = AdaptiveThreadMap::default();
+        tm.set(1);
+        run(&tm, 10, 0);
+        assert!(tm.strategy_state().slot_locks());
+
+        let barrier = Barrier::new(2);
+        thread::scope(|s| {
+            s.spawn(|| {
+                tm.set(2);
+                tm.with(|_| {
+                    barrier.wait();
+                    barrier.wait();
+                });
+            });
+            barrier.wait();
+            // In coarse mode, this would wait for the other thread to release the object-level read lock.
+            assert_eq!(
+                Some(1),
+                tm.with_thread(thread::current().id(), |v| *v).unwrap()
+            );
+            assert_eq!(1, tm.get());
+            barrier.wait();
+        });
+        assert_eq!(3, tm.fold_values(0, |z, v| z + v).unwrap());
+    }
+
+    #[test]
+    fn test_retire_on_thread_exit() {
+        let tm = Arc::new(AdaptiveThreadMap::new(|| 0).retire_on_thread_exit(|acc, v| *acc += v));
+
+        let handles = (0..NTHREADS)
+            .map(|i| {
+                let tm = tm.clone();
+                thread::spawn(move || {
+                    tm.set(i);
+                    tm.fold_values(0, |z, v| z + v).unwrap();
+                })
+            })
+            .collect::<Vec<_>>();
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        let expected = (0..NTHREADS).sum::<i32>();
+        assert_eq!(Some(expected), tm.retired().unwrap());
+        assert_eq!(
+            HashMap::from([(ValueKey::Retired, expected)]),
+            tm.probe().unwrap()
+        );
+    }
+
+    #[cfg(feature = "parking_lot")]
+    #[test]
+    fn test_no_poisoning() {
+        let tm: AdaptiveThreadMap<i32> = AdaptiveThreadMap::default();
+        tm.set(1);
+
+        let res = catch_unwind(AssertUnwindSafe(|| {
+            tm.fold_values(0, |_, _| panic!("panicking"))
+        }));
+        assert!(res.is_err());
+
+        assert!(!tm.is_poisoned().unwrap());
+        assert_eq!(1, tm.try_get().unwrap());
+    }
+
+    #[cfg(not(feature = "parking_lot"))]
+    #[test]
+    fn test_poison_recovery() {
+        let tm: AdaptiveThreadMap<i32> = AdaptiveThreadMap::default();
+        tm.set(1);
+
+        let res = catch_unwind(AssertUnwindSafe(|| {
+            tm.fold_values(0, |_, _| panic!("poisoning"))
+        }));
+        assert!(res.is_err());
+        assert!(tm.is_poisoned().unwrap());
+
+        assert!(tm.try_get().is_err());
+        assert_eq!(
+            HashMap::from([(ValueKey::Thread(thread::current().id()), 1)]),
+            tm.drain_poisoned().unwrap()
+        );
+        tm.clear_poison().unwrap();
+        assert!(!tm.is_poisoned().unwrap());
+        assert_eq!(0, tm.get());
+    }
+
+    #[test]
+    fn test_reentrancy() {
+        let tm: AdaptiveThreadMap<i32> = AdaptiveThreadMap::default();
+        for _ in 0..2 {
+            assert_eq!(
+                Err(ThreadMapLockError::Reentrant),
+                tm.with(|_| tm.try_get())
+            );
+            assert_eq!(
+                Ok(Err(ThreadMapLockError::Reentrant)),
+                tm.fold_values(Ok(()), |_, _| tm.try_set(1))
+            );
+            // Switch modes for the second iteration.
+            run(&tm, 10, 0);
+        }
+    }
+}
@@ -0,0 +1,1407 @@
+use crate::{
+    DefaultRawMutex, DefaultRawRwLock, ThreadInfo, ThreadMapLockError, ValueInit, ValueKey,
+    reentrancy::{Entered, enter},
+    slot_cache,
+    sync::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard, Wait},
+    thread_exit::{ExitHook, next_map_id},
+};
+use lock_api::{RawMutex, RawRwLock};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    hash::{BuildHasher, RandomState},
+    mem::replace,
+    ops::{Deref, DerefMut},
+    sync::{
+        Arc, PoisonError, TryLockError,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    thread::{self, ThreadId},
+    time::{Duration, Instant},
+};
+
+/// Map from the [`ThreadId`]s to the cells holding the values of a [`ThreadMapBase`]. The cells are shared through
+/// an `Arc` so that their locations, which are cached by their threads (see [`slot_cache`]), do not change when the
+/// `HashMap` grows.
+pub(crate) type Slots<V, T, S, M> = HashMap<ThreadId, Arc<<T as Strategy>::Cell<V, M>>, S>;
+
+pub(crate) mod strategy {
+    use super::*;
+
+    /// Storage and locking discipline of the values of a [`ThreadMapBase`]. This trait is sealed: it is only
+    /// implemented by the strategies of this crate, through [`SlotStrategy`].
+    pub trait Strategy: Sized {
+        /// Name reported by the [`Debug`] implementation of the maps.
+        const NAME: &'static str;
+
+        /// Whether each value has its own lock, which is poisoned if a thread panics while holding it.
+        const LOCKED_SLOTS: bool;
+
+        /// State of the strategy shared by all the cells of a map.
+        type State: Default;
+
+        /// Cell holding the value of a thread.
+        type Cell<V, M: RawMutex>;
+
+        /// Access to the value of a thread, holding the locks that protect the value.
+        type Access<'a, V: 'a, S: 'a, R: RawRwLock + 'a, M: RawMutex + 'a>: SlotAccess<V>;
+
+        /// Whether the values of threads other than their own are accessed, e.g., by [`ThreadMapBase::fold`], under the
+        /// object-level write lock rather than under the object-level read lock and their own locks. The result only
+        /// changes through [`Self::set_exclusive_sweeps`], i.e., while the object-level write lock is held.
+        fn exclusive_sweeps(_state: &Self::State) -> bool {
+            !Self::LOCKED_SLOTS
+        }
+
+        /// Whether sweeps release the object-level lock before accessing the values, keeping the cells they visit alive
+        /// through their `Arc`s, so that they do not block other threads while they wait for the per-thread locks. This
+        /// requires every access to a value to hold its cell's own lock, and the cells of removed values to be emptied.
+        const DETACHED_SWEEPS: bool = false;
+
+        /// Whether the current thread accesses its value without acquiring the object-level lock, through the location
+        /// of its cell cached together with the ticket of its value (see [`Self::access_cached`]). Cells removed from
+        /// the map are then kept and reused by later registrations instead of being dropped, so that the cached
+        /// locations remain valid while the map is alive.
+        const CACHED_ACCESS: bool = false;
+
+        /// Records a sweep over `slots` by [`ThreadMapBase::fold`] or [`ThreadMapBase::fold_values`], returning the new
+        /// result of [`Self::exclusive_sweeps`] if it should change.
+        fn on_sweep<V, S, M: RawMutex>(
+            _state: &Self::State,
+            _slots: &Slots<V, Self, S, M>,
+        ) -> Option<bool> {
+            None
+        }
+
+        /// Sets the result of [`Self::exclusive_sweeps`]. `_slots` proves that the object-level write lock is held.
+        fn set_exclusive_sweeps<V, S, M: RawMutex>(
+            _state: &Self::State,
+            _exclusive: bool,
+            _slots: &mut Slots<V, Self, S, M>,
+        ) {
+        }
+
+        /// Creates a cell holding `v`, whose ticket, a number that identifies the registration of `v` in the map, is
+        /// `ticket`.
+        fn new_cell<V, M: RawMutex>(v: V, ticket: u64) -> Self::Cell<V, M>;
+
+        /// Returns the ticket of the value in `cell`, if the strategy records it. The ticket of a cell only changes
+        /// while the cell is not in the map.
+        fn ticket<V, M: RawMutex>(_cell: &Self::Cell<V, M>) -> u64 {
+            0
+        }
+
+        /// Puts `v`, whose ticket is `ticket`, in `cell`, which has been removed from the map and emptied, so that the
+        /// cell can be reused. Only called if [`Self::CACHED_ACCESS`] is `true`.
+        fn refill<V, M: RawMutex>(_cell: &Self::Cell<V, M>, _v: V, _ticket: u64) {
+            unreachable!("cells are only reused with cached access")
+        }
+
+        /// Extracts the value of a cell that has been removed from the map, waiting for any access to the value to
+        /// complete. Returns `None` if the value has already been taken.
+        fn into_value<V, M: RawMutex>(cell: Arc<Self::Cell<V, M>>) -> Option<V>;
+
+        /// Returns `true` if the lock of `cell`, if any, is poisoned.
+        fn is_poisoned<V, M: RawMutex>(cell: &Self::Cell<V, M>) -> bool;
+
+        /// Clears the poisoned state of the lock of `cell`, if any.
+        fn clear_poison<V, M: RawMutex>(cell: &Self::Cell<V, M>);
+
+        /// Returns access to the value in `cell`, or `None` if the value has been taken, consuming `lock` as needed.
+        /// The lock of `cell`, if any, is acquired waiting as specified by `wait`.
+        ///
+        /// # Errors
+        /// - [`TryLockError::Poisoned`] if the lock of `cell` is poisoned.
+        /// - [`TryLockError::WouldBlock`] if the lock of `cell` is not acquired in time.
+        ///
+        /// # Safety
+        /// `cell` must be in the map guarded by `lock`, and `lock` must be shared only if `cell` holds the value of
+        /// the current thread or [`Self::exclusive_sweeps`] is `false`.
+        unsafe fn access<'a, V, S, R: RawRwLock, M: RawMutex>(
+            lock: StateGuard<'a, V, Self, S, R, M>,
+            cell: *const Self::Cell<V, M>,
+            wait: Wait,
+        ) -> Result<Option<Self::Access<'a, V, S, R, M>>, TryLockError<()>>;
+
+        /// Returns access to the value in `cell` without the object-level lock, or `None` if the value has been taken
+        /// or its ticket is not `ticket`, i.e., if the cell has been reused since `ticket` was obtained. Only called if
+        /// [`Self::CACHED_ACCESS`] is `true`. The lock of `cell` is acquired waiting as specified by `wait`.
+        ///
+        /// # Errors
+        /// - [`TryLockError::Poisoned`] if the lock of `cell` is poisoned and the ticket of its value is `ticket`.
+        /// - [`TryLockError::WouldBlock`] if the lock of `cell` is not acquired in time.
+        ///
+        /// # Safety
+        /// `cell` must have been created by a map with this strategy that outlives `'a`.
+        unsafe fn access_cached<'a, V, S, R: RawRwLock, M: RawMutex>(
+            _cell: *const Self::Cell<V, M>,
+            _ticket: u64,
+            _wait: Wait,
+        ) -> Result<Option<Self::Access<'a, V, S, R, M>>, TryLockError<()>> {
+            Ok(None)
+        }
+
+        /// Folds the value in `cell`, if it has not been taken and its ticket is `ticket`, into the accumulator `w`
+        /// with `f`.
+        ///
+        /// # Errors
+        /// - [`PoisonError`] if the lock of `cell` is poisoned.
+        ///
+        /// # Safety
+        /// Unless [`Self::DETACHED_SWEEPS`] is `true`, the object-level lock of the map containing `cell` must be held,
+        /// and it must be held exclusively unless [`Self::exclusive_sweeps`] is `false`.
+        unsafe fn fold_value<V, M: RawMutex, W>(
+            cell: &Self::Cell<V, M>,
+            ticket: u64,
+            w: W,
+            f: impl FnOnce(W, &V) -> W,
+        ) -> Result<W, PoisonError<()>>;
+    }
+
+    /// Access to the value of a thread.
+    pub trait SlotAccess<V> {
+        fn value(&self) -> &V;
+
+        fn value_mut(&mut self) -> &mut V;
+    }
+
+    /// Guard of the object-level lock of a [`ThreadMapBase`], held either shared or exclusively.
+    pub struct StateGuard<'a, V: 'a, T: Strategy, S: 'a, R: RawRwLock + 'a, M: RawMutex + 'a> {
+        lock: StateLock<'a, V, T, S, R, M>,
+        strategy: &'a T::State,
+    }
+
+    pub(super) enum StateLock<'a, V, T: Strategy, S, R: RawRwLock, M: RawMutex> {
+        Shared(RwLockReadGuard<'a, Slots<V, T, S, M>, R>),
+        Exclusive(RwLockWriteGuard<'a, Slots<V, T, S, M>, R>),
+    }
+
+    impl<'a, V, T: Strategy, S, R: RawRwLock, M: RawMutex> StateGuard<'a, V, T, S, R, M> {
+        #[inline]
+        pub(super) fn new(lock: StateLock<'a, V, T, S, R, M>, strategy: &'a T::State) -> Self {
+            Self { lock, strategy }
+        }
+
+        pub(super) fn into_lock(self) -> StateLock<'a, V, T, S, R, M> {
+            self.lock
+        }
+
+        /// Returns the state of the strategy of the map guarded by `self`.
+        pub fn strategy(&self) -> &'a T::State {
+            self.strategy
+        }
+    }
+
+    impl<V, T: Strategy, S, R: RawRwLock, M: RawMutex> Deref for StateGuard<'_, V, T, S, R, M> {
+        type Target = Slots<V, T, S, M>;
+
+        fn deref(&self) -> &Self::Target {
+            match &self.lock {
+                StateLock::Shared(lock) => lock,
+                StateLock::Exclusive(lock) => lock,
+            }
+        }
+    }
+}
+
+use strategy::{SlotAccess, StateGuard, StateLock, Strategy};
+
+/// Strategy for storing and locking the values of a [`ThreadMapBase`]. This trait is sealed and implemented by
+/// [`CellSlots`](crate::CellSlots), the strategy of [`ThreadMap`](crate::ThreadMap),
+/// [`MutexSlots`](crate::MutexSlots), the strategy of [`ThreadMapX`](crate::ThreadMapX),
+/// [`AdaptiveSlots`](crate::AdaptiveSlots), the strategy of [`AdaptiveThreadMap`](crate::AdaptiveThreadMap), and
+/// [`LockFreeSlots`](crate::LockFreeSlots), the strategy of [`LockFreeThreadMap`](crate::LockFreeThreadMap).
+pub trait SlotStrategy: Strategy {}
+
+/// Core shared by [`ThreadMap`](crate::ThreadMap), [`ThreadMapX`](crate::ThreadMapX),
+/// [`AdaptiveThreadMap`](crate::AdaptiveThreadMap), and [`LockFreeThreadMap`](crate::LockFreeThreadMap), which are
+/// aliases of this type with different slot strategies `T`. It encapsulates the association of [`ThreadId`]s to values of type `V`,
+/// kept in a [`HashMap`] that uses the hasher built by `S` and is guarded by an object-level [`lock_api::RwLock`]
+/// whose raw lock is `R`. The raw lock of the accumulator of retired values (see [`Self::retire_on_thread_exit`]),
+/// and of the per-thread locks of strategies that have them, is `M`.
+///
+/// The strategy determines how each value is stored and which locks are held when it is accessed. See
+/// [`ThreadMap`](crate::ThreadMap), [`ThreadMapX`](crate::ThreadMapX),
+/// [`AdaptiveThreadMap`](crate::AdaptiveThreadMap), and [`LockFreeThreadMap`](crate::LockFreeThreadMap) for the
+/// semantics of each strategy,
+/// including reentrancy and thread safety.
+pub struct ThreadMapBase<
+    V,
+    T: SlotStrategy,
+    S = RandomState,
+    R = DefaultRawRwLock,
+    M: RawMutex = DefaultRawMutex,
+> {
+    id: u64,
+    state: Arc<RwLock<Slots<V, T, S, M>, R>>,
+    value_init: ValueInit<V>,
+    next_index: AtomicUsize,
+    /// Changed whenever values of arbitrary threads are removed, invalidating the threads' cached value locations.
+    generation: AtomicU64,
+    exit_hook: Option<ExitHook>,
+    retired: Arc<Mutex<Option<V>, M>>,
+    /// Cells removed from the map, kept for reuse if the strategy has cached access (see [`Strategy::CACHED_ACCESS`]).
+    spare: Arc<Mutex<Vec<Arc<T::Cell<V, M>>>, M>>,
+    strategy: T::State,
+}
+
+impl<V, T: SlotStrategy> ThreadMapBase<V, T> {
+    pub(crate) fn from_value_init(value_init: ValueInit<V>) -> Self {
+        Self::from_value_init_and_hasher(value_init, RandomState::new())
+    }
+
+    /// Creates a new instance, with `value_init` used to create the initial value for each thread.
+    pub fn new(value_init: fn() -> V) -> Self {
+        Self::from_value_init(ValueInit::Fn(value_init))
+    }
+
+    /// Creates a new instance, with the closure `value_init` used to create the initial value for each thread.
+    /// Unlike [`Self::new`], `value_init` can capture state, e.g., runtime configuration shared through an `Arc`.
+    pub fn from_fn(value_init: impl Fn() -> V + Send + Sync + 'static) -> Self {
+        Self::from_value_init(ValueInit::Closure(Box::new(value_init)))
+    }
+
+    /// Creates a new instance, with the closure `value_init` used to create the initial value for each thread.
+    /// Unlike [`Self::from_fn`], `value_init` receives a [`ThreadInfo`] that identifies the thread being initialized,
+    /// e.g., to label per-thread buffers or to seed per-thread random number generators deterministically.
+    pub fn from_thread_fn(value_init: impl Fn(&ThreadInfo) -> V + Send + Sync + 'static) -> Self {
+        Self::from_value_init(ValueInit::ThreadAware(Box::new(value_init)))
+    }
+}
+
+impl<V, T: SlotStrategy, S: BuildHasher> ThreadMapBase<V, T, S> {
+    /// Creates a new instance, with the closure `value_init` used to create the initial value for each thread, as with
+    /// [`Self::from_fn`], whose internal [`HashMap`] uses `hash_builder` to hash the [`ThreadId`]s. See
+    /// [`HashMap::with_hasher`].
+    pub fn with_hasher(
+        value_init: impl Fn() -> V + Send + Sync + 'static,
+        hash_builder: S,
+    ) -> Self {
+        Self::with_raw_locks(value_init, hash_builder)
+    }
+}
+
+impl<V, T: SlotStrategy, S: BuildHasher, R: RawRwLock, M: RawMutex> ThreadMapBase<V, T, S, R, M> {
+    fn from_value_init_and_hasher(value_init: ValueInit<V>, hash_builder: S) -> Self {
+        Self {
+            id: next_map_id(),
+            state: Arc::new(RwLock::new(HashMap::with_hasher(hash_builder))),
+            value_init,
+            next_index: AtomicUsize::new(0),
+            generation: AtomicU64::new(0),
+            exit_hook: None,
+            retired: Arc::new(Mutex::new(None)),
+            spare: Arc::new(Mutex::new(Vec::new())),
+            strategy: T::State::default(),
+        }
+    }
+
+    /// Same as [`Self::with_hasher`] but with the raw lock types `R` and `M` given by the type of `Self`, e.g., to use
+    /// spin locks for very short critical sections or instrumented locks for contention profiling.
+    pub fn with_raw_locks(
+        value_init: impl Fn() -> V + Send + Sync + 'static,
+        hash_builder: S,
+    ) -> Self {
+        Self::from_value_init_and_hasher(ValueInit::Closure(Box::new(value_init)), hash_builder)
+    }
+
+    /// Configures `self` so that when a thread that has a value in `self` exits, its value is removed from `self` and
+    /// passed to `f`, together with the thread's [`ThreadId`]. `f` runs on the exiting thread.
+    ///
+    /// This prevents `self` from growing without bound when it is accessed from many short-lived threads.
+    /// Removal takes place when the exiting thread's thread-local storage is destroyed, which completes before a
+    /// [`JoinHandle::join`](std::thread::JoinHandle::join) on the thread returns. It may not take place for the
+    /// main thread, as the process can terminate first. Threads that registered a value with `self` before this
+    /// method was called are not affected.
+    pub fn on_thread_exit(mut self, f: impl Fn(ThreadId, V) + Send + Sync + 'static) -> Self
+    where
+        V: Send + 'static,
+        T: 'static,
+        T::Cell<V, M>: Send + Sync,
+        S: Send + Sync + 'static,
+        R: Send + Sync + 'static,
+        M: Send + Sync + 'static,
+    {
+        let id = self.id;
+        let state = Arc::downgrade(&self.state);
+        let spare = Arc::downgrade(&self.spare);
+        let f = Arc::new(f);
+        let action = move |tid| {
+            let (Some(state), Some(spare)) = (state.upgrade(), spare.upgrade()) else {
+                return;
+            };
+            let mut lock = state.write().unwrap_or_else(PoisonError::into_inner);
+            slot_cache::invalidate(id);
+            let Some(slot) = lock.remove(&tid) else {
+                return;
+            };
+            drop(lock);
+            if let Some(v) = Self::take_value(&spare, slot) {
+                f(tid, v);
+            }
+        };
+        self.exit_hook = Some(ExitHook::new(self.id, action));
+        self
+    }
+
+    /// Configures `self` so that when a thread that has a value in `self` exits, its value is removed from `self` and
+    /// dropped. See [`Self::on_thread_exit`] for details.
+    pub fn remove_on_thread_exit(self) -> Self
+    where
+        V: Send + 'static,
+        T: 'static,
+        T::Cell<V, M>: Send + Sync,
+        S: Send + Sync + 'static,
+        R: Send + Sync + 'static,
+        M: Send + Sync + 'static,
+    {
+        self.on_thread_exit(|_, _| ())
+    }
+
+    /// Configures `self` so that when a thread that has a value in `self` exits, its value is removed from `self` and
+    /// merged into an accumulator of retired values with `merge`. The first retired value becomes the initial
+    /// accumulator. `merge` runs on the exiting thread.
+    ///
+    /// This keeps the totals from transient threads without keeping an entry for each exited thread. The accumulator
+    /// is included by [`Self::fold`], [`Self::fold_values`], and [`Self::probe`], with the key [`ValueKey::Retired`],
+    /// and can be inspected with [`Self::retired`] and extracted with [`Self::take_retired`]; it is not included by
+    /// [`Self::drain`]. See [`Self::on_thread_exit`] for when values are removed.
+    pub fn retire_on_thread_exit(
+        mut self,
+        merge: impl Fn(&mut V, V) + Send + Sync + 'static,
+    ) -> Self
+    where
+        V: Send + 'static,
+        T: 'static,
+        T::Cell<V, M>: Send + Sync,
+        S: Send + Sync + 'static,
+        R: Send + Sync + 'static,
+        M: Send + Sync + 'static,
+    {
+        let id = self.id;
+        let state = Arc::downgrade(&self.state);
+        let retired = Arc::downgrade(&self.retired);
+        let spare = Arc::downgrade(&self.spare);
+        let merge = Arc::new(merge);
+        let action = move |tid| {
+            let (Some(state), Some(retired), Some(spare)) =
+                (state.upgrade(), retired.upgrade(), spare.upgrade())
+            else {
+                return;
+            };
+            let mut lock = state.write().unwrap_or_else(PoisonError::into_inner);
+            slot_cache::invalidate(id);
+            let Some(slot) = lock.remove(&tid) else {
+                return;
+            };
+            // Acquire the accumulator lock before releasing the object-level lock so that sweeps never observe the
+            // value as neither live nor retired.
+            let mut acc = retired.lock().unwrap_or_else(PoisonError::into_inner);
+            drop(lock);
+            let Some(v) = Self::take_value(&spare, slot) else {
+                return;
+            };
+            match acc.as_mut() {
+                Some(acc) => merge(acc, v),
+                None => *acc = Some(v),
+            }
+        };
+        self.exit_hook = Some(ExitHook::new(self.id, action));
+        self
+    }
+
+    /// Invokes `f` mutably on the value associated with the [`ThreadId`] of the current thread and returns the invocation result.
+    /// If there is no value associated with the current thread then the initializer provided at construction
+    /// ([`Self::new`], [`Self::from_fn`], or [`Self::from_thread_fn`]) is used to instantiate an initial associated
+    /// value before `f` is applied. Neither the initializer nor `f` runs under the object-level write lock, so they
+    /// never block other threads from registering their values.
+    ///
+    /// # Panics
+    /// - If an internal lock is poisoned. See [`Self::try_with_mut`] for a non-panicking alternative.
+    #[inline]
+    pub fn with_mut<W>(&self, f: impl FnOnce(&mut V) -> W) -> W {
+        self.try_with_mut(f).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Same as [`Self::with_mut`] but returns an error instead of panicking.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if an internal lock is poisoned.
+    #[inline]
+    pub fn try_with_mut<W>(&self, f: impl FnOnce(&mut V) -> W) -> Result<W, ThreadMapLockError> {
+        let mut current = self.lock_current(Wait::Forever)?;
+        Ok(f(current.access.value_mut()))
+    }
+
+    /// Same as [`Self::try_with_mut`] but waits at most `timeout` for each internal lock, and returns
+    /// [`ThreadMapLockError::Timeout`] if a lock is not acquired by then. Neither the value initializer nor `f` is
+    /// subject to the timeout. The locks are polled, as the raw lock traits do not support timed acquisition.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError::Timeout`] if an internal lock is not acquired within `timeout`.
+    /// - [`ThreadMapLockError`] if an internal lock is poisoned.
+    pub fn try_with_mut_timeout<W>(
+        &self,
+        timeout: Duration,
+        f: impl FnOnce(&mut V) -> W,
+    ) -> Result<W, ThreadMapLockError> {
+        let wait = match Instant::now().checked_add(timeout) {
+            Some(deadline) => Wait::Until(deadline),
+            None => Wait::Forever,
+        };
+        let mut current = self.lock_current(wait)?;
+        Ok(f(current.access.value_mut()))
+    }
+
+    /// Same as [`Self::try_with_mut`] but returns [`ThreadMapLockError::WouldBlock`] instead of waiting if an
+    /// internal lock is held by another thread, e.g., during a sweep by [`Self::fold`].
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError::WouldBlock`] if an internal lock cannot be acquired without blocking.
+    /// - [`ThreadMapLockError`] if an internal lock is poisoned.
+    pub fn try_with_mut_nonblocking<W>(
+        &self,
+        f: impl FnOnce(&mut V) -> W,
+    ) -> Result<W, ThreadMapLockError> {
+        let mut current = self.lock_current(Wait::Never)?;
+        Ok(f(current.access.value_mut()))
+    }
+
+    /// Returns access to the value associated with the current thread, which is initialized first if there is none.
+    /// The internal locks are acquired waiting as specified by `wait`.
+    #[inline]
+    fn lock_current(&self, wait: Wait) -> Result<Current<'_, V, T, S, R, M>, ThreadMapLockError> {
+        let entered = enter(self.id)?;
+        if T::CACHED_ACCESS
+            && let Some((ticket, cell)) = slot_cache::lookup(self.id)
+        {
+            // SAFETY: the cell was cached by the current thread from `self`, which keeps its cells alive, as the
+            // strategy has cached access.
+            let access = unsafe { T::access_cached(cell as *const T::Cell<V, M>, ticket, wait) }
+                .map_err(|e| Self::thread_lock_error(wait, e))?;
+            if let Some(access) = access {
+                return Ok(Current {
+                    access,
+                    _entered: entered,
+                });
+            }
+        }
+        loop {
+            let lock = self.read_state(wait)?;
+            let generation = self.generation.load(Ordering::Relaxed);
+            // With cached access, the cache was checked above, and its entries record tickets instead of generations.
+            let cached = match T::CACHED_ACCESS {
+                true => None,
+                false => slot_cache::get(self.id, generation),
+            };
+            let cell = match cached {
+                Some(cell) => cell as *const T::Cell<V, M>,
+                None => {
+                    let tid = thread::current().id();
+                    let Some(slot) = lock.get(&tid) else {
+                        drop(lock);
+                        self.register(tid, wait)?;
+                        continue;
+                    };
+                    let cell = Arc::as_ptr(slot);
+                    let key = match T::CACHED_ACCESS {
+                        true => T::ticket(slot),
+                        false => generation,
+                    };
+                    slot_cache::put(self.id, key, cell as *const ());
+                    cell
+                }
+            };
+            // SAFETY: the cell holds the value of the current thread and is in the map guarded by `lock`, as it was
+            // either looked up above or cached when the generation was the current one and has not been invalidated
+            // since, and it cannot be removed while `lock` is held.
+            let access = unsafe {
+                T::access(
+                    StateGuard::new(StateLock::Shared(lock), &self.strategy),
+                    cell,
+                    wait,
+                )
+            }
+            .map_err(|e| Self::thread_lock_error(wait, e))?;
+            if let Some(access) = access {
+                return Ok(Current {
+                    access,
+                    _entered: entered,
+                });
+            }
+            // The value was removed by another thread (e.g., with `drain`) after the lock was released, hence the loop.
+        }
+    }
+
+    /// Associates a new initial value with the current thread, whose [`ThreadId`] is `tid`. The initializer runs
+    /// before the object-level write lock is acquired, waiting as specified by `wait`, so that only the insertion of
+    /// the value blocks other threads.
+    fn register(&self, tid: ThreadId, wait: Wait) -> Result<(), ThreadMapLockError> {
+        let index = self.next_index.fetch_add(1, Ordering::Relaxed);
+        let v0 = self.value_init.call(index);
+        let ticket = index as u64;
+        let spare = match T::CACHED_ACCESS {
+            true => self
+                .spare
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .pop(),
+            false => None,
+        };
+        let cell = match spare {
+            Some(cell) => {
+                T::refill(&cell, v0, ticket);
+                cell
+            }
+            None => Arc::new(T::new_cell(v0, ticket)),
+        };
+        let mut lock = self.write_state(wait)?;
+        lock.insert(tid, cell);
+        if let Some(hook) = &self.exit_hook {
+            hook.on_register(tid);
+        }
+        Ok(())
+    }
+
+    /// Acquires the object-level lock in shared mode, waiting as specified by `wait`.
+    #[inline]
+    fn read_state(
+        &self,
+        wait: Wait,
+    ) -> Result<RwLockReadGuard<'_, Slots<V, T, S, M>, R>, ThreadMapLockError> {
+        self.state
+            .read_wait(wait)
+            .map_err(|e| self.state_lock_error(wait, e))
+    }
+
+    /// Acquires the object-level lock in exclusive mode, waiting as specified by `wait`.
+    fn write_state(
+        &self,
+        wait: Wait,
+    ) -> Result<RwLockWriteGuard<'_, Slots<V, T, S, M>, R>, ThreadMapLockError> {
+        self.state
+            .write_wait(wait)
+            .map_err(|e| self.state_lock_error(wait, e))
+    }
+
+    /// Converts an error from acquiring the object-level lock waiting as specified by `wait`.
+    fn state_lock_error<G>(&self, wait: Wait, e: TryLockError<G>) -> ThreadMapLockError {
+        match e {
+            TryLockError::Poisoned(_) => self.poisoned_state_error(),
+            TryLockError::WouldBlock => Self::expired_wait_error(wait),
+        }
+    }
+
+    /// Converts an error from acquiring the lock of the current thread's value waiting as specified by `wait`.
+    fn thread_lock_error(wait: Wait, e: TryLockError<()>) -> ThreadMapLockError {
+        match e {
+            TryLockError::Poisoned(_) => {
+                ThreadMapLockError::PoisonedThreadLock(thread::current().id())
+            }
+            TryLockError::WouldBlock => Self::expired_wait_error(wait),
+        }
+    }
+
+    /// Returns the error reported when a lock is not acquired within the wait specified by `wait`.
+    fn expired_wait_error(wait: Wait) -> ThreadMapLockError {
+        match wait {
+            Wait::Until(_) => ThreadMapLockError::Timeout,
+            Wait::Never | Wait::Forever => ThreadMapLockError::WouldBlock,
+        }
+    }
+
+    /// Returns the error reported when the object-level lock is poisoned, which identifies the thread that poisoned
+    /// it.
+    pub(crate) fn poisoned_state_error(&self) -> ThreadMapLockError {
+        // The poisoner is recorded before the lock is marked as poisoned, and never cleared.
+        let tid = self
+            .state
+            .poisoner()
+            .expect("a poisoned lock records its poisoner");
+        ThreadMapLockError::PoisonedObjectLock(tid)
+    }
+
+    /// Acquires the object-level lock in the mode required to access the values of threads other than the current
+    /// one: exclusive if the strategy requires exclusive sweeps, and shared otherwise.
+    fn lock_others(&self) -> Result<StateGuard<'_, V, T, S, R, M>, ThreadMapLockError> {
+        loop {
+            let exclusive = T::exclusive_sweeps(&self.strategy);
+            let lock = if exclusive {
+                StateLock::Exclusive(self.write_state(Wait::Forever)?)
+            } else {
+                StateLock::Shared(self.read_state(Wait::Forever)?)
+            };
+            // The mode only changes under the object-level write lock, so it cannot change while `lock` is held.
+            if T::exclusive_sweeps(&self.strategy) == exclusive {
+                return Ok(StateGuard::new(lock, &self.strategy));
+            }
+        }
+    }
+
+    /// Reports a sweep over the values guarded by `lock`, which was acquired with [`Self::lock_others`], to the
+    /// strategy, and changes the mode of subsequent sweeps if the strategy requests it.
+    fn end_sweep(&self, lock: StateGuard<'_, V, T, S, R, M>) {
+        let Some(exclusive) = T::on_sweep(&self.strategy, &lock) else {
+            return;
+        };
+        let mut lock = match lock.into_lock() {
+            StateLock::Exclusive(lock) => lock,
+            StateLock::Shared(lock) => {
+                drop(lock);
+                // The sweep has completed, so a poisoned lock only means that the mode is left unchanged.
+                let Ok(lock) = self.state.write() else {
+                    return;
+                };
+                lock
+            }
+        };
+        T::set_exclusive_sweeps(&self.strategy, exclusive, &mut lock);
+    }
+
+    /// Starts a sweep over the values guarded by `lock`, which was acquired with [`Self::lock_others`]. If the
+    /// strategy has detached sweeps, the cells are collected and `lock` is released, ending the sweep for the strategy.
+    fn begin_sweep<'a>(&'a self, lock: StateGuard<'a, V, T, S, R, M>) -> Sweep<'a, V, T, S, R, M> {
+        if !T::DETACHED_SWEEPS {
+            return Sweep::Locked(lock);
+        }
+        let cells = lock
+            .iter()
+            .map(|(tid, slot)| (*tid, T::ticket(slot), Arc::clone(slot)))
+            .collect();
+        self.end_sweep(lock);
+        Sweep::Detached(cells)
+    }
+
+    /// Ends a sweep started with [`Self::begin_sweep`].
+    fn finish_sweep(&self, sweep: Sweep<'_, V, T, S, R, M>) {
+        if let Sweep::Locked(lock) = sweep {
+            self.end_sweep(lock);
+        }
+    }
+
+    /// Invokes `f` on the value associated with the [`ThreadId`] of the current thread and returns the invocation result.
+    /// If there is no value associated with the current thread then the initializer provided at construction
+    /// ([`Self::new`], [`Self::from_fn`], or [`Self::from_thread_fn`]) is used to instantiate an initial associated
+    /// value before `f` is applied.
+    ///
+    /// # Panics
+    /// - If an internal lock is poisoned. See [`Self::try_with`] for a non-panicking alternative.
+    #[inline]
+    pub fn with<W>(&self, f: impl FnOnce(&V) -> W) -> W {
+        let g = |v: &mut V| f(v);
+        self.with_mut(g)
+    }
+
+    /// Same as [`Self::with`] but returns an error instead of panicking.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if an internal lock is poisoned.
+    #[inline]
+    pub fn try_with<W>(&self, f: impl FnOnce(&V) -> W) -> Result<W, ThreadMapLockError> {
+        let g = |v: &mut V| f(v);
+        self.try_with_mut(g)
+    }
+
+    /// Same as [`Self::try_with`] but waits at most `timeout` for each internal lock. See
+    /// [`Self::try_with_mut_timeout`].
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError::Timeout`] if an internal lock is not acquired within `timeout`.
+    /// - [`ThreadMapLockError`] if an internal lock is poisoned.
+    pub fn try_with_timeout<W>(
+        &self,
+        timeout: Duration,
+        f: impl FnOnce(&V) -> W,
+    ) -> Result<W, ThreadMapLockError> {
+        let g = |v: &mut V| f(v);
+        self.try_with_mut_timeout(timeout, g)
+    }
+
+    /// Same as [`Self::try_with`] but does not wait for the internal locks. See
+    /// [`Self::try_with_mut_nonblocking`].
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError::WouldBlock`] if an internal lock cannot be acquired without blocking.
+    /// - [`ThreadMapLockError`] if an internal lock is poisoned.
+    pub fn try_with_nonblocking<W>(
+        &self,
+        f: impl FnOnce(&V) -> W,
+    ) -> Result<W, ThreadMapLockError> {
+        let g = |v: &mut V| f(v);
+        self.try_with_mut_nonblocking(g)
+    }
+
+    /// Returns a clone of the value associated with the current thread.
+    ///
+    /// # Panics
+    /// - If an internal lock is poisoned. See [`Self::try_get`] for a non-panicking alternative.
+    #[inline]
+    pub fn get(&self) -> V
+    where
+        V: Clone,
+    {
+        self.with(|v| v.clone())
+    }
+
+    /// Same as [`Self::get`] but returns an error instead of panicking.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if an internal lock is poisoned.
+    pub fn try_get(&self) -> Result<V, ThreadMapLockError>
+    where
+        V: Clone,
+    {
+        self.try_with(|v| v.clone())
+    }
+
+    /// Sets the value associated with the current thread to `v`.
+    ///
+    /// # Panics
+    /// - If an internal lock is poisoned. See [`Self::try_set`] for a non-panicking alternative.
+    #[inline]
+    pub fn set(&self, v: V) {
+        self.with_mut(|v0| *v0 = v);
+    }
+
+    /// Same as [`Self::set`] but returns an error instead of panicking.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if an internal lock is poisoned.
+    pub fn try_set(&self, v: V) -> Result<(), ThreadMapLockError> {
+        self.try_with_mut(|v0| *v0 = v)
+    }
+
+    /// Returns a guard that dereferences to the value associated with the current thread, which is initialized first
+    /// if there is none, as with [`Self::with`]. Unlike [`Self::with`], it works well with `?` and early returns.
+    /// The locks that protect the value are held until the guard is dropped, so other accesses to the value, e.g., by
+    /// [`Self::fold`], block until then. While the guard is alive, calls to methods of `self` from the current thread
+    /// are reentrant (see [`ThreadMap`](crate::ThreadMap#reentrancy)).
+    ///
+    /// # Panics
+    /// - If an internal lock is poisoned. See [`Self::try_current`] for a non-panicking alternative.
+    pub fn current(&self) -> ThreadMapBaseRef<'_, V, T, S, R, M> {
+        self.try_current().unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Same as [`Self::current`] but returns an error instead of panicking.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if an internal lock is poisoned.
+    pub fn try_current(&self) -> Result<ThreadMapBaseRef<'_, V, T, S, R, M>, ThreadMapLockError> {
+        self.lock_current(Wait::Forever).map(ThreadMapBaseRef)
+    }
+
+    /// Same as [`Self::current`] but the guard dereferences mutably to the value associated with the current thread.
+    ///
+    /// # Panics
+    /// - If an internal lock is poisoned. See [`Self::try_current_mut`] for a non-panicking alternative.
+    pub fn current_mut(&self) -> ThreadMapBaseRefMut<'_, V, T, S, R, M> {
+        self.try_current_mut().unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Same as [`Self::current_mut`] but returns an error instead of panicking.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if an internal lock is poisoned.
+    pub fn try_current_mut(
+        &self,
+    ) -> Result<ThreadMapBaseRefMut<'_, V, T, S, R, M>, ThreadMapLockError> {
+        self.lock_current(Wait::Forever).map(ThreadMapBaseRefMut)
+    }
+
+    /// Removes the value associated with the current thread, if any, and returns it. A subsequent access from the
+    /// current thread instantiates a new initial value as on the thread's first access.
+    ///
+    /// # Panics
+    /// - If an internal lock is poisoned. See [`Self::try_take`] for a non-panicking alternative.
+    pub fn take(&self) -> Option<V> {
+        self.try_take().unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Same as [`Self::take`] but returns an error instead of panicking.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if an internal lock is poisoned, in which case the value is left in `self`.
+    pub fn try_take(&self) -> Result<Option<V>, ThreadMapLockError> {
+        let _entered = enter(self.id)?;
+        let tid = thread::current().id();
+        let mut lock = self.write_state(Wait::Forever)?;
+        if lock.get(&tid).is_some_and(|slot| T::is_poisoned(slot)) {
+            return Err(ThreadMapLockError::PoisonedThreadLock(tid));
+        }
+        slot_cache::invalidate(self.id);
+        let slot = lock.remove(&tid);
+        drop(lock);
+        Ok(slot.and_then(|slot| Self::take_value(&self.spare, slot)))
+    }
+
+    /// Sets the value associated with the current thread to `v` and returns the previous value, which is
+    /// initialized first if there was no value associated with the current thread.
+    ///
+    /// # Panics
+    /// - If an internal lock is poisoned. See [`Self::try_replace`] for a non-panicking alternative.
+    pub fn replace(&self, v: V) -> V {
+        self.with_mut(|v0| replace(v0, v))
+    }
+
+    /// Same as [`Self::replace`] but returns an error instead of panicking.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if an internal lock is poisoned.
+    pub fn try_replace(&self, v: V) -> Result<V, ThreadMapLockError> {
+        self.try_with_mut(|v0| replace(v0, v))
+    }
+
+    /// Removes and drops the value associated with the current thread, if any, freeing the associated memory.
+    /// A subsequent access from the current thread instantiates a new initial value as on the thread's first access.
+    ///
+    /// # Panics
+    /// - If an internal lock is poisoned. See [`Self::try_remove`] for a non-panicking alternative.
+    pub fn remove(&self) {
+        self.try_remove().unwrap_or_else(|e| panic!("{e}"));
+    }
+
+    /// Same as [`Self::remove`] but returns an error instead of panicking.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if an internal lock is poisoned.
+    pub fn try_remove(&self) -> Result<(), ThreadMapLockError> {
+        self.try_take().map(drop)
+    }
+
+    /// Resets the value associated with the current thread to a new initial value, instantiated as on the thread's
+    /// first access, including a new [`ThreadInfo::index`] for initializers passed to [`Self::from_thread_fn`].
+    ///
+    /// # Panics
+    /// - If an internal lock is poisoned. See [`Self::try_reset`] for a non-panicking alternative.
+    pub fn reset(&self) {
+        self.try_reset().unwrap_or_else(|e| panic!("{e}"));
+    }
+
+    /// Same as [`Self::reset`] but returns an error instead of panicking.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if an internal lock is poisoned.
+    pub fn try_reset(&self) -> Result<(), ThreadMapLockError> {
+        self.try_remove()?;
+        self.try_with_mut(|_| ())
+    }
+
+    /// Invokes `f` mutably on the value associated with the thread whose [`ThreadId`] is `tid` and returns the
+    /// invocation result, or `None` if there is no value associated with `tid`. Unlike [`Self::with_mut`], `tid`
+    /// need not be the current thread, e.g., a supervisor thread can inspect or reset the value of a worker thread.
+    /// See [`ThreadMap`](crate::ThreadMap) and [`ThreadMapX`](crate::ThreadMapX) for the locks held while `f` runs.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if the object-level lock or the per-thread lock of `tid` is poisoned.
+    pub fn with_thread_mut<W>(
+        &self,
+        tid: ThreadId,
+        f: impl FnOnce(&mut V) -> W,
+    ) -> Result<Option<W>, ThreadMapLockError> {
+        let _entered = enter(self.id)?;
+        let lock = self.lock_others()?;
+        let Some(slot) = lock.get(&tid) else {
+            return Ok(None);
+        };
+        let cell = Arc::as_ptr(slot);
+        // SAFETY: the cell is in the map guarded by `lock`, which is held in the mode required to access the values
+        // of other threads.
+        let access = unsafe { T::access(lock, cell, Wait::Forever) }
+            .map_err(|_| ThreadMapLockError::PoisonedThreadLock(tid))?;
+        Ok(access.map(|mut access| f(access.value_mut())))
+    }
+
+    /// Invokes `f` on the value associated with the thread whose [`ThreadId`] is `tid` and returns the invocation
+    /// result, or `None` if there is no value associated with `tid`. See [`Self::with_thread_mut`].
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if the object-level lock or the per-thread lock of `tid` is poisoned.
+    pub fn with_thread<W>(
+        &self,
+        tid: ThreadId,
+        f: impl FnOnce(&V) -> W,
+    ) -> Result<Option<W>, ThreadMapLockError> {
+        let g = |v: &mut V| f(v);
+        self.with_thread_mut(tid, g)
+    }
+
+    /// Returns `true` if there is a value associated with the thread whose [`ThreadId`] is `tid`.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if the object-level lock is poisoned.
+    pub fn contains(&self, tid: ThreadId) -> Result<bool, ThreadMapLockError> {
+        let _entered = enter(self.id)?;
+        Ok(self.read_state(Wait::Forever)?.contains_key(&tid))
+    }
+
+    /// Returns a [`HashMap`] with the values associated with each [`ThreadId`] key and clears `self`'s state.
+    /// The object-level write lock is released before the values are extracted, so if a thread is running a closure
+    /// on its value without holding the object-level lock, e.g., with [`ThreadMapX::with_mut`](crate::ThreadMapX),
+    /// this method waits for the closure to complete without blocking other threads.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if the object-level lock or any per-thread lock is poisoned, in which case `self`'s
+    ///   state is left unchanged.
+    pub fn drain(&self) -> Result<HashMap<ThreadId, V>, ThreadMapLockError> {
+        let _entered = enter(self.id)?;
+        let mut lock = self.write_state(Wait::Forever)?;
+        // Check before taking the state so that no values are lost if a per-thread lock is poisoned.
+        if let Some((tid, _)) = lock.iter().find(|(_, slot)| T::is_poisoned(slot)) {
+            return Err(ThreadMapLockError::PoisonedThreadLock(*tid));
+        }
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        let slots = lock.drain().collect::<Vec<_>>();
+        drop(lock);
+        Ok(self.take_slots(slots))
+    }
+
+    /// Extracts the values of `slots`, which have been removed from `self`'s state.
+    fn take_slots(&self, slots: Vec<(ThreadId, Arc<T::Cell<V, M>>)>) -> HashMap<ThreadId, V> {
+        slots
+            .into_iter()
+            .filter_map(|(k, slot)| Some((k, Self::take_value(&self.spare, slot)?)))
+            .collect()
+    }
+
+    /// Extracts the value of `slot`, which has been removed from the map, keeping the cell in `spare` for reuse if the
+    /// strategy has cached access.
+    fn take_value(
+        spare: &Mutex<Vec<Arc<T::Cell<V, M>>>, M>,
+        slot: Arc<T::Cell<V, M>>,
+    ) -> Option<V> {
+        if !T::CACHED_ACCESS {
+            return T::into_value(slot);
+        }
+        let v = T::into_value(Arc::clone(&slot));
+        spare
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(slot);
+        v
+    }
+
+    /// Folds every association in `self` into an accumulator (with initial value `z`) by applying an operation `f`,
+    /// returning the final result. If `self` was configured with [`Self::retire_on_thread_exit`], the accumulated
+    /// value of the exited threads, if any, is folded first, with the key [`ValueKey::Retired`]. The accumulator lock
+    /// is held while the values are folded, so that [`Self::take_retired`] cannot take the accumulator in the middle
+    /// of the fold, and a value retired during the fold is folded exactly once.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if an internal lock is poisoned.
+    pub fn fold<W>(
+        &self,
+        z: W,
+        mut f: impl FnMut(W, (ValueKey, &V)) -> W,
+    ) -> Result<W, ThreadMapLockError> {
+        let _entered = enter(self.id)?;
+        let lock = self.lock_others()?;
+        // A retiring thread removes its value under the object-level write lock and then merges it while holding the
+        // accumulator lock, which is also the only other way the accumulator changes, through `take_retired`. As `acc`
+        // is acquired while `lock` is held and kept until the values are folded, every value is either in the map when
+        // `lock` is acquired and merged after the fold, or already in the accumulator. The accumulator lock is acquired
+        // after the object-level lock, in the same order as when retiring a value, and only to read the accumulator, so
+        // a panic in `f` does not poison it.
+        let acc = self
+            .retired
+            .lock_read()
+            .map_err(|_| ThreadMapLockError::PoisonedRetiredLock)?;
+        let sweep = self.begin_sweep(lock);
+        let w = acc.iter().fold(z, |w, v| f(w, (ValueKey::Retired, v)));
+        let w = Self::fold_entries(&sweep, w, |w, (tid, v)| f(w, (ValueKey::Thread(tid), v)))?;
+        drop(acc);
+        self.finish_sweep(sweep);
+        Ok(w)
+    }
+
+    /// Folds the entries visited by `sweep`.
+    fn fold_entries<W>(
+        sweep: &Sweep<V, T, S, R, M>,
+        z: W,
+        mut f: impl FnMut(W, (ThreadId, &V)) -> W,
+    ) -> Result<W, ThreadMapLockError> {
+        sweep.try_fold(z, |w, tid, ticket, cell| {
+            // SAFETY: `sweep` was started with `begin_sweep`.
+            unsafe { T::fold_value(cell, ticket, w, |w, v| f(w, (tid, v))) }
+                .map_err(|_| ThreadMapLockError::PoisonedThreadLock(tid))
+        })
+    }
+
+    /// Folds every value in `self` into an accumulator (with initial value `z`) by applying an operation `f`,
+    /// returning the final result. As with [`Self::fold`], the accumulated value of the exited threads, if any, is
+    /// folded first.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if an internal lock is poisoned.
+    pub fn fold_values<W>(
+        &self,
+        z: W,
+        mut f: impl FnMut(W, &V) -> W,
+    ) -> Result<W, ThreadMapLockError> {
+        self.fold(z, |w, (_, v)| f(w, v))
+    }
+
+    /// Returns a [`HashMap`] with clones of the values associated with each [`ThreadId`] key, and of the accumulated
+    /// value of the retired threads, if any, with the key [`ValueKey::Retired`], at the time the probe was executed.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if an internal lock is poisoned.
+    pub fn probe(&self) -> Result<HashMap<ValueKey, V>, ThreadMapLockError>
+    where
+        V: Clone,
+    {
+        let z = HashMap::<ValueKey, V>::new();
+        self.fold(z, |mut w, (key, v)| {
+            w.insert(key, v.clone());
+            w
+        })
+    }
+
+    /// Returns a snapshot of `self` whose [`Debug`] output includes the values associated with each [`ThreadId`].
+    /// Unlike the [`Debug`] implementation of `self`, which only reports the [`ThreadId`]s, the snapshot holds the
+    /// object-level lock until it is dropped, so no values can be added or removed while it is formatted, and values
+    /// cannot be modified while they are formatted. With [`ThreadMapX`](crate::ThreadMapX), the snapshot holds the
+    /// handles of the values instead, so values can be added or removed, and removed values are not formatted. Values
+    /// whose per-thread lock is poisoned are formatted as `<poisoned>`.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if the object-level lock is poisoned.
+    pub fn debug_snapshot(&self) -> Result<impl Debug + '_, ThreadMapLockError>
+    where
+        V: Debug,
+    {
+        struct DebugSnapshot<'a, V, T: SlotStrategy, S, R: RawRwLock, M: RawMutex> {
+            sweep: Sweep<'a, V, T, S, R, M>,
+            _entered: Entered,
+        }
+
+        impl<V: Debug, T: SlotStrategy, S, R: RawRwLock, M: RawMutex> Debug
+            for DebugSnapshot<'_, V, T, S, R, M>
+        {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                let mut d = f.debug_map();
+                let _ = self.sweep.try_fold((), |(), tid, ticket, cell| {
+                    // SAFETY: `sweep` was started with `begin_sweep`.
+                    let res =
+                        unsafe { T::fold_value(cell, ticket, &mut d, |d, v| d.entry(&tid, v)) };
+                    if res.is_err() {
+                        d.entry(&tid, &format_args!("<poisoned>"));
+                    }
+                    Ok::<_, ()>(())
+                });
+                d.finish()
+            }
+        }
+
+        let entered = enter(self.id)?;
+        Ok(DebugSnapshot {
+            sweep: self.begin_sweep(self.lock_others()?),
+            _entered: entered,
+        })
+    }
+
+    /// Returns a clone of the accumulated value of the threads retired by [`Self::retire_on_thread_exit`], or `None`
+    /// if no thread has been retired since `self` was created or [`Self::take_retired`] was last called.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if the accumulator lock is poisoned.
+    pub fn retired(&self) -> Result<Option<V>, ThreadMapLockError>
+    where
+        V: Clone,
+    {
+        let _entered = enter(self.id)?;
+        let acc = self
+            .retired
+            .lock_read()
+            .map_err(|_| ThreadMapLockError::PoisonedRetiredLock)?;
+        Ok(acc.clone())
+    }
+
+    /// Returns the accumulated value of the threads retired by [`Self::retire_on_thread_exit`], if any, leaving
+    /// no accumulated value in its place.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if the accumulator lock is poisoned.
+    pub fn take_retired(&self) -> Result<Option<V>, ThreadMapLockError> {
+        let _entered = enter(self.id)?;
+        let mut acc = self
+            .retired
+            .lock()
+            .map_err(|_| ThreadMapLockError::PoisonedRetiredLock)?;
+        Ok(acc.take())
+    }
+
+    /// Returns `true` if `self`'s object-level lock, the lock of its accumulator of retired values, or any of its
+    /// per-thread locks is poisoned, which happens when a thread panics while holding the lock.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError::Reentrant`] if called while the current thread is accessing `self`.
+    pub fn is_poisoned(&self) -> Result<bool, ThreadMapLockError> {
+        let _entered = enter(self.id)?;
+        if self.state.is_poisoned() || self.retired.is_poisoned() {
+            return Ok(true);
+        }
+        if !T::LOCKED_SLOTS {
+            return Ok(false);
+        }
+        let lock = self.state.read().unwrap_or_else(PoisonError::into_inner);
+        Ok(lock.values().any(|slot| T::is_poisoned(slot)))
+    }
+
+    /// Clears the poisoned state of `self`'s object-level lock, the lock of its accumulator of retired values, and its
+    /// per-thread locks, if any, so that subsequent method calls can succeed.
+    /// Callers should make sure the values in `self` are in a consistent state, or remove them with
+    /// [`Self::drain_poisoned`], before clearing the poisoned state.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError::Reentrant`] if called while the current thread is accessing `self`.
+    pub fn clear_poison(&self) -> Result<(), ThreadMapLockError> {
+        let _entered = enter(self.id)?;
+        self.state.clear_poison();
+        self.retired.clear_poison();
+        if T::LOCKED_SLOTS {
+            let lock = self.state.read().unwrap_or_else(PoisonError::into_inner);
+            for slot in lock.values() {
+                T::clear_poison(slot);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns `true` if `self`'s object-level lock is poisoned.
+    pub(crate) fn is_state_poisoned(&self) -> bool {
+        self.state.is_poisoned()
+    }
+
+    /// Returns `true` if the lock of `self`'s accumulator of retired values is poisoned.
+    pub(crate) fn is_retired_poisoned(&self) -> bool {
+        self.retired.is_poisoned()
+    }
+
+    /// Returns the state of the slot strategy of `self`.
+    #[cfg(test)]
+    pub(crate) fn strategy_state(&self) -> &T::State {
+        &self.strategy
+    }
+
+    /// Returns the number of cells held by `self`, in the map or kept for reuse.
+    #[cfg(test)]
+    pub(crate) fn cell_count(&self) -> usize {
+        let len = self
+            .state
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len();
+        len + self
+            .spare
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+
+    /// Poisons the lock of `self`'s accumulator of retired values, as a panic while the accumulator is updated would.
+    #[cfg(all(test, not(feature = "parking_lot")))]
+    pub(crate) fn poison_retired(&self) {
+        let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _acc = self.retired.lock();
+            panic!("poisoning");
+        }));
+    }
+
+    /// Returns the state of the slot strategy of `self` mutably, for configuration methods.
+    pub(crate) fn strategy_state_mut(&mut self) -> &mut T::State {
+        &mut self.strategy
+    }
+
+    /// Same as [`Self::drain`] but succeeds even if `self`'s object-level lock, the lock of its accumulator of retired
+    /// values, or any of its per-thread locks is poisoned. Unlike [`Self::drain`], it also takes the accumulated value
+    /// of the retired threads, if any, with the key [`ValueKey::Retired`], so that no value is lost when the poisoned
+    /// state is then cleared. The poisoned state of the object-level lock and of the accumulator lock is not cleared;
+    /// see [`Self::clear_poison`].
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError::Reentrant`] if called while the current thread is accessing `self`.
+    pub fn drain_poisoned(&self) -> Result<HashMap<ValueKey, V>, ThreadMapLockError> {
+        let _entered = enter(self.id)?;
+        let mut lock = self.state.write().unwrap_or_else(PoisonError::into_inner);
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        let slots = lock.drain().collect::<Vec<_>>();
+        // Taken under the object-level lock, so that the value of a retiring thread is either drained with the slots or
+        // already merged into the accumulator.
+        let acc = self
+            .retired
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        drop(lock);
+        let mut drained = self
+            .take_slots(slots)
+            .into_iter()
+            .map(|(tid, v)| (ValueKey::Thread(tid), v))
+            .collect::<HashMap<_, _>>();
+        drained.extend(acc.map(|v| (ValueKey::Retired, v)));
+        Ok(drained)
+    }
+}
+
+/// Access to the value associated with the current thread, holding the map's reentrancy marker.
+struct Current<'a, V: 'a, T: SlotStrategy, S: 'a, R: RawRwLock + 'a, M: RawMutex + 'a> {
+    /// Declared before `_entered` so that the locks are released first.
+    access: T::Access<'a, V, S, R, M>,
+    _entered: Entered,
+}
+
+/// Values visited by a sweep, i.e., by a method that accesses the values of threads other than the current one.
+enum Sweep<'a, V: 'a, T: SlotStrategy, S: 'a, R: RawRwLock + 'a, M: RawMutex + 'a> {
+    /// The object-level lock, held in the mode required to access the values of other threads.
+    Locked(StateGuard<'a, V, T, S, R, M>),
+    /// The cells in the map when the sweep started, with the tickets of their values, collected under the object-level
+    /// lock, which has been released.
+    Detached(Vec<(ThreadId, u64, Arc<T::Cell<V, M>>)>),
+}
+
+impl<V, T: SlotStrategy, S, R: RawRwLock, M: RawMutex> Sweep<'_, V, T, S, R, M> {
+    /// Folds the cells visited by `self`, with the tickets of their values, into an accumulator (with initial value
+    /// `z`) with `f`, stopping at the first error.
+    fn try_fold<W, E>(
+        &self,
+        z: W,
+        mut f: impl FnMut(W, ThreadId, u64, &T::Cell<V, M>) -> Result<W, E>,
+    ) -> Result<W, E> {
+        match self {
+            Self::Locked(lock) => lock
+                .iter()
+                .try_fold(z, |w, (tid, slot)| f(w, *tid, T::ticket(slot), slot)),
+            Self::Detached(cells) => cells
+                .iter()
+                .try_fold(z, |w, (tid, ticket, cell)| f(w, *tid, *ticket, cell)),
+        }
+    }
+}
+
+/// Guard returned by [`ThreadMapBase::current`] that dereferences to the value associated with the current thread.
+/// It holds the locks that protect the value until dropped. It is not [`Send`], so it cannot leave the current thread.
+pub struct ThreadMapBaseRef<
+    'a,
+    V: 'a,
+    T: SlotStrategy,
+    S: 'a = RandomState,
+    R: RawRwLock + 'a = DefaultRawRwLock,
+    M: RawMutex + 'a = DefaultRawMutex,
+>(Current<'a, V, T, S, R, M>);
+
+impl<V, T: SlotStrategy, S, R: RawRwLock, M: RawMutex> Deref
+    for ThreadMapBaseRef<'_, V, T, S, R, M>
+{
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        self.0.access.value()
+    }
+}
+
+impl<V: Debug, T: SlotStrategy, S, R: RawRwLock, M: RawMutex> Debug
+    for ThreadMapBaseRef<'_, V, T, S, R, M>
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(self.0.access.value(), f)
+    }
+}
+
+/// Guard returned by [`ThreadMapBase::current_mut`] that dereferences mutably to the value associated with the
+/// current thread. It holds the locks that protect the value until dropped. It is not [`Send`], so it cannot leave the
+/// current thread.
+pub struct ThreadMapBaseRefMut<
+    'a,
+    V: 'a,
+    T: SlotStrategy,
+    S: 'a = RandomState,
+    R: RawRwLock + 'a = DefaultRawRwLock,
+    M: RawMutex + 'a = DefaultRawMutex,
+>(Current<'a, V, T, S, R, M>);
+
+impl<V, T: SlotStrategy, S, R: RawRwLock, M: RawMutex> Deref
+    for ThreadMapBaseRefMut<'_, V, T, S, R, M>
+{
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        self.0.access.value()
+    }
+}
+
+impl<V, T: SlotStrategy, S, R: RawRwLock, M: RawMutex> DerefMut
+    for ThreadMapBaseRefMut<'_, V, T, S, R, M>
+{
+    fn deref_mut(&mut self) -> &mut V {
+        self.0.access.value_mut()
+    }
+}
+
+impl<V: Debug, T: SlotStrategy, S, R: RawRwLock, M: RawMutex> Debug
+    for ThreadMapBaseRefMut<'_, V, T, S, R, M>
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(self.0.access.value(), f)
+    }
+}
+
+/// Reports the [`ThreadId`]s that have values in `self`, but not the values, which may be concurrently modified by
+/// their threads. Use [`ThreadMapBase::debug_snapshot`] to format the values. Never blocks: if the object-level lock
+/// is not immediately available, the [`ThreadId`]s are not reported.
+impl<V, T: SlotStrategy, S, R: RawRwLock, M: RawMutex> Debug for ThreadMapBase<V, T, S, R, M> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut d = f.debug_struct(T::NAME);
+        match self.state.try_read() {
+            Ok(lock) => d.field("thread_ids", &lock.keys().collect::<Vec<_>>()),
+            Err(_) => d.field("thread_ids", &format_args!("<locked>")),
+        };
+        d.finish_non_exhaustive()
+    }
+}
+
+impl<V: Default, T: SlotStrategy> Default for ThreadMapBase<V, T> {
+    fn default() -> Self {
+        Self::new(V::default)
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod test {
+    use super::{SlotStrategy, ThreadMapBase};
+    use crate::{CellSlots, MutexSlots};
+    use std::thread;
+
+    const NTHREADS: i32 = 20;
+
+    fn sum_in_threads<T: SlotStrategy>(tm: &ThreadMapBase<i32, T>) -> i32
+    where
+        ThreadMapBase<i32, T>: Sync,
+    {
+        thread::scope(|s| {
+            for i in 0..NTHREADS {
+                s.spawn(move || tm.set(i));
+            }
+        });
+        tm.with_mut(|v| *v = NTHREADS);
+        tm.fold_values(0, |z, v| z + v).unwrap()
+    }
+
+    #[test]
+    fn test_generic_over_strategy() {
+        let expected = (0..=NTHREADS).sum::<i32>();
+        assert_eq!(
+            expected,
+            sum_in_threads(&ThreadMapBase::<_, CellSlots>::default())
+        );
+        assert_eq!(
+            expected,
+            sum_in_threads(&ThreadMapBase::<_, MutexSlots>::default())
+        );
+        assert_eq!(
+            "ThreadMapX { thread_ids: [], .. }",
+            format!("{:?}", ThreadMapBase::<i32, MutexSlots>::default())
+        );
+    }
+}
@@ -0,0 +1,560 @@
+use crate::{
+    DefaultRawMutex, DefaultRawRwLock,
+    sync::{Mutex, MutexGuard, Wait},
+    thread_map_base::{
+        SlotStrategy, ThreadMapBase, ThreadMapBaseRef, ThreadMapBaseRefMut,
+        strategy::{SlotAccess, StateGuard, Strategy},
+    },
+};
+use lock_api::{RawMutex, RawRwLock};
+use std::{
+    hash::RandomState,
+    sync::{
+        Arc, PoisonError, TryLockError,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+/// Cell holding the value of a thread under its own lock, together with the ticket of the value, which identifies its
+/// registration. The value is `None` once it has been removed from the map. The cell is then kept by the map and reused
+/// for a later registration, with a new ticket, so that a thread holding the cached location of the cell can tell
+/// that its value is gone.
+pub struct TicketCell<V, M> {
+    value: Mutex<Option<V>, M>,
+    ticket: AtomicU64,
+}
+
+/// Slot strategy of [`LockFreeThreadMap`], which stores each value in a cell with its own lock, like
+/// [`MutexSlots`](crate::MutexSlots), and lets each thread access its value through the cached location of its cell,
+/// without acquiring the object-level lock. Cells are never dropped while the map is alive, so that cached locations
+/// remain valid, and accesses check the ticket of the value found in the cell.
+#[derive(Debug)]
+pub struct LockFreeSlots;
+
+impl Strategy for LockFreeSlots {
+    const NAME: &'static str = "LockFreeThreadMap";
+
+    const LOCKED_SLOTS: bool = true;
+
+    const DETACHED_SWEEPS: bool = true;
+
+    const CACHED_ACCESS: bool = true;
+
+    type State = ();
+
+    type Cell<V, M: RawMutex> = TicketCell<V, M>;
+
+    type Access<'a, V: 'a, S: 'a, R: RawRwLock + 'a, M: RawMutex + 'a> = TicketSlot<'a, V, M>;
+
+    fn new_cell<V, M: RawMutex>(v: V, ticket: u64) -> TicketCell<V, M> {
+        TicketCell {
+            value: Mutex::new(Some(v)),
+            ticket: AtomicU64::new(ticket),
+        }
+    }
+
+    #[inline]
+    fn ticket<V, M: RawMutex>(cell: &TicketCell<V, M>) -> u64 {
+        cell.ticket.load(Ordering::Relaxed)
+    }
+
+    fn refill<V, M: RawMutex>(cell: &TicketCell<V, M>, v: V, ticket: u64) {
+        // The ticket is stored before the value's lock is released, so a thread that finds the value under that lock
+        // also finds its ticket.
+        cell.ticket.store(ticket, Ordering::Relaxed);
+        cell.value.clear_poison();
+        *cell.value.lock().unwrap_or_else(PoisonError::into_inner) = Some(v);
+    }
+
+    fn into_value<V, M: RawMutex>(cell: Arc<TicketCell<V, M>>) -> Option<V> {
+        cell.value
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take()
+    }
+
+    fn is_poisoned<V, M: RawMutex>(cell: &TicketCell<V, M>) -> bool {
+        cell.value.is_poisoned()
+    }
+
+    fn clear_poison<V, M: RawMutex>(cell: &TicketCell<V, M>) {
+        cell.value.clear_poison();
+    }
+
+    #[inline]
+    unsafe fn access<'a, V, S, R: RawRwLock, M: RawMutex>(
+        lock: StateGuard<'a, V, Self, S, R, M>,
+        cell: *const TicketCell<V, M>,
+        wait: Wait,
+    ) -> Result<Option<TicketSlot<'a, V, M>>, TryLockError<()>> {
+        // SAFETY: the cell is in the map guarded by `lock`, so its ticket cannot change while `lock` is held.
+        let ticket = Self::ticket(unsafe { &*cell });
+        drop(lock);
+        // SAFETY: the cell belongs to the map guarded by `lock`, which is borrowed for `'a`.
+        unsafe { Self::access_cached::<V, S, R, M>(cell, ticket, wait) }
+    }
+
+    #[inline]
+    unsafe fn access_cached<'a, V, S, R: RawRwLock, M: RawMutex>(
+        cell: *const TicketCell<V, M>,
+        ticket: u64,
+        wait: Wait,
+    ) -> Result<Option<TicketSlot<'a, V, M>>, TryLockError<()>> {
+        // SAFETY: the caller guarantees that the map of the cell, which keeps its cells alive, outlives `'a`.
+        let cell = unsafe { &*cell };
+        let (guard, poisoned) = match cell.value.lock_wait(wait) {
+            Ok(guard) => (guard, false),
+            Err(TryLockError::Poisoned(e)) => (e.into_inner(), true),
+            Err(TryLockError::WouldBlock) => return Err(TryLockError::WouldBlock),
+        };
+        // The value may have been removed by another thread (e.g., with `drain`), and the cell reused for a later
+        // registration, since `ticket` was obtained.
+        if guard.is_none() || Self::ticket(cell) != ticket {
+            return Ok(None);
+        }
+        if poisoned {
+            return Err(TryLockError::Poisoned(PoisonError::new(())));
+        }
+        Ok(Some(TicketSlot(guard)))
+    }
+
+    unsafe fn fold_value<V, M: RawMutex, W>(
+        cell: &TicketCell<V, M>,
+        ticket: u64,
+        w: W,
+        f: impl FnOnce(W, &V) -> W,
+    ) -> Result<W, PoisonError<()>> {
+        let (v, poisoned) = match cell.value.lock() {
+            Ok(v) => (v, false),
+            Err(e) => (e.into_inner(), true),
+        };
+        // The value may have been taken, and the cell reused for a later registration, since the sweep started.
+        match v.as_ref() {
+            Some(_) if Self::ticket(cell) != ticket => Ok(w),
+            Some(_) if poisoned => Err(PoisonError::new(())),
+            Some(v) => Ok(f(w, v)),
+            None => Ok(w),
+        }
+    }
+}
+
+impl SlotStrategy for LockFreeSlots {}
+
+/// Access to the value of a thread in a [`LockFreeThreadMap`], holding the thread's lock.
+pub struct TicketSlot<'a, V, M: RawMutex>(MutexGuard<'a, Option<V>, M>);
+
+impl<V, M: RawMutex> SlotAccess<V> for TicketSlot<'_, V, M> {
+    #[inline]
+    fn value(&self) -> &V {
+        self.0
+            .as_ref()
+            .expect("value cannot be removed while its lock is held")
+    }
+
+    #[inline]
+    fn value_mut(&mut self) -> &mut V {
+        self.0
+            .as_mut()
+            .expect("value cannot be removed while its lock is held")
+    }
+}
+
+/// Like [`ThreadMapX`](crate::ThreadMapX), this type encapsulates the association of
+/// [`ThreadId`](std::thread::ThreadId)s to values of type `V`, with a [`Mutex`](lock_api::Mutex) for each value.
+/// It differs from [`ThreadMapX`](crate::ThreadMapX) in that the per-thread methods ([`Self::with`],
+/// [`Self::with_mut`], [`Self::get`], [`Self::set`], [`Self::current`], etc.) do not acquire the object-level lock
+/// once the current thread's value is registered: each thread caches the location of its cell and only acquires the
+/// value's lock. Registrations of new values, and removals, still acquire the object-level write lock briefly to update
+/// the [`HashMap`](std::collections::HashMap) that indexes the cells by [`ThreadId`](std::thread::ThreadId), so
+/// lookups by [`ThreadId`](std::thread::ThreadId) (e.g., [`Self::with_thread`] and [`Self::contains`]) are hash
+/// lookups. It is an alias of [`ThreadMapBase`] with the [`LockFreeSlots`] strategy.
+///
+/// # Cell Reuse
+///
+/// So that the cached locations remain valid, the cells of removed values (e.g., with [`Self::take`], [`Self::drain`],
+/// or on thread exit, see [`Self::remove_on_thread_exit`]) are emptied and kept by the map, and reused by later
+/// registrations. The memory used by the map is therefore bounded by the largest number of values it held at once,
+/// not by the number of threads that ever accessed it. Each registration gives its value a new ticket, which an
+/// access through a cached location checks, so a thread whose value was removed never accesses a value registered
+/// later by another thread in the same cell.
+///
+/// # Sweeps
+///
+/// As with [`ThreadMapX`](crate::ThreadMapX), sweeps ([`Self::fold`], [`Self::fold_values`], [`Self::probe`], and
+/// [`Self::debug_snapshot`]) only hold the object-level read lock while they collect the cells, and then lock the
+/// values one at a time, so they may or may not observe values registered while they run.
+///
+/// # Example
+///
+/// ```rust
+/// use std::thread;
+/// use thread_map::LockFreeThreadMap;
+///
+/// let tm = LockFreeThreadMap::<i32>::default();
+/// thread::scope(|s| {
+///     for i in 0..8 {
+///         let tm = &tm;
+///         s.spawn(move || tm.with_mut(|v| *v += i));
+///     }
+/// });
+/// assert_eq!(28, tm.fold_values(0, |z, v| z + v).unwrap());
+/// ```
+///
+/// # Reentrancy
+///
+/// As with [`ThreadMapX`](crate::ThreadMapX), calling a method of a [`LockFreeThreadMap`] from within a closure
+/// passed to a method of the same instance, or while holding a value returned by [`Self::current`],
+/// [`Self::current_mut`], or [`Self::debug_snapshot`], is detected: fallible methods return
+/// [`ThreadMapLockError::Reentrant`](crate::ThreadMapLockError::Reentrant), and the other methods panic.
+///
+/// # Thread Safety
+///
+/// `LockFreeThreadMap<V>` is [`Send`] and [`Sync`] if and only if `V` is [`Send`], the same contract as
+/// [`ThreadMapX`](crate::ThreadMapX):
+///
+/// ```compile_fail
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<thread_map::LockFreeThreadMap<std::rc::Rc<i32>>>();
+/// ```
+///
+/// # Hashing and Locks
+///
+/// As with [`ThreadMapX`](crate::ThreadMapX), the hasher and the raw locks can be chosen with [`Self::with_hasher`]
+/// and [`Self::with_raw_locks`].
+pub type LockFreeThreadMap<V, S = RandomState, R = DefaultRawRwLock, M = DefaultRawMutex> =
+    ThreadMapBase<V, LockFreeSlots, S, R, M>;
+
+/// Guard returned by [`LockFreeThreadMap::current`] that dereferences to the value associated with the current
+/// thread. It holds the current thread's lock until dropped, and is not [`Send`].
+pub type LockFreeThreadMapRef<'a, V, S = RandomState, R = DefaultRawRwLock, M = DefaultRawMutex> =
+    ThreadMapBaseRef<'a, V, LockFreeSlots, S, R, M>;
+
+/// Guard returned by [`LockFreeThreadMap::current_mut`] that dereferences mutably to the value associated with the
+/// current thread. It holds the current thread's lock until dropped, and is not [`Send`].
+pub type LockFreeThreadMapRefMut<
+    'a,
+    V,
+    S = RandomState,
+    R = DefaultRawRwLock,
+    M = DefaultRawMutex,
+> = ThreadMapBaseRefMut<'a, V, LockFreeSlots, S, R, M>;
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod test {
+    use super::LockFreeThreadMap;
+    use crate::{ThreadMapLockError, ValueKey};
+    use std::{
+        collections::HashMap,
+        panic::{AssertUnwindSafe, catch_unwind},
+        sync::{Arc, Barrier},
+        thread::{self},
+        time::Duration,
+    };
+
+    const NTHREADS: i32 = 20;
+    const NITER: i32 = 10;
+    const SLEEP_MICROS: u64 = 10;
+
+    fn update_value((i0, v0): &mut (i32, i32), i: i32) {
+        *i0 = i;
+        *v0 += i;
+    }
+
+    #[test]
+    fn test_lifecycle() {
+        let tm: LockFreeThreadMap<(i32, i32)> = LockFreeThreadMap::default();
+
+        thread::scope(|s| {
+            let tm = &tm;
+            for i in 0..NTHREADS {
+                s.spawn(move || {
+                    for _ in 0..NITER {
+                        thread::sleep(Duration::from_micros(SLEEP_MICROS));
+                        tm.with_mut(move |p: &mut (i32, i32)| update_value(p, i));
+                    }
+                    let value = tm.get();
+                    assert_eq!((i, i * NITER), value);
+                });
+            }
+
+            for _ in 0..NITER {
+                tm.with_mut(move |p: &mut (i32, i32)| update_value(p, NTHREADS))
+            }
+        });
+
+        let expected = (0..=NTHREADS)
+            .map(|i| (i, i * NITER))
+            .collect::<HashMap<_, _>>();
+        let expected_sum = expected.values().sum::<i32>();
+
+        let sum = tm.fold_values(0, |z, (_, v)| z + v).unwrap();
+        assert_eq!(expected_sum, sum);
+
+        let probed = tm.probe().unwrap().into_values().collect::<HashMap<_, _>>();
+        assert_eq!(expected, probed);
+
+        let dumped = tm.drain().unwrap().into_values().collect::<HashMap<_, _>>();
+        assert_eq!(expected, dumped);
+        assert!(tm.probe().unwrap().is_empty());
+
+        // The current thread's value is re-initialized after being drained.
+        assert_eq!((0, 0), tm.get());
+    }
+
+    #[test]
+    fn test_from_thread_fn() {
+        let tm: LockFreeThreadMap<usize> = LockFreeThreadMap::from_thread_fn(|info| info.index());
+
+        thread::scope(|s| {
+            let tm = &tm;
+            for _ in 0..NTHREADS {
+                s.spawn(move || tm.get());
+            }
+        });
+
+        let mut indices = tm
+            .fold_values(Vec::new(), |mut z, index| {
+                z.push(*index);
+                z
+            })
+            .unwrap();
+        indices.sort();
+        assert_eq!((0..NTHREADS as usize).collect::<Vec<_>>(), indices);
+    }
+
+    #[cfg(feature = "parking_lot")]
+    #[test]
+    fn test_no_poisoning() {
+        let tm: LockFreeThreadMap<i32> = LockFreeThreadMap::default();
+        tm.set(1);
+
+        let res = catch_unwind(AssertUnwindSafe(|| tm.with(|_| panic!("panicking"))));
+        assert!(res.is_err());
+        let res = catch_unwind(AssertUnwindSafe(|| {
+            tm.fold_values(0, |_, _| panic!("panicking"))
+        }));
+        assert!(res.is_err());
+
+        assert!(!tm.is_poisoned().unwrap());
+        assert_eq!(1, tm.try_get().unwrap());
+        assert_eq!(1, tm.fold_values(0, |z, v| z + v).unwrap());
+    }
+
+    #[cfg(not(feature = "parking_lot"))]
+    #[test]
+    fn test_poison_recovery() {
+        use crate::ThreadMapLockError;
+
+        let tm: LockFreeThreadMap<i32> = LockFreeThreadMap::default();
+
+        thread::scope(|s| {
+            let tm = &tm;
+            for i in 0..NTHREADS {
+                s.spawn(move || tm.set(i));
+            }
+        });
+        tm.set(NTHREADS);
+        assert!(!tm.is_poisoned().unwrap());
+
+        let res = catch_unwind(AssertUnwindSafe(|| tm.with(|_| panic!("poisoning"))));
+        assert!(res.is_err());
+        assert!(tm.is_poisoned().unwrap());
+        let err = ThreadMapLockError::PoisonedThreadLock(thread::current().id());
+        assert_eq!(Err(err), tm.try_get());
+        assert_eq!(Err(err), tm.drain().map(|_| ()));
+
+        let expected_sum = (0..=NTHREADS).sum::<i32>();
+        let drained = tm.drain_poisoned().unwrap();
+        assert_eq!(expected_sum, drained.values().sum::<i32>());
+        // The poisoned per-thread lock was removed with the drained values, and its poison is cleared on reuse.
+        assert!(!tm.is_poisoned().unwrap());
+
+        tm.clear_poison().unwrap();
+        assert!(!tm.is_poisoned().unwrap());
+        assert_eq!(0, tm.get());
+        assert_eq!(0, tm.fold_values(0, |z, v| z + v).unwrap());
+    }
+
+    #[test]
+    fn test_retire_on_thread_exit() {
+        let tm: Arc<LockFreeThreadMap<i32>> =
+            Arc::new(LockFreeThreadMap::default().retire_on_thread_exit(|acc, v| *acc += v));
+
+        let handles = (0..NTHREADS)
+            .map(|i| {
+                let tm = tm.clone();
+                thread::spawn(move || tm.set(i))
+            })
+            .collect::<Vec<_>>();
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        tm.set(NTHREADS);
+        let retired_sum = (0..NTHREADS).sum::<i32>();
+        assert_eq!(Some(retired_sum), tm.retired().unwrap());
+        let expected = HashMap::from([
+            (ValueKey::Thread(thread::current().id()), NTHREADS),
+            (ValueKey::Retired, retired_sum),
+        ]);
+        assert_eq!(expected, tm.probe().unwrap());
+        let folded = tm
+            .fold(HashMap::new(), |mut z, (key, v)| {
+                *z.entry(key).or_insert(0) += v;
+                z
+            })
+            .unwrap();
+        assert_eq!(expected, folded);
+        assert_eq!(
+            retired_sum + NTHREADS,
+            tm.fold_values(0, |z, v| z + v).unwrap()
+        );
+
+        assert_eq!(Some(retired_sum), tm.take_retired().unwrap());
+        assert_eq!(
+            HashMap::from([(ValueKey::Thread(thread::current().id()), NTHREADS)]),
+            tm.probe().unwrap()
+        );
+        assert_eq!(NTHREADS, tm.fold_values(0, |z, v| z + v).unwrap());
+    }
+
+    #[test]
+    fn test_with_thread() {
+        let tm: LockFreeThreadMap<i32> = LockFreeThreadMap::default();
+        let barrier = Barrier::new(2);
+
+        thread::scope(|s| {
+            let tm = &tm;
+            let barrier = &barrier;
+            let worker = s.spawn(move || {
+                tm.set(1);
+                barrier.wait(); // value set
+                barrier.wait(); // value inspected and reset by supervisor
+                tm.get()
+            });
+            let tid = worker.thread().id();
+
+            barrier.wait();
+            assert!(tm.contains(tid).unwrap());
+            assert!(!tm.contains(thread::current().id()).unwrap());
+            assert_eq!(Some(1), tm.with_thread(tid, |v| *v).unwrap());
+            assert_eq!(Some(()), tm.with_thread_mut(tid, |v| *v = 42).unwrap());
+            barrier.wait();
+
+            assert_eq!(42, worker.join().unwrap());
+        });
+    }
+
+    #[test]
+    fn test_registration_not_blocked() {
+        let tm: LockFreeThreadMap<i32> = LockFreeThreadMap::default();
+        let barrier = Barrier::new(2);
+
+        thread::scope(|s| {
+            let tm = &tm;
+            let barrier = &barrier;
+            s.spawn(move || {
+                tm.with_mut(|v| {
+                    barrier.wait(); // closure running
+                    barrier.wait(); // other thread registered
+                    *v += 1;
+                })
+            });
+
+            barrier.wait();
+            tm.set(1);
+            assert_eq!(Some(1), tm.take());
+            *tm.current_mut() += 2;
+            assert_eq!(2, *tm.current());
+            barrier.wait();
+        });
+
+        assert_eq!(3, tm.fold_values(0, |z, v| z + v).unwrap());
+        let tid = thread::current().id();
+        let out = format!("{:?}", tm.debug_snapshot().unwrap());
+        assert!(out.contains(&format!("{tid:?}: 2")));
+    }
+
+    #[test]
+    fn test_cell_reuse() {
+        let tm: Arc<LockFreeThreadMap<i32>> =
+            Arc::new(LockFreeThreadMap::default().remove_on_thread_exit());
+        tm.set(-1);
+
+        for i in 0..NTHREADS {
+            let tm = tm.clone();
+            thread::spawn(move || tm.set(i)).join().unwrap();
+        }
+
+        // The cell of each exited thread was reused by the next one.
+        assert_eq!(2, tm.cell_count());
+        assert_eq!(-1, tm.fold_values(0, |z, v| z + v).unwrap());
+    }
+
+    #[test]
+    fn test_stale_cached_cell() {
+        let tm: LockFreeThreadMap<usize> = LockFreeThreadMap::from_thread_fn(|info| info.index());
+        let barrier = Barrier::new(2);
+
+        thread::scope(|s| {
+            let tm = &tm;
+            let barrier = &barrier;
+            let worker = s.spawn(move || {
+                assert_eq!(0, tm.get());
+                barrier.wait(); // value registered and cached
+                barrier.wait(); // value drained and cell reused
+                tm.get()
+            });
+            let tid = worker.thread().id();
+
+            barrier.wait();
+            assert_eq!(HashMap::from([(tid, 0)]), tm.drain().unwrap());
+            assert_eq!(1, tm.get());
+            assert_eq!(None, tm.with_thread(tid, |v| *v).unwrap());
+            barrier.wait();
+
+            // The worker's cached cell now holds the current thread's value, which it must not access.
+            assert_eq!(2, worker.join().unwrap());
+        });
+
+        assert_eq!(1, tm.get());
+        assert_eq!(3, tm.fold_values(0, |z, v| z + v).unwrap());
+        assert_eq!(2, tm.cell_count());
+    }
+
+    #[test]
+    fn test_lock_wait() {
+        let tm: LockFreeThreadMap<i32> = LockFreeThreadMap::default();
+        tm.set(1);
+
+        let locked = Barrier::new(2);
+        let release = Barrier::new(2);
+        thread::scope(|s| {
+            s.spawn(|| {
+                // The sweep holds the lock of the main thread's value while it visits the value.
+                tm.fold_values(0, |z, v| {
+                    locked.wait();
+                    release.wait();
+                    z + v
+                })
+            });
+            locked.wait();
+            assert_eq!(
+                Err(ThreadMapLockError::WouldBlock),
+                tm.try_with_nonblocking(|v| *v)
+            );
+            assert_eq!(
+                Err(ThreadMapLockError::Timeout),
+                tm.try_with_mut_timeout(Duration::from_millis(10), |v| *v += 1)
+            );
+            release.wait();
+        });
+
+        assert_eq!(Ok(1), tm.try_with_mut_nonblocking(|v| *v));
+        assert_eq!(
+            Ok(2),
+            tm.try_with_timeout(Duration::from_secs(60), |v| *v + 1)
+        );
+    }
+}
@@ -0,0 +1,1066 @@
+use crate::{
+    ThreadInfo, ThreadMap, ThreadMapLockError, ThreadMapRef, ThreadMapRefMut, ValueInit, ValueKey,
+    reentrancy::{Entered, enter},
+    thread_exit::next_map_id,
+};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    mem::{replace, take},
+    num::NonZeroUsize,
+    ops::{Deref, DerefMut},
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread::{self, ThreadId},
+    time::Duration,
+};
+
+/// Number of shards used when the available parallelism cannot be determined.
+const DEFAULT_SHARDS: usize = 4;
+
+/// Source of the shard seeds of threads.
+static NEXT_SHARD_SEED: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// Seed that selects the shard of the current thread in every [`ShardedThreadMap`]. Seeds are assigned
+    /// round-robin so that threads are evenly distributed over the shards.
+    static SHARD_SEED: usize = NEXT_SHARD_SEED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Like [`ThreadMap`], this type encapsulates the association of [`ThreadId`]s to values of type `V`, and its API
+/// matches that of [`ThreadMap`]. It differs from [`ThreadMap`] in that it partitions the threads over a number of
+/// shards, each of which is a [`ThreadMap`] with its own object-level lock. Registrations of new threads and
+/// sweeps ([`Self::fold`], [`Self::fold_values`], [`Self::probe`], [`Self::drain`]) therefore contend with the
+/// threads of one shard at a time instead of with all threads, which reduces lock contention when there are many
+/// threads.
+///
+/// Sweeps lock the shards one at a time, so, unlike with [`ThreadMap`], they do not observe all values at a single
+/// point in time. The number of shards defaults to the available parallelism (see
+/// [`std::thread::available_parallelism`]) and can be set with [`Self::with_shards`].
+///
+/// # Example
+///
+/// ```rust
+/// use std::thread;
+/// use thread_map::ShardedThreadMap;
+///
+/// let tm = ShardedThreadMap::<i32>::default().with_shards(4);
+/// thread::scope(|s| {
+///     for i in 0..8 {
+///         let tm = &tm;
+///         s.spawn(move || tm.with_mut(|v| *v += i));
+///     }
+/// });
+/// assert_eq!(28, tm.fold_values(0, |z, v| z + v).unwrap());
+/// ```
+///
+/// # Reentrancy
+///
+/// As with [`ThreadMap`], calling a method of a [`ShardedThreadMap`] from within a closure passed to a method of the
+/// same instance, or while holding a value returned by [`Self::current`], [`Self::current_mut`], or
+/// [`Self::debug_snapshot`], is detected: fallible methods return [`ThreadMapLockError::Reentrant`], and the other
+/// methods panic.
+///
+/// # Thread Safety
+///
+/// `ShardedThreadMap<V>` is [`Send`] and [`Sync`] if and only if `V` is [`Send`], the same contract as
+/// [`ThreadMap`]:
+///
+/// ```compile_fail
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<thread_map::ShardedThreadMap<std::rc::Rc<i32>>>();
+/// ```
+pub struct ShardedThreadMap<V> {
+    id: u64,
+    shards: Box<[ThreadMap<V>]>,
+    value_init: Arc<ValueInit<V>>,
+    next_index: Arc<AtomicUsize>,
+    /// Applies the configuration methods, e.g., [`Self::on_thread_exit`], to a new shard.
+    configure_shard: Option<Box<dyn Fn(ThreadMap<V>) -> ThreadMap<V> + Send + Sync>>,
+    /// Merges the accumulators of retired values of the shards, if configured with [`Self::retire_on_thread_exit`].
+    merge: Option<Arc<dyn Fn(&mut V, V) + Send + Sync>>,
+}
+
+impl<V> ShardedThreadMap<V> {
+    fn from_value_init(value_init: ValueInit<V>) -> Self {
+        let n = thread::available_parallelism().map_or(DEFAULT_SHARDS, NonZeroUsize::get);
+        let mut tm = Self {
+            id: next_map_id(),
+            shards: Box::default(),
+            value_init: Arc::new(value_init),
+            next_index: Arc::new(AtomicUsize::new(0)),
+            configure_shard: None,
+            merge: None,
+        };
+        tm.shards = tm.new_shards(n);
+        tm
+    }
+
+    /// Creates `n` empty shards, configured according to the configuration methods called so far.
+    fn new_shards(&self, n: usize) -> Box<[ThreadMap<V>]> {
+        (0..n)
+            .map(|_| {
+                let value_init =
+                    ValueInit::Shared(self.value_init.clone(), self.next_index.clone());
+                let shard = ThreadMap::from_value_init(value_init);
+                match &self.configure_shard {
+                    Some(configure) => configure(shard),
+                    None => shard,
+                }
+            })
+            .collect()
+    }
+
+    /// Creates a new [`ShardedThreadMap`] instance, with `value_init` used to create the initial value for each thread.
+    pub fn new(value_init: fn() -> V) -> Self {
+        Self::from_value_init(ValueInit::Fn(value_init))
+    }
+
+    /// Creates a new [`ShardedThreadMap`] instance, with the closure `value_init` used to create the initial value for
+    /// each thread. See [`ThreadMap::from_fn`].
+    pub fn from_fn(value_init: impl Fn() -> V + Send + Sync + 'static) -> Self {
+        Self::from_value_init(ValueInit::Closure(Box::new(value_init)))
+    }
+
+    /// Creates a new [`ShardedThreadMap`] instance, with the closure `value_init` used to create the initial value for
+    /// each thread. See [`ThreadMap::from_thread_fn`]. [`ThreadInfo::index`] is unique across all shards.
+    pub fn from_thread_fn(value_init: impl Fn(&ThreadInfo) -> V + Send + Sync + 'static) -> Self {
+        Self::from_value_init(ValueInit::ThreadAware(Box::new(value_init)))
+    }
+
+    /// Configures `self` to partition the threads over `n` shards. Values already associated with threads are
+    /// dropped, so this method is meant to be called right after construction.
+    ///
+    /// # Panics
+    /// - If `n` is zero.
+    pub fn with_shards(mut self, n: usize) -> Self {
+        assert!(n > 0, "number of shards must be positive");
+        self.shards = self.new_shards(n);
+        self
+    }
+
+    /// Returns the number of shards of `self`.
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Applies `configure` to each shard of `self` and to the shards created later by [`Self::with_shards`].
+    fn configure(
+        mut self,
+        configure: impl Fn(ThreadMap<V>) -> ThreadMap<V> + Send + Sync + 'static,
+    ) -> Self {
+        self.shards = take(&mut self.shards)
+            .into_vec()
+            .into_iter()
+            .map(&configure)
+            .collect();
+        self.configure_shard = Some(Box::new(configure));
+        self
+    }
+
+    /// Configures `self` so that when a thread that has a value in `self` exits, its value is removed from `self` and
+    /// passed to `f`, together with the thread's [`ThreadId`]. See [`ThreadMap::on_thread_exit`].
+    pub fn on_thread_exit(self, f: impl Fn(ThreadId, V) + Send + Sync + 'static) -> Self
+    where
+        V: Send + 'static,
+    {
+        let f = Arc::new(f);
+        self.configure(move |shard| {
+            let f = f.clone();
+            shard.on_thread_exit(move |tid, v| f(tid, v))
+        })
+    }
+
+    /// Configures `self` so that when a thread that has a value in `self` exits, its value is removed from `self` and
+    /// dropped. See [`Self::on_thread_exit`] for details.
+    pub fn remove_on_thread_exit(self) -> Self
+    where
+        V: Send + 'static,
+    {
+        self.on_thread_exit(|_, _| ())
+    }
+
+    /// Configures `self` so that when a thread that has a value in `self` exits, its value is removed from `self` and
+    /// merged into an accumulator of retired values with `merge`. See [`ThreadMap::retire_on_thread_exit`].
+    ///
+    /// Each shard keeps its own accumulator, so that [`Self::fold_values`] observes each retired value exactly once
+    /// even though it locks one shard at a time. [`Self::retired`] and [`Self::take_retired`] merge the accumulators of
+    /// the shards with `merge`.
+    pub fn retire_on_thread_exit(
+        mut self,
+        merge: impl Fn(&mut V, V) + Send + Sync + 'static,
+    ) -> Self
+    where
+        V: Send + 'static,
+    {
+        let merge: Arc<dyn Fn(&mut V, V) + Send + Sync> = Arc::new(merge);
+        self.merge = Some(merge.clone());
+        self.configure(move |shard| {
+            let merge = merge.clone();
+            shard.retire_on_thread_exit(move |acc, v| merge(acc, v))
+        })
+    }
+
+    /// Returns the shard of the current thread.
+    fn current_shard(&self) -> &ThreadMap<V> {
+        let seed = SHARD_SEED.with(|seed| *seed);
+        &self.shards[seed % self.shards.len()]
+    }
+
+    /// Invokes `f` mutably on the value associated with the [`ThreadId`] of the current thread and returns the invocation result.
+    /// If there is no value associated with the current thread then the initializer provided at construction
+    /// ([`Self::new`], [`Self::from_fn`], or [`Self::from_thread_fn`]) is used to instantiate an initial associated
+    /// value before `f` is applied. Only the lock of the current thread's shard is acquired.
+    ///
+    /// # Panics
+    /// - If the lock of the current thread's shard is poisoned. See [`Self::try_with_mut`] for a non-panicking alternative.
+    pub fn with_mut<W>(&self, f: impl FnOnce(&mut V) -> W) -> W {
+        self.try_with_mut(f).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Same as [`Self::with_mut`] but returns an error instead of panicking.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if the lock of the current thread's shard is poisoned.
+    pub fn try_with_mut<W>(&self, f: impl FnOnce(&mut V) -> W) -> Result<W, ThreadMapLockError> {
+        let _entered = enter(self.id)?;
+        self.current_shard().try_with_mut(f)
+    }
+
+    /// Same as [`Self::try_with_mut`] but waits at most `timeout` for each lock of the current thread's shard. See
+    /// [`ThreadMap::try_with_mut_timeout`].
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError::Timeout`] if a lock of the current thread's shard is not acquired within `timeout`.
+    /// - [`ThreadMapLockError`] if the lock of the current thread's shard is poisoned.
+    pub fn try_with_mut_timeout<W>(
+        &self,
+        timeout: Duration,
+        f: impl FnOnce(&mut V) -> W,
+    ) -> Result<W, ThreadMapLockError> {
+        let _entered = enter(self.id)?;
+        self.current_shard().try_with_mut_timeout(timeout, f)
+    }
+
+    /// Same as [`Self::try_with_mut`] but does not wait for the locks of the current thread's shard. See
+    /// [`ThreadMap::try_with_mut_nonblocking`].
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError::WouldBlock`] if a lock of the current thread's shard cannot be acquired without
+    ///   blocking.
+    /// - [`ThreadMapLockError`] if the lock of the current thread's shard is poisoned.
+    pub fn try_with_mut_nonblocking<W>(
+        &self,
+        f: impl FnOnce(&mut V) -> W,
+    ) -> Result<W, ThreadMapLockError> {
+        let _entered = enter(self.id)?;
+        self.current_shard().try_with_mut_nonblocking(f)
+    }
+
+    /// Invokes `f` on the value associated with the [`ThreadId`] of the current thread and returns the invocation result.
+    /// See [`Self::with_mut`].
+    ///
+    /// # Panics
+    /// - If the lock of the current thread's shard is poisoned. See [`Self::try_with`] for a non-panicking alternative.
+    pub fn with<W>(&self, f: impl FnOnce(&V) -> W) -> W {
+        let g = |v: &mut V| f(v);
+        self.with_mut(g)
+    }
+
+    /// Same as [`Self::with`] but returns an error instead of panicking.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if the lock of the current thread's shard is poisoned.
+    pub fn try_with<W>(&self, f: impl FnOnce(&V) -> W) -> Result<W, ThreadMapLockError> {
+        let g = |v: &mut V| f(v);
+        self.try_with_mut(g)
+    }
+
+    /// Same as [`Self::try_with`] but waits at most `timeout` for each lock of the current thread's shard. See
+    /// [`ThreadMap::try_with_mut_timeout`].
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError::Timeout`] if a lock of the current thread's shard is not acquired within `timeout`.
+    /// - [`ThreadMapLockError`] if the lock of the current thread's shard is poisoned.
+    pub fn try_with_timeout<W>(
+        &self,
+        timeout: Duration,
+        f: impl FnOnce(&V) -> W,
+    ) -> Result<W, ThreadMapLockError> {
+        let g = |v: &mut V| f(v);
+        self.try_with_mut_timeout(timeout, g)
+    }
+
+    /// Same as [`Self::try_with`] but does not wait for the locks of the current thread's shard. See
+    /// [`ThreadMap::try_with_mut_nonblocking`].
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError::WouldBlock`] if a lock of the current thread's shard cannot be acquired without
+    ///   blocking.
+    /// - [`ThreadMapLockError`] if the lock of the current thread's shard is poisoned.
+    pub fn try_with_nonblocking<W>(
+        &self,
+        f: impl FnOnce(&V) -> W,
+    ) -> Result<W, ThreadMapLockError> {
+        let g = |v: &mut V| f(v);
+        self.try_with_mut_nonblocking(g)
+    }
+
+    /// Returns a clone of the value associated with the current thread.
+    ///
+    /// # Panics
+    /// - If the lock of the current thread's shard is poisoned. See [`Self::try_get`] for a non-panicking alternative.
+    pub fn get(&self) -> V
+    where
+        V: Clone,
+    {
+        self.with(|v| v.clone())
+    }
+
+    /// Same as [`Self::get`] but returns an error instead of panicking.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if the lock of the current thread's shard is poisoned.
+    pub fn try_get(&self) -> Result<V, ThreadMapLockError>
+    where
+        V: Clone,
+    {
+        self.try_with(|v| v.clone())
+    }
+
+    /// Sets the value associated with the current thread to `v`.
+    ///
+    /// # Panics
+    /// - If the lock of the current thread's shard is poisoned. See [`Self::try_set`] for a non-panicking alternative.
+    pub fn set(&self, v: V) {
+        self.with_mut(|v0| *v0 = v);
+    }
+
+    /// Same as [`Self::set`] but returns an error instead of panicking.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if the lock of the current thread's shard is poisoned.
+    pub fn try_set(&self, v: V) -> Result<(), ThreadMapLockError> {
+        self.try_with_mut(|v0| *v0 = v)
+    }
+
+    /// Returns a guard that dereferences to the value associated with the current thread. See [`ThreadMap::current`].
+    /// Only the lock of the current thread's shard is held until the guard is dropped.
+    ///
+    /// # Panics
+    /// - If the lock of the current thread's shard is poisoned. See [`Self::try_current`] for a non-panicking alternative.
+    pub fn current(&self) -> ShardedThreadMapRef<'_, V> {
+        self.try_current().unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Same as [`Self::current`] but returns an error instead of panicking.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if the lock of the current thread's shard is poisoned.
+    pub fn try_current(&self) -> Result<ShardedThreadMapRef<'_, V>, ThreadMapLockError> {
+        let entered = enter(self.id)?;
+        Ok(ShardedThreadMapRef {
+            inner: self.current_shard().try_current()?,
+            _entered: entered,
+        })
+    }
+
+    /// Same as [`Self::current`] but the guard dereferences mutably to the value associated with the current thread.
+    ///
+    /// # Panics
+    /// - If the lock of the current thread's shard is poisoned. See [`Self::try_current_mut`] for a non-panicking alternative.
+    pub fn current_mut(&self) -> ShardedThreadMapRefMut<'_, V> {
+        self.try_current_mut().unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Same as [`Self::current_mut`] but returns an error instead of panicking.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if the lock of the current thread's shard is poisoned.
+    pub fn try_current_mut(&self) -> Result<ShardedThreadMapRefMut<'_, V>, ThreadMapLockError> {
+        let entered = enter(self.id)?;
+        Ok(ShardedThreadMapRefMut {
+            inner: self.current_shard().try_current_mut()?,
+            _entered: entered,
+        })
+    }
+
+    /// Removes the value associated with the current thread, if any, and returns it. See [`ThreadMap::take`].
+    ///
+    /// # Panics
+    /// - If the lock of the current thread's shard is poisoned. See [`Self::try_take`] for a non-panicking alternative.
+    pub fn take(&self) -> Option<V> {
+        self.try_take().unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Same as [`Self::take`] but returns an error instead of panicking.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if the lock of the current thread's shard is poisoned.
+    pub fn try_take(&self) -> Result<Option<V>, ThreadMapLockError> {
+        let _entered = enter(self.id)?;
+        self.current_shard().try_take()
+    }
+
+    /// Sets the value associated with the current thread to `v` and returns the previous value, which is
+    /// initialized first if there was no value associated with the current thread.
+    ///
+    /// # Panics
+    /// - If the lock of the current thread's shard is poisoned. See [`Self::try_replace`] for a non-panicking alternative.
+    pub fn replace(&self, v: V) -> V {
+        self.with_mut(|v0| replace(v0, v))
+    }
+
+    /// Same as [`Self::replace`] but returns an error instead of panicking.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if the lock of the current thread's shard is poisoned.
+    pub fn try_replace(&self, v: V) -> Result<V, ThreadMapLockError> {
+        self.try_with_mut(|v0| replace(v0, v))
+    }
+
+    /// Removes and drops the value associated with the current thread, if any. See [`ThreadMap::remove`].
+    ///
+    /// # Panics
+    /// - If the lock of the current thread's shard is poisoned. See [`Self::try_remove`] for a non-panicking alternative.
+    pub fn remove(&self) {
+        self.try_remove().unwrap_or_else(|e| panic!("{e}"));
+    }
+
+    /// Same as [`Self::remove`] but returns an error instead of panicking.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if the lock of the current thread's shard is poisoned.
+    pub fn try_remove(&self) -> Result<(), ThreadMapLockError> {
+        self.try_take().map(drop)
+    }
+
+    /// Resets the value associated with the current thread to a new initial value. See [`ThreadMap::reset`].
+    ///
+    /// # Panics
+    /// - If the lock of the current thread's shard is poisoned. See [`Self::try_reset`] for a non-panicking alternative.
+    pub fn reset(&self) {
+        self.try_reset().unwrap_or_else(|e| panic!("{e}"));
+    }
+
+    /// Same as [`Self::reset`] but returns an error instead of panicking.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if the lock of the current thread's shard is poisoned.
+    pub fn try_reset(&self) -> Result<(), ThreadMapLockError> {
+        self.try_remove()?;
+        self.try_with_mut(|_| ())
+    }
+
+    /// Invokes `f` mutably on the value associated with the thread whose [`ThreadId`] is `tid` and returns the
+    /// invocation result, or `None` if there is no value associated with `tid`. See [`ThreadMap::with_thread_mut`].
+    /// The shard of `tid` is looked up by locking the shards one at a time, and only the lock of that shard is held
+    /// while `f` runs.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if the lock of a shard is poisoned.
+    pub fn with_thread_mut<W>(
+        &self,
+        tid: ThreadId,
+        f: impl FnOnce(&mut V) -> W,
+    ) -> Result<Option<W>, ThreadMapLockError> {
+        let _entered = enter(self.id)?;
+        let mut f = Some(f);
+        for shard in self.shards.iter() {
+            let w = shard.with_thread_mut(tid, |v| f.take().map(|f| f(v)))?;
+            if let Some(w) = w.flatten() {
+                return Ok(Some(w));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Invokes `f` on the value associated with the thread whose [`ThreadId`] is `tid` and returns the invocation
+    /// result, or `None` if there is no value associated with `tid`. See [`Self::with_thread_mut`].
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if the lock of a shard is poisoned.
+    pub fn with_thread<W>(
+        &self,
+        tid: ThreadId,
+        f: impl FnOnce(&V) -> W,
+    ) -> Result<Option<W>, ThreadMapLockError> {
+        let g = |v: &mut V| f(v);
+        self.with_thread_mut(tid, g)
+    }
+
+    /// Returns `true` if there is a value associated with the thread whose [`ThreadId`] is `tid`.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if the lock of a shard is poisoned.
+    pub fn contains(&self, tid: ThreadId) -> Result<bool, ThreadMapLockError> {
+        let _entered = enter(self.id)?;
+        for shard in self.shards.iter() {
+            if shard.contains(tid)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Returns a [`HashMap`] with the values associated with each [`ThreadId`] key and clears `self`'s state,
+    /// locking the shards one at a time.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if the lock of any shard is poisoned, in which case `self`'s state is left unchanged.
+    pub fn drain(&self) -> Result<HashMap<ThreadId, V>, ThreadMapLockError> {
+        let _entered = enter(self.id)?;
+        // Check before draining any shard so that no values are lost if a shard's lock is poisoned.
+        if let Some(shard) = self.shards.iter().find(|shard| shard.is_state_poisoned()) {
+            return Err(shard.poisoned_state_error());
+        }
+        self.shards
+            .iter()
+            .try_fold(HashMap::new(), |mut map, shard| {
+                map.extend(shard.drain()?);
+                Ok(map)
+            })
+    }
+
+    /// Folds every association in `self` into an accumulator (with initial value `z`) by applying an operation `f`,
+    /// returning the final result. The shards are locked one at a time. If `self` was configured with
+    /// [`Self::retire_on_thread_exit`], the accumulated value of the exited threads of each shard, if any, is folded
+    /// before the values of the shard, with the key [`ValueKey::Retired`], so `f` may be called with that key once per
+    /// shard.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if the lock of a shard is poisoned.
+    pub fn fold<W>(
+        &self,
+        z: W,
+        mut f: impl FnMut(W, (ValueKey, &V)) -> W,
+    ) -> Result<W, ThreadMapLockError> {
+        let _entered = enter(self.id)?;
+        self.shards
+            .iter()
+            .try_fold(z, |w, shard| shard.fold(w, &mut f))
+    }
+
+    /// Folds every value in `self` into an accumulator (with initial value `z`) by applying an operation `f`,
+    /// returning the final result. The shards are locked one at a time. If `self` was configured with
+    /// [`Self::retire_on_thread_exit`], the accumulated value of the exited threads of each shard, if any, is folded
+    /// before the values of the shard.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if an internal lock is poisoned.
+    pub fn fold_values<W>(
+        &self,
+        z: W,
+        mut f: impl FnMut(W, &V) -> W,
+    ) -> Result<W, ThreadMapLockError> {
+        let _entered = enter(self.id)?;
+        self.shards
+            .iter()
+            .try_fold(z, |w, shard| shard.fold_values(w, &mut f))
+    }
+
+    /// Returns a [`HashMap`] with clones of the values associated with each [`ThreadId`] key, and of the accumulated
+    /// value of the retired threads merged across shards, if any, with the key [`ValueKey::Retired`], at the time each
+    /// shard was probed.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if an internal lock is poisoned.
+    pub fn probe(&self) -> Result<HashMap<ValueKey, V>, ThreadMapLockError>
+    where
+        V: Clone,
+    {
+        let z = HashMap::<ValueKey, V>::new();
+        self.fold(z, |mut w, (key, v)| {
+            self.insert_merged(&mut w, key, v.clone());
+            w
+        })
+    }
+
+    /// Inserts `v` into `map` with `key`, merging it with `merge` into the value already there, if any. Only the
+    /// accumulators of retired values of the shards share a key.
+    fn insert_merged(&self, map: &mut HashMap<ValueKey, V>, key: ValueKey, v: V) {
+        match (map.get_mut(&key), &self.merge) {
+            (Some(acc), Some(merge)) => merge(acc, v),
+            _ => {
+                map.insert(key, v);
+            }
+        }
+    }
+
+    /// Returns a snapshot of `self` whose [`Debug`] output lists the values associated with each [`ThreadId`] by
+    /// shard. The snapshot holds the locks of all shards until it is dropped. See [`ThreadMap::debug_snapshot`].
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if the lock of a shard is poisoned.
+    pub fn debug_snapshot(&self) -> Result<impl Debug + '_, ThreadMapLockError>
+    where
+        V: Debug,
+    {
+        struct DebugSnapshot<S> {
+            shards: Vec<S>,
+            _entered: Entered,
+        }
+
+        impl<S: Debug> Debug for DebugSnapshot<S> {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.debug_list().entries(&self.shards).finish()
+            }
+        }
+
+        let entered = enter(self.id)?;
+        let shards = self
+            .shards
+            .iter()
+            .map(|shard| shard.debug_snapshot())
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(DebugSnapshot {
+            shards,
+            _entered: entered,
+        })
+    }
+
+    /// Merges the accumulators of retired values of the shards.
+    fn merge_retired(&self, accs: Vec<Option<V>>) -> Option<V> {
+        accs.into_iter().flatten().reduce(|mut acc, v| {
+            if let Some(merge) = &self.merge {
+                merge(&mut acc, v);
+            }
+            acc
+        })
+    }
+
+    /// Returns a clone of the accumulated value of the threads retired by [`Self::retire_on_thread_exit`], merged
+    /// across shards, or `None` if no thread has been retired since `self` was created or [`Self::take_retired`] was
+    /// last called.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if the accumulator lock of a shard is poisoned.
+    pub fn retired(&self) -> Result<Option<V>, ThreadMapLockError>
+    where
+        V: Clone,
+    {
+        let _entered = enter(self.id)?;
+        let accs = self
+            .shards
+            .iter()
+            .map(|shard| shard.retired())
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(self.merge_retired(accs))
+    }
+
+    /// Returns the accumulated value of the threads retired by [`Self::retire_on_thread_exit`], merged across shards,
+    /// if any, leaving no accumulated value in its place.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError`] if the accumulator lock of any shard is poisoned, in which case no accumulated value
+    ///   is taken.
+    pub fn take_retired(&self) -> Result<Option<V>, ThreadMapLockError> {
+        let _entered = enter(self.id)?;
+        // Check before taking any accumulator so that no values are lost if an accumulator lock is poisoned.
+        if self.shards.iter().any(|shard| shard.is_retired_poisoned()) {
+            return Err(ThreadMapLockError::PoisonedRetiredLock);
+        }
+        let accs = self
+            .shards
+            .iter()
+            .map(|shard| shard.take_retired())
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(self.merge_retired(accs))
+    }
+
+    /// Returns `true` if the lock of any shard, or of its accumulator of retired values, is poisoned, which happens
+    /// when a thread panics while holding the lock.
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError::Reentrant`] if called while the current thread is accessing `self`.
+    pub fn is_poisoned(&self) -> Result<bool, ThreadMapLockError> {
+        let _entered = enter(self.id)?;
+        for shard in self.shards.iter() {
+            if shard.is_poisoned()? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Clears the poisoned state of the locks of all shards. See [`ThreadMap::clear_poison`].
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError::Reentrant`] if called while the current thread is accessing `self`.
+    pub fn clear_poison(&self) -> Result<(), ThreadMapLockError> {
+        let _entered = enter(self.id)?;
+        self.shards
+            .iter()
+            .try_for_each(|shard| shard.clear_poison())
+    }
+
+    /// Same as [`Self::drain`] but succeeds even if the lock of any shard, or of its accumulator of retired values, is
+    /// poisoned. Unlike [`Self::drain`], it also takes the accumulated value of the retired threads merged across
+    /// shards, if any, with the key [`ValueKey::Retired`]. The poisoned state is not cleared; see
+    /// [`Self::clear_poison`] and [`ThreadMap::drain_poisoned`].
+    ///
+    /// # Errors
+    /// - [`ThreadMapLockError::Reentrant`] if called while the current thread is accessing `self`.
+    pub fn drain_poisoned(&self) -> Result<HashMap<ValueKey, V>, ThreadMapLockError> {
+        let _entered = enter(self.id)?;
+        self.shards
+            .iter()
+            .try_fold(HashMap::new(), |mut map, shard| {
+                for (key, v) in shard.drain_poisoned()? {
+                    self.insert_merged(&mut map, key, v);
+                }
+                Ok(map)
+            })
+    }
+}
+
+/// Reports the [`ThreadId`]s that have values in each shard, as with the [`Debug`] implementation of [`ThreadMap`].
+impl<V> Debug for ShardedThreadMap<V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ShardedThreadMap")
+            .field("shards", &self.shards)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<V: Default> Default for ShardedThreadMap<V> {
+    fn default() -> Self {
+        Self::new(V::default)
+    }
+}
+
+/// Guard returned by [`ShardedThreadMap::current`] that dereferences to the value associated with the current thread.
+/// It holds the lock of the current thread's shard until dropped. It is not [`Send`], so it cannot leave the current
+/// thread.
+pub struct ShardedThreadMapRef<'a, V> {
+    inner: ThreadMapRef<'a, V>,
+    _entered: Entered,
+}
+
+impl<V> Deref for ShardedThreadMapRef<'_, V> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        &self.inner
+    }
+}
+
+impl<V: Debug> Debug for ShardedThreadMapRef<'_, V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(&self.inner, f)
+    }
+}
+
+/// Guard returned by [`ShardedThreadMap::current_mut`] that dereferences mutably to the value associated with the
+/// current thread. It holds the lock of the current thread's shard until dropped. It is not [`Send`], so it cannot
+/// leave the current thread.
+pub struct ShardedThreadMapRefMut<'a, V> {
+    inner: ThreadMapRefMut<'a, V>,
+    _entered: Entered,
+}
+
+impl<V> Deref for ShardedThreadMapRefMut<'_, V> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        &self.inner
+    }
+}
+
+impl<V> DerefMut for ShardedThreadMapRefMut<'_, V> {
+    fn deref_mut(&mut self) -> &mut V {
+        &mut self.inner
+    }
+}
+
+impl<V: Debug> Debug for ShardedThreadMapRefMut<'_, V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(&self.inner, f)
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod test {
+    use super::ShardedThreadMap;
+    use crate::{ThreadMapLockError, ValueKey};
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Barrier},
+        thread::{self},
+        time::Duration,
+    };
+
+    const NTHREADS: i32 = 20;
+    const NITER: i32 = 10;
+    const SLEEP_MICROS: u64 = 10;
+    const NSHARDS: usize = 3;
+
+    fn update_value((i0, v0): &mut (i32, i32), i: i32) {
+        *i0 = i;
+        *v0 += i;
+    }
+
+    #[test]
+    fn test_lifecycle() {
+        let tm: ShardedThreadMap<(i32, i32)> = ShardedThreadMap::default().with_shards(NSHARDS);
+        assert_eq!(NSHARDS, tm.shard_count());
+
+        thread::scope(|s| {
+            let tm = &tm;
+            for i in 0..NTHREADS {
+                s.spawn(move || {
+                    for _ in 0..NITER {
+                        thread::sleep(Duration::from_micros(SLEEP_MICROS));
+                        tm.with_mut(move |p: &mut (i32, i32)| update_value(p, i));
+                    }
+                    let value = tm.get();
+                    assert_eq!((i, i * NITER), value);
+                });
+            }
+
+            for _ in 0..NITER {
+                tm.with_mut(move |p: &mut (i32, i32)| update_value(p, NTHREADS))
+            }
+        });
+
+        let expected = (0..=NTHREADS)
+            .map(|i| (i, i * NITER))
+            .collect::<HashMap<_, _>>();
+        let expected_sum = expected.values().sum::<i32>();
+
+        let sum = tm.fold_values(0, |z, (_, v)| z + v).unwrap();
+        assert_eq!(expected_sum, sum);
+
+        let probed = tm.probe().unwrap().into_values().collect::<HashMap<_, _>>();
+        assert_eq!(expected, probed);
+
+        let dumped = tm.drain().unwrap().into_values().collect::<HashMap<_, _>>();
+        assert_eq!(expected, dumped);
+        assert!(tm.probe().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_from_thread_fn() {
+        let tm: ShardedThreadMap<usize> =
+            ShardedThreadMap::from_thread_fn(|info| info.index()).with_shards(NSHARDS);
+
+        thread::scope(|s| {
+            let tm = &tm;
+            for _ in 0..NTHREADS {
+                s.spawn(move || tm.get());
+            }
+        });
+
+        let mut indices = tm
+            .fold_values(Vec::new(), |mut z, index| {
+                z.push(*index);
+                z
+            })
+            .unwrap();
+        indices.sort();
+        assert_eq!((0..NTHREADS as usize).collect::<Vec<_>>(), indices);
+    }
+
+    #[test]
+    fn test_retire_on_thread_exit() {
+        let tm: Arc<ShardedThreadMap<i32>> = Arc::new(
+            ShardedThreadMap::default()
+                .retire_on_thread_exit(|acc, v| *acc += v)
+                .with_shards(NSHARDS),
+        );
+
+        let handles = (0..NTHREADS)
+            .map(|i| {
+                let tm = tm.clone();
+                thread::spawn(move || tm.set(i))
+            })
+            .collect::<Vec<_>>();
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        tm.set(NTHREADS);
+        let retired_sum = (0..NTHREADS).sum::<i32>();
+        assert_eq!(Some(retired_sum), tm.retired().unwrap());
+        let expected = HashMap::from([
+            (ValueKey::Thread(thread::current().id()), NTHREADS),
+            (ValueKey::Retired, retired_sum),
+        ]);
+        assert_eq!(expected, tm.probe().unwrap());
+        let folded = tm
+            .fold(HashMap::new(), |mut z, (key, v)| {
+                *z.entry(key).or_insert(0) += v;
+                z
+            })
+            .unwrap();
+        assert_eq!(expected, folded);
+        assert_eq!(
+            retired_sum + NTHREADS,
+            tm.fold_values(0, |z, v| z + v).unwrap()
+        );
+
+        assert_eq!(Some(retired_sum), tm.take_retired().unwrap());
+        assert_eq!(
+            HashMap::from([(ValueKey::Thread(thread::current().id()), NTHREADS)]),
+            tm.probe().unwrap()
+        );
+        assert_eq!(None, tm.retired().unwrap());
+        assert_eq!(NTHREADS, tm.fold_values(0, |z, v| z + v).unwrap());
+    }
+
+    #[cfg(not(feature = "parking_lot"))]
+    #[test]
+    fn test_retired_poison_recovery() {
+        let tm: Arc<ShardedThreadMap<i32>> = Arc::new(
+            ShardedThreadMap::default()
+                .with_shards(NSHARDS)
+                .retire_on_thread_exit(|acc, v| *acc += v),
+        );
+
+        let handles = (0..NTHREADS)
+            .map(|i| {
+                let tm = tm.clone();
+                thread::spawn(move || tm.set(i))
+            })
+            .collect::<Vec<_>>();
+        for h in handles {
+            h.join().unwrap();
+        }
+        tm.set(NTHREADS);
+
+        for shard in tm.shards.iter() {
+            shard.poison_retired();
+        }
+        assert!(tm.is_poisoned().unwrap());
+        assert_eq!(
+            Err(ThreadMapLockError::PoisonedRetiredLock),
+            tm.take_retired()
+        );
+
+        // The accumulator is drained together with the values.
+        let retired_sum = (0..NTHREADS).sum::<i32>();
+        assert_eq!(
+            HashMap::from([
+                (ValueKey::Thread(thread::current().id()), NTHREADS),
+                (ValueKey::Retired, retired_sum),
+            ]),
+            tm.drain_poisoned().unwrap()
+        );
+
+        tm.clear_poison().unwrap();
+        assert!(!tm.is_poisoned().unwrap());
+        assert_eq!(None, tm.take_retired().unwrap());
+        assert_eq!(0, tm.fold_values(0, |z, v| z + v).unwrap());
+    }
+
+    #[test]
+    fn test_with_thread() {
+        let tm: ShardedThreadMap<i32> = ShardedThreadMap::default().with_shards(NSHARDS);
+        let barrier = Barrier::new(2);
+
+        thread::scope(|s| {
+            let tm = &tm;
+            let barrier = &barrier;
+            let worker = s.spawn(move || {
+                tm.set(1);
+                barrier.wait(); // value set
+                barrier.wait(); // value inspected and reset by supervisor
+                tm.get()
+            });
+            let tid = worker.thread().id();
+
+            barrier.wait();
+            assert!(tm.contains(tid).unwrap());
+            assert!(!tm.contains(thread::current().id()).unwrap());
+            assert_eq!(Some(1), tm.with_thread(tid, |v| *v).unwrap());
+            assert_eq!(Some(()), tm.with_thread_mut(tid, |v| *v = 42).unwrap());
+            assert_eq!(
+                None,
+                tm.with_thread(thread::current().id(), |v| *v).unwrap()
+            );
+            barrier.wait();
+
+            assert_eq!(42, worker.join().unwrap());
+        });
+    }
+
+    #[test]
+    fn test_take_current() {
+        let tm: ShardedThreadMap<i32> = ShardedThreadMap::default().with_shards(NSHARDS);
+
+        *tm.current_mut() += 1;
+        assert_eq!(1, *tm.current());
+        assert_eq!(1, tm.replace(2));
+        assert_eq!(Some(2), tm.take());
+        assert_eq!(None, tm.take());
+        tm.set(3);
+        tm.reset();
+        assert_eq!(0, tm.get());
+
+        let tid = thread::current().id();
+        let out = format!("{:?}", tm.debug_snapshot().unwrap());
+        assert!(out.contains(&format!("{{{tid:?}: 0}}")));
+    }
+
+    #[test]
+    fn test_reentrancy() {
+        let tm: ShardedThreadMap<i32> = ShardedThreadMap::default().with_shards(NSHARDS);
+
+        thread::scope(|s| {
+            let tm = &tm;
+            for i in 0..NTHREADS {
+                s.spawn(move || tm.set(i));
+            }
+        });
+
+        // A reentrant sweep fails before locking any shard, so no shard is drained.
+        assert_eq!(
+            Err(ThreadMapLockError::Reentrant),
+            tm.with(|_| tm.drain().map(|_| ()))
+        );
+        let guard = tm.current();
+        assert!(matches!(tm.drain(), Err(ThreadMapLockError::Reentrant)));
+        drop(guard);
+
+        let expected_sum = (0..NTHREADS).sum::<i32>();
+        assert_eq!(expected_sum, tm.fold_values(0, |z, v| z + v).unwrap());
+    }
+
+    #[test]
+    fn test_lock_wait() {
+        let tm: ShardedThreadMap<i32> = ShardedThreadMap::default();
+        tm.set(1);
+
+        let locked = Barrier::new(2);
+        let release = Barrier::new(2);
+        thread::scope(|s| {
+            s.spawn(|| {
+                // The sweep holds the write lock of the main thread's shard while it visits the value.
+                tm.fold_values(0, |z, v| {
+                    locked.wait();
+                    release.wait();
+                    z + v
+                })
+            });
+            locked.wait();
+            assert_eq!(
+                Err(ThreadMapLockError::WouldBlock),
+                tm.try_with_nonblocking(|v| *v)
+            );
+            assert_eq!(
+                Err(ThreadMapLockError::Timeout),
+                tm.try_with_mut_timeout(Duration::from_millis(10), |v| *v += 1)
+            );
+            release.wait();
+        });
+
+        assert_eq!(Ok(1), tm.try_with_mut_nonblocking(|v| *v));
+        assert_eq!(
+            Ok(2),
+            tm.try_with_timeout(Duration::from_secs(60), |v| *v + 1)
+        );
+    }
+}
@@ -1,191 +1,235 @@
-use crate::{POISONED_OBJECT_RW_LOCK, ThreadMapLockError};
+use crate::{
+    DefaultRawRwLock,
+    sync::Wait,
+    thread_map_base::{
+        SlotStrategy, ThreadMapBase, ThreadMapBaseRef, ThreadMapBaseRefMut,
+        strategy::{SlotAccess, StateGuard, Strategy},
+    },
+};
+use lock_api::{RawMutex, RawRwLock};
 use std::{
     cell::UnsafeCell,
-    collections::HashMap,
-    fmt::Debug,
-    mem::take,
-    ops::DerefMut,
-    sync::RwLock,
-    thread::{self, ThreadId},
+    hash::RandomState,
+    sync::{Arc, PoisonError, TryLockError},
 };
 
-/// Wrapper to enable cell to be used as value in `HashMap`.
-struct UnsafeSyncCell<V>(UnsafeCell<V>);
+/// Wrapper to enable cell to be shared by the map.
+pub struct UnsafeSyncCell<V>(UnsafeCell<V>);
 
 /// SAFETY:
 /// An instance is only accessed privately by [`ThreadMap`], in two ways:
 /// - Under a [`ThreadMap`] instance read lock, always in the same thread.
 /// - Under a [`ThreadMap`] instance write lock, from an arbitrary thread.
-unsafe impl<V> Sync for UnsafeSyncCell<V> {}
-
-impl<V: Debug> Debug for UnsafeSyncCell<V> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(format_args!("{:?}", unsafe { &*self.0.get() }))
-    }
-}
-
-/// This type encapsulates the association of [`ThreadId`]s to values of type `V`. It is a simple and easy-to-use alternative
-/// to the [`std::thread_local`] macro and the [`thread_local`](https://crates.io/crates/thread_local) crate.
-///
-/// # Example
 ///
-/// ```rust
-#[doc = include_str!("../examples/doc_thread_map.rs")]
-/// ```
+/// In both cases the accessing thread has exclusive access to the value, so, as with [`Mutex`](std::sync::Mutex), it
+/// is sufficient for `V` to be [`Send`], but it must be [`Send`] as the value may be accessed from, or dropped in, a
+/// thread other than the one that created it.
+unsafe impl<V: Send> Sync for UnsafeSyncCell<V> {}
+
+/// Slot strategy of [`ThreadMap`], which stores each value in a cell without a lock of its own and relies on the
+/// object-level lock: a thread accesses its own value under the object-level read lock, and the values of other
+/// threads are only accessed under the object-level write lock.
 #[derive(Debug)]
-pub struct ThreadMap<V> {
-    state: RwLock<HashMap<ThreadId, UnsafeSyncCell<V>>>,
-    value_init: fn() -> V,
-}
+pub struct CellSlots;
 
-impl<V> ThreadMap<V> {
-    /// Creates a new [`ThreadMap`] instance, with `value_init` used to create the initial value for each thread.
-    pub fn new(value_init: fn() -> V) -> Self {
-        Self {
-            state: RwLock::new(HashMap::new()),
-            value_init,
-        }
-    }
+impl Strategy for CellSlots {
+    const NAME: &'static str = "ThreadMap";
 
-    /// Invokes `f` mutably on the value associated with the [`ThreadId`] of the current thread and returns the invocation result.
-    /// If there is no value associated with the current thread then the `value_init` argument of [`Self::new`] is used
-    /// to instantiate an initial associated value before `f` is applied.
-    ///
-    /// # Panics
-    /// - If `self`'s lock is poisoned.
-    pub fn with_mut<W>(&self, f: impl FnOnce(&mut V) -> W) -> W {
-        let lock = self.state.read().expect(POISONED_OBJECT_RW_LOCK);
-        let tid = thread::current().id();
-        match lock.get(&tid) {
-            Some(c) => {
-                let v = c.0.get();
-                // SAFETY: call below is always done in the thread with `ThreadId` `tid`, under an instance-level read lock.
-                // all other access to the cell is done under an instance-level write lock.
-                let rv = unsafe { &mut *v };
-                f(rv)
-            }
-            None => {
-                // Drop read lock and acquire write lock.
-                drop(lock);
-                let mut lock = self.state.write().expect(POISONED_OBJECT_RW_LOCK);
-                let mut v0 = (self.value_init)();
-                let w = f(&mut v0);
-                lock.insert(tid, UnsafeSyncCell(UnsafeCell::new(v0)));
-                w
-            }
-        }
-    }
+    const LOCKED_SLOTS: bool = false;
 
-    /// Invokes `f` on the value associated with the [`ThreadId`] of the current thread and returns the invocation result.
-    /// If there is no value associated with the current thread then the `value_init` argument of [`Self::new`] is used
-    /// to instantiate an initial associated value before `f` is applied.
-    ///
-    /// # Panics
-    /// - If `self`'s lock is poisoned.
-    pub fn with<W>(&self, f: impl FnOnce(&V) -> W) -> W {
-        let g = |v: &mut V| f(v);
-        self.with_mut(g)
-    }
+    type State = ();
 
-    /// Returns a clone of the value associated with the current thread.
-    ///
-    /// # Panics
-    /// - If `self`'s lock is poisoned.
-    pub fn get(&self) -> V
-    where
-        V: Clone,
-    {
-        self.with(|v| v.clone())
-    }
+    type Cell<V, M: RawMutex> = UnsafeSyncCell<V>;
+
+    type Access<'a, V: 'a, S: 'a, R: RawRwLock + 'a, M: RawMutex + 'a> =
+        CurrentCell<'a, V, S, R, M>;
 
-    // Sets the value associated with the current thread to `v`.
-    ///
-    /// # Panics
-    /// - If `self`'s lock is poisoned.
-    pub fn set(&self, v: V) {
-        self.with_mut(|v0| *v0 = v);
+    fn new_cell<V, M: RawMutex>(v: V, _ticket: u64) -> UnsafeSyncCell<V> {
+        UnsafeSyncCell(UnsafeCell::new(v))
     }
 
-    /// Returns a [`HashMap`] with the values associated with each [`ThreadId`] key and clears `self`'s state.
-    ///
-    /// # Errors
-    /// - [`ThreadMapLockError`] if the internal lock is poisoned.
-    pub fn drain(&self) -> Result<HashMap<ThreadId, V>, ThreadMapLockError> {
-        let mut lock = self.state.write()?;
-        let rmap = lock.deref_mut();
-        let tmap = take(rmap);
-        let map = tmap
-            .into_iter()
-            .map(|(k, v)| (k, v.0.into_inner()))
-            .collect::<HashMap<_, _>>();
-        Ok(map)
+    fn into_value<V, M: RawMutex>(cell: Arc<UnsafeSyncCell<V>>) -> Option<V> {
+        // The cell is never shared outside the map, so this is its only reference.
+        Arc::into_inner(cell).map(|c| c.0.into_inner())
     }
 
-    /// Folds every association in `self` into an accumulator (with initial value `z`) by applying an operation `f`,
-    /// returning the final result.
-    ///
-    /// # Errors
-    /// - [`ThreadMapLockError`] if the internal lock is poisoned.
-    pub fn fold<W>(
-        &self,
-        z: W,
-        f: impl FnMut(W, (ThreadId, &V)) -> W,
-    ) -> Result<W, ThreadMapLockError> {
-        let w = self
-            .state
-            .write()?
-            .iter()
-            .map(|(tid, c)| {
-                let v = c.0.get();
-                // SAFETY: call below is always done under an instance-level write lock.
-                (*tid, unsafe { &*v })
-            })
-            .fold(z, f);
-        Ok(w)
+    fn is_poisoned<V, M: RawMutex>(_: &UnsafeSyncCell<V>) -> bool {
+        false
     }
 
-    /// Folds every value in `self` into an accumulator (with initial value `z`) by applying an operation `f`,
-    /// returning the final result.
-    ///
-    /// # Errors
-    /// - [`ThreadMapLockError`] if the internal lock is poisoned.
-    pub fn fold_values<W>(
-        &self,
-        z: W,
-        mut f: impl FnMut(W, &V) -> W,
-    ) -> Result<W, ThreadMapLockError> {
-        self.fold(z, |w, (_, v)| f(w, v))
+    fn clear_poison<V, M: RawMutex>(_: &UnsafeSyncCell<V>) {}
+
+    #[inline]
+    unsafe fn access<'a, V, S, R: RawRwLock, M: RawMutex>(
+        lock: StateGuard<'a, V, Self, S, R, M>,
+        cell: *const UnsafeSyncCell<V>,
+        _wait: Wait,
+    ) -> Result<Option<CurrentCell<'a, V, S, R, M>>, TryLockError<()>> {
+        // SAFETY: the cell is in the map guarded by `lock`, so it is alive while `lock` is held.
+        let value = unsafe { (*cell).0.get() };
+        Ok(Some(CurrentCell { value, _lock: lock }))
     }
 
-    /// Returns a [`HashMap`] with clones of the values associated with each [`ThreadId`] key at the time the probe
-    /// was executed.
-    ///
-    /// # Errors
-    /// - [`ThreadMapLockError`] if the internal lock is poisoned.
-    pub fn probe(&self) -> Result<HashMap<ThreadId, V>, ThreadMapLockError>
-    where
-        V: Clone,
-    {
-        let z = HashMap::<ThreadId, V>::new();
-        self.fold(z, |mut w, (tid, v)| {
-            w.insert(tid, v.clone());
-            w
-        })
+    unsafe fn fold_value<V, M: RawMutex, W>(
+        cell: &UnsafeSyncCell<V>,
+        _ticket: u64,
+        w: W,
+        f: impl FnOnce(W, &V) -> W,
+    ) -> Result<W, PoisonError<()>> {
+        // SAFETY: the caller holds the object-level write lock.
+        Ok(f(w, unsafe { &*cell.0.get() }))
     }
 }
 
-impl<V: Default> Default for ThreadMap<V> {
-    fn default() -> Self {
-        Self::new(V::default)
+impl SlotStrategy for CellSlots {}
+
+/// Access to the value of a thread in a [`ThreadMap`], holding the object-level lock: shared if the value is the
+/// current thread's, and exclusive otherwise.
+pub struct CurrentCell<'a, V: 'a, S: 'a, R: RawRwLock + 'a, M: RawMutex + 'a> {
+    value: *mut V,
+    _lock: StateGuard<'a, V, CellSlots, S, R, M>,
+}
+
+impl<V, S, R: RawRwLock, M: RawMutex> SlotAccess<V> for CurrentCell<'_, V, S, R, M> {
+    #[inline]
+    fn value(&self) -> &V {
+        // SAFETY: `self` holds an instance-level read lock and the cell belongs to the current thread, or `self` holds
+        // an instance-level write lock. All other access to the cell is done under an instance-level write lock.
+        unsafe { &*self.value }
+    }
+
+    #[inline]
+    fn value_mut(&mut self) -> &mut V {
+        // SAFETY: as in `Self::value`. `self` is not aliased as the map's reentrancy marker is held while it is used.
+        unsafe { &mut *self.value }
     }
 }
 
+/// This type encapsulates the association of [`ThreadId`](std::thread::ThreadId)s to values of type `V`. It is a simple and easy-to-use alternative
+/// to the [`std::thread_local`] macro and the [`thread_local`](https://crates.io/crates/thread_local) crate.
+///
+/// It is an alias of [`ThreadMapBase`] with the [`CellSlots`] strategy, which stores each value without a lock of its
+/// own. The per-thread methods ([`Self::with`], [`Self::with_mut`], [`Self::get`], [`Self::set`], etc.) hold the
+/// object-level read lock while they run, and the methods that access the values of other threads ([`Self::fold`],
+/// [`Self::fold_values`], [`Self::probe`], [`Self::with_thread_mut`], and [`Self::debug_snapshot`]) hold the
+/// object-level write lock, so they block, and are blocked by, all other accesses to `self`.
+///
+/// # Example
+///
+/// ```rust
+#[doc = include_str!("../examples/doc_thread_map.rs")]
+/// ```
+///
+/// # Reentrancy
+///
+/// Calling a method of a [`ThreadMap`] from within a closure passed to a method of the same instance, or while holding a
+/// value returned by [`Self::current`], [`Self::current_mut`], or [`Self::debug_snapshot`], would deadlock or alias a mutable reference. Such reentrant calls are
+/// detected: fallible methods return [`ThreadMapLockError::Reentrant`](crate::ThreadMapLockError::Reentrant), and the other methods panic.
+/// Calling methods of a different instance is fine.
+///
+/// # Thread Safety
+///
+/// `ThreadMap<V>` is [`Send`] and [`Sync`] if and only if `V` is [`Send`], the same contract as [`Mutex`](std::sync::Mutex).
+/// Values are accessed from threads other than their own (by methods such as [`Self::fold`] and
+/// [`Self::with_thread`], and when `self` is dropped) only with exclusive access, so `V` need not be [`Sync`]:
+///
+/// ```rust
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<thread_map::ThreadMap<std::cell::Cell<i32>>>();
+/// ```
+///
+/// A map whose values are not [`Send`] cannot be shared with or sent to other threads:
+///
+/// ```compile_fail
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<thread_map::ThreadMap<std::rc::Rc<i32>>>();
+/// ```
+///
+/// ```compile_fail
+/// use std::{rc::Rc, thread};
+/// use thread_map::ThreadMap;
+///
+/// let tm = ThreadMap::new(|| Rc::new(0));
+/// thread::scope(|s| {
+///     s.spawn(|| tm.with(|v| **v));
+/// });
+/// ```
+///
+/// ```compile_fail
+/// fn assert_send<T: Send>() {}
+/// assert_send::<thread_map::ThreadMap<std::rc::Rc<i32>>>();
+/// ```
+///
+/// but can still be used on the current thread:
+///
+/// ```rust
+/// use std::rc::Rc;
+/// use thread_map::ThreadMap;
+///
+/// let tm = ThreadMap::new(|| Rc::new(0));
+/// tm.set(Rc::new(1));
+/// assert_eq!(1, tm.with(|v| **v));
+/// assert_eq!(1, tm.fold_values(0, |z, v| z + **v).unwrap());
+/// ```
+///
+/// # Hashing
+///
+/// The values are kept in a [`HashMap`](std::collections::HashMap) keyed by [`ThreadId`](std::thread::ThreadId) that uses the hasher built by `S`, [`RandomState`] by
+/// default. As [`ThreadId`](std::thread::ThreadId)s are unique and not chosen by callers, the faster [`ThreadIdBuildHasher`](crate::ThreadIdBuildHasher)
+/// can be used instead with [`Self::with_hasher`].
+///
+/// # Locks
+///
+/// The object-level lock is a [`lock_api::RwLock`] whose raw lock is `R`, [`DefaultRawRwLock`] by default. Any other
+/// implementation of [`lock_api::RawRwLock`] can be used instead with [`Self::with_raw_locks`].
+pub type ThreadMap<V, S = RandomState, R = DefaultRawRwLock> = ThreadMapBase<V, CellSlots, S, R>;
+
+/// Guard returned by [`ThreadMap::current`] that dereferences to the value associated with the current thread.
+/// It holds the map's object-level read lock until dropped. It is not [`Send`], so it cannot leave the current thread:
+///
+/// ```compile_fail
+/// use std::thread;
+/// use thread_map::ThreadMap;
+///
+/// let tm = ThreadMap::new(|| 0);
+/// let guard = tm.current();
+/// thread::scope(|s| {
+///     s.spawn(move || *guard);
+/// });
+/// ```
+pub type ThreadMapRef<'a, V, S = RandomState, R = DefaultRawRwLock> =
+    ThreadMapBaseRef<'a, V, CellSlots, S, R>;
+
+/// Guard returned by [`ThreadMap::current_mut`] that dereferences mutably to the value associated with the current
+/// thread. It holds the map's object-level read lock until dropped. It is not [`Send`], so it cannot leave the
+/// current thread:
+///
+/// ```compile_fail
+/// use std::thread;
+/// use thread_map::ThreadMap;
+///
+/// let tm = ThreadMap::new(|| 0);
+/// let mut guard = tm.current_mut();
+/// thread::scope(|s| {
+///     s.spawn(move || *guard += 1);
+/// });
+/// ```
+pub type ThreadMapRefMut<'a, V, S = RandomState, R = DefaultRawRwLock> =
+    ThreadMapBaseRefMut<'a, V, CellSlots, S, R>;
+
 #[allow(clippy::unwrap_used)]
 #[cfg(test)]
 mod test {
     use super::ThreadMap;
+    use crate::{
+        ThreadIdBuildHasher, ThreadMapLockError, ValueKey,
+        raw_lock::test::{CountingRawRwLock, RW_LOCKS},
+    };
     use std::{
         collections::HashMap,
+        hash::RandomState,
+        panic::{AssertUnwindSafe, catch_unwind},
+        sync::{Arc, Barrier, Mutex, atomic::Ordering},
         thread::{self},
         time::Duration,
     };
@@ -261,4 +305,608 @@ mod test {
         let sum = tm.fold_values(0, |z, v| z + v).unwrap();
         assert_eq!(expected_sum, sum);
     }
+
+    #[test]
+    fn test_with_hasher() {
+        let tm = Arc::new(
+            ThreadMap::with_hasher(|| 0, ThreadIdBuildHasher::default())
+                .retire_on_thread_exit(|acc, v| *acc += v),
+        );
+
+        let handles = (0..NTHREADS)
+            .map(|i| {
+                let tm = tm.clone();
+                thread::spawn(move || tm.set(i))
+            })
+            .collect::<Vec<_>>();
+        for h in handles {
+            h.join().unwrap();
+        }
+        tm.set(NTHREADS);
+
+        let retired_sum = (0..NTHREADS).sum::<i32>();
+        assert_eq!(Some(retired_sum), tm.retired().unwrap());
+        assert_eq!(
+            retired_sum + NTHREADS,
+            tm.fold_values(0, |z, v| z + v).unwrap()
+        );
+        assert_eq!(
+            HashMap::from([(thread::current().id(), NTHREADS)]),
+            tm.drain().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_custom_raw_lock() {
+        let init = Arc::new(1);
+        let tm: ThreadMap<i32, RandomState, CountingRawRwLock> =
+            ThreadMap::with_raw_locks(move || *init, RandomState::new());
+
+        thread::scope(|s| {
+            let tm = &tm;
+            for i in 0..NTHREADS {
+                s.spawn(move || tm.with_mut(|v| *v += i));
+            }
+        });
+
+        let expected_sum = (0..NTHREADS).sum::<i32>() + NTHREADS;
+        assert_eq!(expected_sum, tm.fold_values(0, |z, v| z + v).unwrap());
+        assert!(RW_LOCKS.load(Ordering::Relaxed) > 0);
+    }
+
+    #[test]
+    fn test_from_fn() {
+        let capacity = Arc::new(8usize);
+        let tm: ThreadMap<Vec<i32>> = {
+            let capacity = capacity.clone();
+            ThreadMap::from_fn(move || Vec::with_capacity(*capacity))
+        };
+
+        thread::scope(|s| {
+            let tm = &tm;
+            let capacity = *capacity;
+            for i in 0..NTHREADS {
+                s.spawn(move || {
+                    tm.with_mut(|v| v.push(i));
+                    tm.with(|v| assert!(v.capacity() >= capacity));
+                });
+            }
+        });
+
+        let expected_sum = (0..NTHREADS).sum::<i32>();
+        let sum = tm.fold_values(0, |z, v| z + v.iter().sum::<i32>()).unwrap();
+        assert_eq!(expected_sum, sum);
+    }
+
+    #[test]
+    fn test_from_thread_fn() {
+        let tm: ThreadMap<(String, usize)> = ThreadMap::from_thread_fn(|info| {
+            let name = info.thread().name().unwrap_or_default().to_owned();
+            (name, info.index())
+        });
+
+        thread::scope(|s| {
+            let tm = &tm;
+            for i in 0..NTHREADS {
+                thread::Builder::new()
+                    .name(format!("worker-{i}"))
+                    .spawn_scoped(s, move || {
+                        let (name, _) = tm.get();
+                        assert_eq!(format!("worker-{i}"), name);
+                    })
+                    .unwrap();
+            }
+        });
+
+        let mut indices = tm
+            .fold_values(Vec::new(), |mut z, (_, index)| {
+                z.push(*index);
+                z
+            })
+            .unwrap();
+        indices.sort();
+        assert_eq!((0..NTHREADS as usize).collect::<Vec<_>>(), indices);
+    }
+
+    #[cfg(not(feature = "parking_lot"))]
+    #[test]
+    fn test_try_poisoned() {
+        let tm: ThreadMap<i32> = ThreadMap::default();
+
+        tm.try_set(1).unwrap();
+        assert_eq!(1, tm.try_get().unwrap());
+        assert_eq!(2, tm.try_with(|v| v + 1).unwrap());
+
+        let poisoner = thread::scope(|s| {
+            let worker = s.spawn(|| tm.fold_values(0, |_, _| panic!("poisoning")));
+            let tid = worker.thread().id();
+            assert!(worker.join().is_err());
+            tid
+        });
+
+        let err = ThreadMapLockError::PoisonedObjectLock(poisoner);
+        assert_eq!(Err(err), tm.try_with_mut(|v| *v += 1));
+        assert_eq!(Err(err), tm.try_with(|v| *v));
+        assert_eq!(Err(err), tm.try_get());
+        assert_eq!(Err(err), tm.try_set(2));
+        assert_eq!(Err(err), tm.try_with_nonblocking(|v| *v));
+        assert_eq!(
+            format!("object RwLock poisoned by {poisoner:?}"),
+            err.to_string()
+        );
+
+        // Once the poisoned state is cleared, the error reports the thread that poisons the lock next.
+        tm.clear_poison().unwrap();
+        let res = catch_unwind(AssertUnwindSafe(|| {
+            tm.fold_values(0, |_, _| panic!("poisoning"))
+        }));
+        assert!(res.is_err());
+        let err = ThreadMapLockError::PoisonedObjectLock(thread::current().id());
+        assert_eq!(Err(err), tm.try_get());
+    }
+
+    #[cfg(feature = "parking_lot")]
+    #[test]
+    fn test_no_poisoning() {
+        let tm: ThreadMap<i32> = ThreadMap::default();
+        tm.set(1);
+
+        let res = catch_unwind(AssertUnwindSafe(|| tm.with(|_| panic!("panicking"))));
+        assert!(res.is_err());
+        let res = catch_unwind(AssertUnwindSafe(|| {
+            tm.fold_values(0, |_, _| panic!("panicking"))
+        }));
+        assert!(res.is_err());
+
+        assert!(!tm.is_poisoned().unwrap());
+        assert_eq!(1, tm.try_get().unwrap());
+        assert_eq!(1, tm.fold_values(0, |z, v| z + v).unwrap());
+    }
+
+    #[test]
+    fn test_lock_wait() {
+        let tm: ThreadMap<i32> = ThreadMap::default();
+        tm.set(1);
+
+        let locked = Barrier::new(2);
+        let release = Barrier::new(2);
+        thread::scope(|s| {
+            s.spawn(|| {
+                // The sweep holds the object-level write lock while it visits the value of the main thread.
+                tm.fold_values(0, |z, v| {
+                    locked.wait();
+                    release.wait();
+                    z + v
+                })
+            });
+            locked.wait();
+            assert_eq!(
+                Err(ThreadMapLockError::WouldBlock),
+                tm.try_with_nonblocking(|v| *v)
+            );
+            assert_eq!(
+                Err(ThreadMapLockError::Timeout),
+                tm.try_with_mut_timeout(Duration::from_millis(10), |v| *v += 1)
+            );
+            release.wait();
+        });
+
+        assert_eq!(Ok(1), tm.try_with_mut_nonblocking(|v| *v));
+        assert_eq!(
+            Ok(2),
+            tm.try_with_timeout(Duration::from_secs(60), |v| *v + 1)
+        );
+    }
+
+    #[cfg(not(feature = "parking_lot"))]
+    #[test]
+    fn test_poison_recovery() {
+        let tm: ThreadMap<i32> = ThreadMap::default();
+
+        thread::scope(|s| {
+            let tm = &tm;
+            for i in 0..NTHREADS {
+                s.spawn(move || tm.set(i));
+            }
+        });
+        tm.set(NTHREADS);
+        assert!(!tm.is_poisoned().unwrap());
+
+        let res = catch_unwind(AssertUnwindSafe(|| {
+            tm.fold_values(0, |_, _| panic!("poisoning"))
+        }));
+        assert!(res.is_err());
+        assert!(tm.is_poisoned().unwrap());
+        assert!(tm.drain().is_err());
+
+        let expected_sum = (0..=NTHREADS).sum::<i32>();
+        let drained = tm.drain_poisoned().unwrap();
+        assert_eq!(expected_sum, drained.values().sum::<i32>());
+        assert!(tm.is_poisoned().unwrap());
+
+        tm.clear_poison().unwrap();
+        assert!(!tm.is_poisoned().unwrap());
+        assert_eq!(0, tm.get());
+        assert_eq!(0, tm.fold_values(0, |z, v| z + v).unwrap());
+    }
+
+    #[cfg(not(feature = "parking_lot"))]
+    #[test]
+    fn test_retired_poison_recovery() {
+        let tm: Arc<ThreadMap<i32>> =
+            Arc::new(ThreadMap::default().retire_on_thread_exit(|acc, v| *acc += v));
+
+        let handles = (0..NTHREADS)
+            .map(|i| {
+                let tm = tm.clone();
+                thread::spawn(move || tm.set(i))
+            })
+            .collect::<Vec<_>>();
+        for h in handles {
+            h.join().unwrap();
+        }
+        tm.set(NTHREADS);
+
+        tm.poison_retired();
+        assert!(tm.is_poisoned().unwrap());
+        assert_eq!(
+            Err(ThreadMapLockError::PoisonedRetiredLock),
+            tm.take_retired()
+        );
+
+        // The accumulator is drained together with the values.
+        let retired_sum = (0..NTHREADS).sum::<i32>();
+        assert_eq!(
+            HashMap::from([
+                (ValueKey::Thread(thread::current().id()), NTHREADS),
+                (ValueKey::Retired, retired_sum),
+            ]),
+            tm.drain_poisoned().unwrap()
+        );
+
+        tm.clear_poison().unwrap();
+        assert!(!tm.is_poisoned().unwrap());
+        assert_eq!(None, tm.take_retired().unwrap());
+        assert_eq!(0, tm.fold_values(0, |z, v| z + v).unwrap());
+    }
+
+    #[test]
+    fn test_take_replace_remove_reset() {
+        let tm: ThreadMap<i32> = ThreadMap::from_thread_fn(|info| info.index() as i32 * 100);
+
+        thread::scope(|s| {
+            let tm = &tm;
+            for i in 0..NTHREADS {
+                s.spawn(move || {
+                    assert_eq!(None, tm.take());
+                    tm.set(i);
+                    assert_eq!(i, tm.replace(i + 1));
+                    assert_eq!(Some(i + 1), tm.take());
+                    assert_eq!(None, tm.take());
+
+                    tm.set(i);
+                    tm.remove();
+                    assert_eq!(None, tm.take());
+
+                    tm.set(i);
+                    tm.reset();
+                    assert_ne!(i, tm.get());
+                    assert_eq!(0, tm.get() % 100);
+                    tm.remove();
+                });
+            }
+        });
+
+        assert!(tm.drain().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_remove_on_thread_exit() {
+        let tm: Arc<ThreadMap<i32>> = Arc::new(ThreadMap::default().remove_on_thread_exit());
+
+        let handles = (0..NTHREADS)
+            .map(|i| {
+                let tm = tm.clone();
+                thread::spawn(move || tm.set(i))
+            })
+            .collect::<Vec<_>>();
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        tm.set(NTHREADS);
+        let probed = tm.probe().unwrap();
+        assert_eq!(
+            HashMap::from([(ValueKey::Thread(thread::current().id()), NTHREADS)]),
+            probed
+        );
+    }
+
+    #[test]
+    fn test_on_thread_exit() {
+        let exited = Arc::new(Mutex::new(HashMap::new()));
+        let tm: Arc<ThreadMap<i32>> = {
+            let exited = exited.clone();
+            Arc::new(ThreadMap::default().on_thread_exit(move |tid, v| {
+                exited.lock().unwrap().insert(tid, v);
+            }))
+        };
+
+        let handles = (0..NTHREADS)
+            .map(|i| {
+                let tm = tm.clone();
+                thread::spawn(move || {
+                    // Re-registration after `take` must not cause the value to be reported twice.
+                    tm.set(i);
+                    tm.take();
+                    tm.set(i);
+                })
+            })
+            .collect::<Vec<_>>();
+        let tids = handles
+            .into_iter()
+            .map(|h| {
+                let tid = h.thread().id();
+                h.join().unwrap();
+                tid
+            })
+            .collect::<Vec<_>>();
+
+        assert!(tm.drain().unwrap().is_empty());
+        let exited = exited.lock().unwrap();
+        assert_eq!(NTHREADS as usize, exited.len());
+        for (i, tid) in tids.iter().enumerate() {
+            assert_eq!(Some(&(i as i32)), exited.get(tid));
+        }
+    }
+
+    #[test]
+    fn test_retire_on_thread_exit() {
+        let tm: Arc<ThreadMap<i32>> =
+            Arc::new(ThreadMap::default().retire_on_thread_exit(|acc, v| *acc += v));
+
+        let handles = (0..NTHREADS)
+            .map(|i| {
+                let tm = tm.clone();
+                thread::spawn(move || tm.set(i))
+            })
+            .collect::<Vec<_>>();
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        tm.set(NTHREADS);
+        let retired_sum = (0..NTHREADS).sum::<i32>();
+        assert_eq!(Some(retired_sum), tm.retired().unwrap());
+        let expected = HashMap::from([
+            (ValueKey::Thread(thread::current().id()), NTHREADS),
+            (ValueKey::Retired, retired_sum),
+        ]);
+        assert_eq!(expected, tm.probe().unwrap());
+        let folded = tm
+            .fold(HashMap::new(), |mut z, (key, v)| {
+                *z.entry(key).or_insert(0) += v;
+                z
+            })
+            .unwrap();
+        assert_eq!(expected, folded);
+        assert_eq!(
+            retired_sum + NTHREADS,
+            tm.fold_values(0, |z, v| z + v).unwrap()
+        );
+
+        assert_eq!(Some(retired_sum), tm.take_retired().unwrap());
+        assert_eq!(
+            HashMap::from([(ValueKey::Thread(thread::current().id()), NTHREADS)]),
+            tm.probe().unwrap()
+        );
+        assert_eq!(None, tm.retired().unwrap());
+        assert_eq!(NTHREADS, tm.fold_values(0, |z, v| z + v).unwrap());
+    }
+
+    #[test]
+    fn test_with_thread() {
+        let tm: ThreadMap<i32> = ThreadMap::default();
+        let barrier = Barrier::new(2);
+
+        thread::scope(|s| {
+            let tm = &tm;
+            let barrier = &barrier;
+            let worker = s.spawn(move || {
+                tm.set(1);
+                barrier.wait(); // value set
+                barrier.wait(); // value inspected and reset by supervisor
+                tm.get()
+            });
+            let tid = worker.thread().id();
+
+            barrier.wait();
+            assert!(tm.contains(tid).unwrap());
+            assert!(!tm.contains(thread::current().id()).unwrap());
+            assert_eq!(Some(1), tm.with_thread(tid, |v| *v).unwrap());
+            assert_eq!(Some(()), tm.with_thread_mut(tid, |v| *v = 42).unwrap());
+            assert_eq!(
+                None,
+                tm.with_thread(thread::current().id(), |v| *v).unwrap()
+            );
+            barrier.wait();
+
+            assert_eq!(42, worker.join().unwrap());
+        });
+    }
+
+    #[test]
+    fn test_debug() {
+        let tm: ThreadMap<i32> = ThreadMap::default();
+        tm.set(42);
+        let tid = thread::current().id();
+
+        let out = format!("{tm:?}");
+        assert_eq!(format!("ThreadMap {{ thread_ids: [{tid:?}], .. }}"), out);
+
+        // The `Debug` implementation does not block while the lock is held.
+        let out = tm.with(|_| format!("{tm:?}"));
+        assert!(out.starts_with("ThreadMap"));
+
+        let out = format!("{:?}", tm.debug_snapshot().unwrap());
+        assert_eq!(format!("{{{tid:?}: 42}}"), out);
+    }
+
+    #[test]
+    fn test_reentrancy() {
+        fn is_reentrant<T>(res: Result<T, ThreadMapLockError>) -> bool {
+            matches!(res, Err(ThreadMapLockError::Reentrant))
+        }
+
+        let tm: ThreadMap<i32> = ThreadMap::default();
+        let other: ThreadMap<i32> = ThreadMap::default();
+
+        // Per-thread methods within per-thread methods, first on a new and then on an existing value.
+        assert!(is_reentrant(tm.with_mut(|_| tm.try_with_mut(|v| *v += 1))));
+        assert!(is_reentrant(tm.with_mut(|_| tm.try_with_mut(|v| *v += 1))));
+        assert!(is_reentrant(tm.with(|_| tm.try_get())));
+        assert!(is_reentrant(tm.with_mut(|_| tm.try_set(1))));
+        assert!(is_reentrant(tm.with(|_| tm.try_take())));
+
+        // Sweeps and per-thread methods within each other.
+        assert!(is_reentrant(tm.with(|_| tm.fold_values(0, |z, v| z + v))));
+        assert!(is_reentrant(tm.with(|_| tm.drain())));
+        assert!(is_reentrant(tm.with(|_| tm.is_poisoned())));
+        assert!(is_reentrant(tm.with(|_| tm.clear_poison())));
+        assert!(is_reentrant(tm.with(|_| tm.drain_poisoned())));
+        assert!(is_reentrant(
+            tm.with(|_| tm.contains(thread::current().id()))
+        ));
+        assert!(is_reentrant(
+            tm.fold_values(Ok(()), |_, _| tm.try_set(1)).unwrap()
+        ));
+        assert!(is_reentrant(
+            tm.fold(Ok(None), |_, _| tm
+                .with_thread(thread::current().id(), |v| *v))
+                .unwrap()
+        ));
+        assert!(is_reentrant(
+            tm.fold_values(Ok(0), |_, _| tm.fold_values(0, |z, v| z + v))
+                .unwrap()
+        ));
+        assert!(is_reentrant(
+            tm.with_thread_mut(thread::current().id(), |_| tm.probe())
+                .unwrap()
+                .unwrap()
+        ));
+
+        // Methods while a debug snapshot is alive.
+        let snapshot = tm.debug_snapshot().unwrap();
+        assert!(is_reentrant(tm.try_get()));
+        assert!(is_reentrant(tm.debug_snapshot().map(|_| ())));
+        drop(snapshot);
+
+        // Nesting with a different instance is fine.
+        assert_eq!(
+            1,
+            tm.with(|_| other.with_mut(|v| {
+                *v += 1;
+                *v
+            }))
+        );
+
+        // The instance is still usable.
+        assert!(!tm.is_poisoned().unwrap());
+        assert_eq!(0, tm.get());
+        tm.set(2);
+        assert_eq!(2, tm.fold_values(0, |z, v| z + v).unwrap());
+
+        // Panicking methods.
+        let res = catch_unwind(AssertUnwindSafe(|| tm.with(|_| tm.get())));
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_first_access_not_under_write_lock() {
+        let barrier = Arc::new(Barrier::new(2));
+        let tm: ThreadMap<i32> = {
+            let barrier = barrier.clone();
+            ThreadMap::from_fn(move || {
+                barrier.wait(); // initializer running
+                barrier.wait(); // other thread done
+                1
+            })
+        };
+
+        thread::scope(|s| {
+            let tm = &tm;
+            let barrier = &barrier;
+            s.spawn(move || tm.with_mut(|v| *v += 1));
+
+            barrier.wait();
+            // Would deadlock if the initializer ran under the object-level write lock.
+            assert_eq!(0, tm.fold_values(0, |z, v| z + v).unwrap());
+            barrier.wait();
+        });
+
+        assert_eq!(2, tm.fold_values(0, |z, v| z + v).unwrap());
+    }
+
+    #[test]
+    fn test_slot_cache() {
+        // More maps than the cache holds, accessed alternately.
+        let tms = (0..NTHREADS)
+            .map(|_| ThreadMap::default())
+            .collect::<Vec<_>>();
+        for _ in 0..NITER {
+            for (i, tm) in tms.iter().enumerate() {
+                tm.with_mut(|v: &mut i32| *v += i as i32);
+            }
+        }
+        for (i, tm) in tms.iter().enumerate() {
+            assert_eq!(i as i32 * NITER, tm.get());
+        }
+
+        // Cached locations of values removed by other threads, or by the current thread, are not used.
+        let tm = &tms[1];
+        thread::scope(|s| {
+            s.spawn(|| tm.drain().unwrap());
+        });
+        assert_eq!(0, tm.get());
+        tm.set(2);
+        assert_eq!(Some(2), tm.take());
+        assert_eq!(0, tm.get());
+    }
+
+    #[test]
+    fn test_current() {
+        fn add(tm: &ThreadMap<i32>, i: i32) -> Result<i32, ThreadMapLockError> {
+            let mut v = tm.try_current_mut()?;
+            *v += i;
+            Ok(*v)
+        }
+
+        let tm: ThreadMap<i32> = ThreadMap::default();
+
+        thread::scope(|s| {
+            let tm = &tm;
+            for i in 0..NTHREADS {
+                s.spawn(move || {
+                    for _ in 0..NITER {
+                        add(tm, i).unwrap();
+                    }
+                    assert_eq!(i * NITER, *tm.current());
+                });
+            }
+        });
+
+        let expected_sum = (0..NTHREADS).map(|i| i * NITER).sum::<i32>();
+        assert_eq!(expected_sum, tm.fold_values(0, |z, v| z + v).unwrap());
+
+        // Other methods of the same instance are reentrant while a guard is alive.
+        let guard = tm.current();
+        assert_eq!("0", format!("{guard:?}"));
+        assert_eq!(Err(ThreadMapLockError::Reentrant), tm.try_get());
+        assert!(matches!(
+            tm.try_current_mut(),
+            Err(ThreadMapLockError::Reentrant)
+        ));
+        drop(guard);
+        assert_eq!(1, add(&tm, 1).unwrap());
+    }
 }
@@ -1,178 +1,257 @@
-use crate::{POISONED_OBJECT_RW_LOCK, POISONED_THREAD_LOCK};
-
-use super::ThreadMapLockError;
+use crate::{
+    DefaultRawMutex, DefaultRawRwLock,
+    sync::{Mutex, MutexGuard, Wait, discard_guard},
+    thread_map_base::{
+        SlotStrategy, ThreadMapBase, ThreadMapBaseRef, ThreadMapBaseRefMut,
+        strategy::{SlotAccess, StateGuard, Strategy},
+    },
+};
+use lock_api::{RawMutex, RawRwLock};
 use std::{
-    collections::HashMap,
-    mem::take,
-    ops::DerefMut,
-    sync::{Mutex, RwLock},
-    thread::{self, ThreadId},
+    hash::RandomState,
+    sync::{Arc, PoisonError, TryLockError},
 };
 
+/// Cell holding the value of a thread under its own lock. The value is `None` once it has been removed from the map,
+/// so that a thread that obtained the cell before the removal does not access a stale value.
+pub struct MutexCell<V, M>(Mutex<Option<V>, M>);
+
+/// Slot strategy of [`ThreadMapX`], which stores each value in a cell with its own lock. Accesses to a value hold the
+/// value's lock, and only hold the object-level lock while the cell is looked up. Sweeps collect the cells under the
+/// object-level read lock and release it before acquiring the values' locks.
+#[derive(Debug)]
+pub struct MutexSlots;
+
+impl Strategy for MutexSlots {
+    const NAME: &'static str = "ThreadMapX";
+
+    const LOCKED_SLOTS: bool = true;
+
+    const DETACHED_SWEEPS: bool = true;
+
+    type State = ();
+
+    type Cell<V, M: RawMutex> = MutexCell<V, M>;
+
+    type Access<'a, V: 'a, S: 'a, R: RawRwLock + 'a, M: RawMutex + 'a> = CurrentSlot<'a, V, M>;
+
+    fn new_cell<V, M: RawMutex>(v: V, _ticket: u64) -> MutexCell<V, M> {
+        MutexCell(Mutex::new(Some(v)))
+    }
+
+    fn into_value<V, M: RawMutex>(cell: Arc<MutexCell<V, M>>) -> Option<V> {
+        cell.0.lock().unwrap_or_else(PoisonError::into_inner).take()
+    }
+
+    fn is_poisoned<V, M: RawMutex>(cell: &MutexCell<V, M>) -> bool {
+        cell.0.is_poisoned()
+    }
+
+    fn clear_poison<V, M: RawMutex>(cell: &MutexCell<V, M>) {
+        cell.0.clear_poison();
+    }
+
+    #[inline]
+    unsafe fn access<'a, V, S, R: RawRwLock, M: RawMutex>(
+        lock: StateGuard<'a, V, Self, S, R, M>,
+        cell: *const MutexCell<V, M>,
+        wait: Wait,
+    ) -> Result<Option<CurrentSlot<'a, V, M>>, TryLockError<()>> {
+        // SAFETY: the cell is in the map guarded by `lock`, inside an `Arc`, and it cannot be removed while `lock` is
+        // held. Therefore, the `Arc`'s strong count is at least 1 when it is incremented.
+        let slot = unsafe {
+            Arc::increment_strong_count(cell);
+            Arc::from_raw(cell)
+        };
+        drop(lock);
+        // SAFETY: the mutex is kept alive by `slot`, which is moved into the returned value, where it is dropped
+        // after the guard.
+        let mutex = unsafe { &(*Arc::as_ptr(&slot)).0 };
+        let guard = mutex.lock_wait(wait).map_err(discard_guard)?;
+        // The value may have been removed by another thread (e.g., with `drain`) after `lock` was released.
+        Ok(guard.is_some().then(|| CurrentSlot { guard, _slot: slot }))
+    }
+
+    unsafe fn fold_value<V, M: RawMutex, W>(
+        cell: &MutexCell<V, M>,
+        _ticket: u64,
+        w: W,
+        f: impl FnOnce(W, &V) -> W,
+    ) -> Result<W, PoisonError<()>> {
+        let v = cell.0.lock().map_err(|_| PoisonError::new(()))?;
+        // The value may have been taken by its thread, which then waits for the object-level lock to remove the slot.
+        Ok(match v.as_ref() {
+            Some(v) => f(w, v),
+            None => w,
+        })
+    }
+}
+
+impl SlotStrategy for MutexSlots {}
+
+/// Access to the value of a thread in a [`ThreadMapX`], holding the thread's lock.
+pub struct CurrentSlot<'a, V, M: RawMutex> {
+    /// Declared before `_slot` so that it is dropped first, as it refers to the mutex in `_slot`.
+    guard: MutexGuard<'a, Option<V>, M>,
+    _slot: Arc<MutexCell<V, M>>,
+}
+
+impl<V, M: RawMutex> SlotAccess<V> for CurrentSlot<'_, V, M> {
+    #[inline]
+    fn value(&self) -> &V {
+        self.guard
+            .as_ref()
+            .expect("value cannot be removed while its lock is held")
+    }
+
+    #[inline]
+    fn value_mut(&mut self) -> &mut V {
+        self.guard
+            .as_mut()
+            .expect("value cannot be removed while its lock is held")
+    }
+}
+
 /// Like [`ThreadMap`](crate::ThreadMap),
-/// this type encapsulates the association of [`ThreadId`]s to values of type `V` and is a simple and easy-to-use alternative
+/// this type encapsulates the association of [`ThreadId`](std::thread::ThreadId)s to values of type `V` and is a simple and easy-to-use alternative
 /// to the [`std::thread_local`] macro and the [`thread_local`](https://crates.io/crates/thread_local) crate.
-/// It differs from [`ThreadMap`](crate::ThreadMap) in that it contains a [`Mutex`] for each value, allowing the methods
+/// It differs from [`ThreadMap`](crate::ThreadMap) in that it contains a [`Mutex`](lock_api::Mutex) for each value, allowing the methods
 /// [`Self::fold`], [`Self::fold_values`], and [`Self::probe`]
 /// to run more efficiently when there are concurrent calls to the per-thread methods
 /// ([`Self::with`], [`Self::with_mut`], [`Self::get`], [`Self::set`])
 /// by using fine-grained per-thread locking instead of acquiring an object-level write lock.
 /// On the other hand, the per-thread methods may run a bit slower as they require the acquision of the per-thread lock.
+/// It is an alias of [`ThreadMapBase`] with the [`MutexSlots`] strategy.
+///
+/// Each value is stored behind a shared handle, so the per-thread methods ([`Self::with_mut`], [`Self::with_thread_mut`],
+/// etc.) release the object-level lock before running their closures. Therefore, a thread performing a long
+/// operation on its value does not prevent other threads from registering their values or [`Self::drain`] from
+/// removing the values. The methods that iterate over all values ([`Self::fold`], [`Self::fold_values`],
+/// [`Self::probe`], and [`Self::debug_snapshot`]) only hold the object-level read lock while they collect the handles
+/// of the values, and then acquire the per-thread locks one at a time, so a sweep waiting for a long operation on a
+/// value does not block other threads from registering their values. They visit the values associated with a
+/// [`ThreadId`](std::thread::ThreadId) when they start, skipping those that are removed before they are reached.
+/// [`Self::with_thread_mut`] only holds the per-thread lock of the target thread while its
+/// closure runs, so it only blocks, and is blocked by, accesses to that thread's value.
 ///
 /// # Example
 ///
 /// ```rust
 #[doc = include_str!("../examples/doc_thread_map_x.rs")]
 /// ```
-#[derive(Debug)]
-pub struct ThreadMapX<V> {
-    state: RwLock<HashMap<ThreadId, Mutex<V>>>,
-    value_init: fn() -> V,
-}
-
-impl<V> ThreadMapX<V> {
-    /// Creates a new [`ThreadMapX`] instance, with `value_init` used to create the initial value for each thread.
-    pub fn new(value_init: fn() -> V) -> Self {
-        Self {
-            state: RwLock::new(HashMap::new()),
-            value_init,
-        }
-    }
-
-    /// Invokes `f` mutably on the value associated with the [`ThreadId`] of the current thread and returns the invocation result.
-    /// If there is no value associated with the current thread then the `value_init` argument of [`Self::new`] is used
-    /// to instantiate an initial associated value before `f` is applied.
-    ///
-    /// # Panics
-    /// - If `self`'s lock is poisoned.
-    pub fn with_mut<W>(&self, f: impl FnOnce(&mut V) -> W) -> W {
-        let lock = self.state.read().expect(POISONED_OBJECT_RW_LOCK);
-        let tid = thread::current().id();
-        match lock.get(&tid) {
-            Some(c) => {
-                let mut v = c.lock();
-                // A panic that poisons the thread mutex will also poison the object-level RwLock.
-                let rv = v.as_mut().expect(POISONED_THREAD_LOCK);
-                f(rv)
-            }
-            None => {
-                // Drop read lock and acquire write lock.
-                drop(lock);
-                let mut lock = self.state.write().expect(POISONED_OBJECT_RW_LOCK);
-                let mut v0 = (self.value_init)();
-                let w = f(&mut v0);
-                lock.insert(tid, Mutex::new(v0));
-                w
-            }
-        }
-    }
-
-    /// Invokes `f` on the value associated with the [`ThreadId`] of the current thread and returns the invocation result.
-    /// If there is no value associated with the current thread then the `value_init` argument of [`Self::new`] is used
-    /// to instantiate an initial associated value before `f` is applied.
-    ///
-    /// # Panics
-    /// - If `self`'s lock is poisoned.
-    pub fn with<W>(&self, f: impl FnOnce(&V) -> W) -> W {
-        let g = |v: &mut V| f(v);
-        self.with_mut(g)
-    }
-
-    /// Returns a clone of the value associated with the current thread.
-    ///
-    /// # Panics
-    /// - If `self`'s lock is poisoned.
-    pub fn get(&self) -> V
-    where
-        V: Clone,
-    {
-        self.with(|v| v.clone())
-    }
-
-    // Sets the value associated with the current thread to `v`.
-    ///
-    /// # Panics
-    /// - If `self`'s lock is poisoned.
-    pub fn set(&self, v: V) {
-        self.with_mut(|v0| *v0 = v);
-    }
-
-    /// Returns a [`HashMap`] with the values associated with each [`ThreadId`] key and clears `self`'s state.
-    ///
-    /// # Errors
-    /// - [`ThreadMapLockError`] if the internal lock is poisoned.
-    pub fn drain(&self) -> Result<HashMap<ThreadId, V>, ThreadMapLockError> {
-        let mut lock = self.state.write()?;
-        let rmap = lock.deref_mut();
-        let tmap = take(rmap);
-        tmap.into_iter()
-            .map(|(k, v)| {
-                let v = v.into_inner()?;
-                Ok((k, v))
-            })
-            .collect()
-    }
-
-    /// Folds every association in `self` into an accumulator (with initial value `z`) by applying an operation `f`,
-    /// returning the final result.
-    ///
-    /// # Errors
-    /// - [`ThreadMapLockError`] if the internal lock is poisoned.
-    pub fn fold<W>(
-        &self,
-        z: W,
-        mut f: impl FnMut(W, (ThreadId, &V)) -> W,
-    ) -> Result<W, ThreadMapLockError> {
-        self.state.read()?.iter().try_fold(z, |w, (tid, v)| {
-            let tid = *tid;
-            let mut mlock = v.lock()?;
-            let v = mlock.deref_mut();
-            Ok(f(w, (tid, v)))
-        })
-    }
+///
+/// # Reentrancy
+///
+/// Calling a method of a [`ThreadMapX`] from within a closure passed to a method of the same instance, or while holding a
+/// value returned by [`Self::current`], [`Self::current_mut`], or [`Self::debug_snapshot`], would deadlock or alias a mutable reference. Such reentrant calls are
+/// detected: fallible methods return [`ThreadMapLockError::Reentrant`](crate::ThreadMapLockError::Reentrant), and the other methods panic.
+/// Calling methods of a different instance is fine.
+///
+/// # Thread Safety
+///
+/// `ThreadMapX<V>` is [`Send`] and [`Sync`] if and only if `V` is [`Send`], the same contract as [`Mutex`](std::sync::Mutex).
+/// Values are accessed from threads other than their own (by methods such as [`Self::fold`] and
+/// [`Self::with_thread`], and when `self` is dropped) only with exclusive access, so `V` need not be [`Sync`]:
+///
+/// ```rust
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<thread_map::ThreadMapX<std::cell::Cell<i32>>>();
+/// ```
+///
+/// A map whose values are not [`Send`] cannot be shared with or sent to other threads:
+///
+/// ```compile_fail
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<thread_map::ThreadMapX<std::rc::Rc<i32>>>();
+/// ```
+///
+/// ```compile_fail
+/// use std::{rc::Rc, thread};
+/// use thread_map::ThreadMapX;
+///
+/// let tm = ThreadMapX::new(|| Rc::new(0));
+/// thread::scope(|s| {
+///     s.spawn(|| tm.with(|v| **v));
+/// });
+/// ```
+///
+/// ```compile_fail
+/// fn assert_send<T: Send>() {}
+/// assert_send::<thread_map::ThreadMapX<std::rc::Rc<i32>>>();
+/// ```
+///
+/// but can still be used on the current thread:
+///
+/// ```rust
+/// use std::rc::Rc;
+/// use thread_map::ThreadMapX;
+///
+/// let tm = ThreadMapX::new(|| Rc::new(0));
+/// tm.set(Rc::new(1));
+/// assert_eq!(1, tm.with(|v| **v));
+/// assert_eq!(1, tm.fold_values(0, |z, v| z + **v).unwrap());
+/// ```
+///
+/// # Hashing
+///
+/// As with [`ThreadMap`](crate::ThreadMap), the hasher of the internal [`HashMap`](std::collections::HashMap) is built by `S`, [`RandomState`]
+/// by default, and [`ThreadIdBuildHasher`](crate::ThreadIdBuildHasher) can be used instead with [`Self::with_hasher`].
+///
+/// # Locks
+///
+/// The object-level lock is a [`lock_api::RwLock`] whose raw lock is `R`, [`DefaultRawRwLock`] by default, and the
+/// per-thread locks are [`lock_api::Mutex`]es whose raw lock is `M`, [`DefaultRawMutex`] by default. Any other
+/// implementations of [`lock_api::RawRwLock`] and [`lock_api::RawMutex`] can be used instead with
+/// [`Self::with_raw_locks`].
+pub type ThreadMapX<V, S = RandomState, R = DefaultRawRwLock, M = DefaultRawMutex> =
+    ThreadMapBase<V, MutexSlots, S, R, M>;
 
-    /// Folds every value in `self` into an accumulator (with initial value `z`) by applying an operation `f`,
-    /// returning the final result.
-    ///
-    /// # Errors
-    /// - [`ThreadMapLockError`] if the internal lock is poisoned.
-    pub fn fold_values<W>(
-        &self,
-        z: W,
-        mut f: impl FnMut(W, &V) -> W,
-    ) -> Result<W, ThreadMapLockError> {
-        self.fold(z, |w, (_, v)| f(w, v))
-    }
-
-    /// Returns a [`HashMap`] with clones of the values associated with each [`ThreadId`] key at the time the probe
-    /// was executed.
-    ///
-    /// # Errors
-    /// - [`ThreadMapLockError`] if the internal lock is poisoned.
-    pub fn probe(&self) -> Result<HashMap<ThreadId, V>, ThreadMapLockError>
-    where
-        V: Clone,
-    {
-        let z = HashMap::<ThreadId, V>::new();
-        self.fold(z, |mut w, (tid, v)| {
-            w.insert(tid, v.clone());
-            w
-        })
-    }
-}
+/// Guard returned by [`ThreadMapX::current`] that dereferences to the value associated with the current thread.
+/// It holds the current thread's lock until dropped. It is not [`Send`], so it cannot leave the current thread:
+///
+/// ```compile_fail
+/// use std::thread;
+/// use thread_map::ThreadMapX;
+///
+/// let tm = ThreadMapX::new(|| 0);
+/// let guard = tm.current();
+/// thread::scope(|s| {
+///     s.spawn(move || *guard);
+/// });
+/// ```
+pub type ThreadMapXRef<'a, V, S = RandomState, R = DefaultRawRwLock, M = DefaultRawMutex> =
+    ThreadMapBaseRef<'a, V, MutexSlots, S, R, M>;
 
-impl<V: Default> Default for ThreadMapX<V> {
-    fn default() -> Self {
-        Self::new(V::default)
-    }
-}
+/// Guard returned by [`ThreadMapX::current_mut`] that dereferences mutably to the value associated with the current
+/// thread. It holds the current thread's lock until dropped. It is not [`Send`], so it cannot leave the current
+/// thread:
+///
+/// ```compile_fail
+/// use std::thread;
+/// use thread_map::ThreadMapX;
+///
+/// let tm = ThreadMapX::new(|| 0);
+/// let mut guard = tm.current_mut();
+/// thread::scope(|s| {
+///     s.spawn(move || *guard += 1);
+/// });
+/// ```
+pub type ThreadMapXRefMut<'a, V, S = RandomState, R = DefaultRawRwLock, M = DefaultRawMutex> =
+    ThreadMapBaseRefMut<'a, V, MutexSlots, S, R, M>;
 
 #[allow(clippy::unwrap_used)]
 #[cfg(test)]
 mod test {
     use super::ThreadMapX;
+    use crate::{
+        ThreadIdBuildHasher, ThreadMapLockError, ValueKey,
+        raw_lock::test::{CountingRawMutex, CountingRawRwLock, MUTEX_LOCKS, RW_LOCKS},
+    };
     use std::{
         collections::HashMap,
+        hash::RandomState,
+        panic::{AssertUnwindSafe, catch_unwind},
+        sync::{Arc, Barrier, Mutex, atomic::Ordering},
         thread::{self},
         time::Duration,
     };
@@ -248,4 +327,652 @@ mod test {
         let sum = tm.fold_values(0, |z, v| z + v).unwrap();
         assert_eq!(expected_sum, sum);
     }
+
+    #[test]
+    fn test_with_hasher() {
+        let tm = Arc::new(
+            ThreadMapX::with_hasher(|| 0, ThreadIdBuildHasher::default())
+                .retire_on_thread_exit(|acc, v| *acc += v),
+        );
+
+        let handles = (0..NTHREADS)
+            .map(|i| {
+                let tm = tm.clone();
+                thread::spawn(move || tm.set(i))
+            })
+            .collect::<Vec<_>>();
+        for h in handles {
+            h.join().unwrap();
+        }
+        tm.set(NTHREADS);
+
+        let retired_sum = (0..NTHREADS).sum::<i32>();
+        assert_eq!(Some(retired_sum), tm.retired().unwrap());
+        assert_eq!(
+            retired_sum + NTHREADS,
+            tm.fold_values(0, |z, v| z + v).unwrap()
+        );
+        assert_eq!(
+            HashMap::from([(thread::current().id(), NTHREADS)]),
+            tm.drain().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_custom_raw_lock() {
+        let init = Arc::new(1);
+        let tm: ThreadMapX<i32, RandomState, CountingRawRwLock, CountingRawMutex> =
+            ThreadMapX::with_raw_locks(move || *init, RandomState::new());
+
+        thread::scope(|s| {
+            let tm = &tm;
+            for i in 0..NTHREADS {
+                s.spawn(move || tm.with_mut(|v| *v += i));
+            }
+        });
+
+        let expected_sum = (0..NTHREADS).sum::<i32>() + NTHREADS;
+        assert_eq!(expected_sum, tm.fold_values(0, |z, v| z + v).unwrap());
+        assert!(RW_LOCKS.load(Ordering::Relaxed) > 0);
+        assert!(MUTEX_LOCKS.load(Ordering::Relaxed) > 0);
+    }
+
+    #[test]
+    fn test_from_fn() {
+        let capacity = Arc::new(8usize);
+        let tm: ThreadMapX<Vec<i32>> = {
+            let capacity = capacity.clone();
+            ThreadMapX::from_fn(move || Vec::with_capacity(*capacity))
+        };
+
+        thread::scope(|s| {
+            let tm = &tm;
+            let capacity = *capacity;
+            for i in 0..NTHREADS {
+                s.spawn(move || {
+                    tm.with_mut(|v| v.push(i));
+                    tm.with(|v| assert!(v.capacity() >= capacity));
+                });
+            }
+        });
+
+        let expected_sum = (0..NTHREADS).sum::<i32>();
+        let sum = tm.fold_values(0, |z, v| z + v.iter().sum::<i32>()).unwrap();
+        assert_eq!(expected_sum, sum);
+    }
+
+    #[test]
+    fn test_from_thread_fn() {
+        let tm: ThreadMapX<(String, usize)> = ThreadMapX::from_thread_fn(|info| {
+            let name = info.thread().name().unwrap_or_default().to_owned();
+            (name, info.index())
+        });
+
+        thread::scope(|s| {
+            let tm = &tm;
+            for i in 0..NTHREADS {
+                thread::Builder::new()
+                    .name(format!("worker-{i}"))
+                    .spawn_scoped(s, move || {
+                        let (name, _) = tm.get();
+                        assert_eq!(format!("worker-{i}"), name);
+                    })
+                    .unwrap();
+            }
+        });
+
+        let mut indices = tm
+            .fold_values(Vec::new(), |mut z, (_, index)| {
+                z.push(*index);
+                z
+            })
+            .unwrap();
+        indices.sort();
+        assert_eq!((0..NTHREADS as usize).collect::<Vec<_>>(), indices);
+    }
+
+    #[cfg(not(feature = "parking_lot"))]
+    #[test]
+    fn test_try_poisoned() {
+        let tm: ThreadMapX<i32> = ThreadMapX::default();
+
+        tm.try_set(1).unwrap();
+        assert_eq!(1, tm.try_get().unwrap());
+        assert_eq!(2, tm.try_with(|v| v + 1).unwrap());
+
+        let res = catch_unwind(AssertUnwindSafe(|| {
+            tm.fold_values(0, |_, _| panic!("poisoning"))
+        }));
+        assert!(res.is_err());
+
+        let err = ThreadMapLockError::PoisonedThreadLock(thread::current().id());
+        assert_eq!(Err(err), tm.try_with_mut(|v| *v += 1));
+        assert_eq!(Err(err), tm.try_with(|v| *v));
+        assert_eq!(Err(err), tm.try_get());
+        assert_eq!(Err(err), tm.try_set(2));
+        assert_eq!(
+            format!("poisoned thread lock for {:?}", thread::current().id()),
+            err.to_string()
+        );
+    }
+
+    #[cfg(feature = "parking_lot")]
+    #[test]
+    fn test_no_poisoning() {
+        let tm: ThreadMapX<i32> = ThreadMapX::default();
+        tm.set(1);
+
+        let res = catch_unwind(AssertUnwindSafe(|| tm.with(|_| panic!("panicking"))));
+        assert!(res.is_err());
+        let res = catch_unwind(AssertUnwindSafe(|| {
+            tm.fold_values(0, |_, _| panic!("panicking"))
+        }));
+        assert!(res.is_err());
+
+        assert!(!tm.is_poisoned().unwrap());
+        assert_eq!(1, tm.try_get().unwrap());
+        assert_eq!(1, tm.fold_values(0, |z, v| z + v).unwrap());
+    }
+
+    #[test]
+    fn test_lock_wait() {
+        let tm: ThreadMapX<i32> = ThreadMapX::default();
+        tm.set(1);
+
+        let locked = Barrier::new(2);
+        let release = Barrier::new(2);
+        thread::scope(|s| {
+            s.spawn(|| {
+                // The sweep holds the lock of the main thread's value while it visits the value.
+                tm.fold_values(0, |z, v| {
+                    locked.wait();
+                    release.wait();
+                    z + v
+                })
+            });
+            locked.wait();
+            assert_eq!(
+                Err(ThreadMapLockError::WouldBlock),
+                tm.try_with_nonblocking(|v| *v)
+            );
+            assert_eq!(
+                Err(ThreadMapLockError::Timeout),
+                tm.try_with_mut_timeout(Duration::from_millis(10), |v| *v += 1)
+            );
+            release.wait();
+        });
+
+        assert_eq!(Ok(1), tm.try_with_mut_nonblocking(|v| *v));
+        assert_eq!(
+            Ok(2),
+            tm.try_with_timeout(Duration::from_secs(60), |v| *v + 1)
+        );
+    }
+
+    #[cfg(not(feature = "parking_lot"))]
+    #[test]
+    fn test_poison_recovery() {
+        let tm: ThreadMapX<i32> = ThreadMapX::default();
+
+        thread::scope(|s| {
+            let tm = &tm;
+            for i in 0..NTHREADS {
+                s.spawn(move || tm.set(i));
+            }
+        });
+        tm.set(NTHREADS);
+        assert!(!tm.is_poisoned().unwrap());
+
+        let res = catch_unwind(AssertUnwindSafe(|| {
+            tm.fold_values(0, |_, _| panic!("poisoning"))
+        }));
+        assert!(res.is_err());
+        assert!(tm.is_poisoned().unwrap());
+        assert!(tm.drain().is_err());
+
+        let expected_sum = (0..=NTHREADS).sum::<i32>();
+        let drained = tm.drain_poisoned().unwrap();
+        assert_eq!(expected_sum, drained.values().sum::<i32>());
+        // The poisoned per-thread lock was removed with the drained values.
+        assert!(!tm.is_poisoned().unwrap());
+
+        tm.clear_poison().unwrap();
+        assert!(!tm.is_poisoned().unwrap());
+        assert_eq!(0, tm.get());
+        assert_eq!(0, tm.fold_values(0, |z, v| z + v).unwrap());
+    }
+
+    #[cfg(not(feature = "parking_lot"))]
+    #[test]
+    fn test_retired_poison_recovery() {
+        let tm: Arc<ThreadMapX<i32>> =
+            Arc::new(ThreadMapX::default().retire_on_thread_exit(|acc, v| *acc += v));
+
+        let handles = (0..NTHREADS)
+            .map(|i| {
+                let tm = tm.clone();
+                thread::spawn(move || tm.set(i))
+            })
+            .collect::<Vec<_>>();
+        for h in handles {
+            h.join().unwrap();
+        }
+        tm.set(NTHREADS);
+
+        tm.poison_retired();
+        assert!(tm.is_poisoned().unwrap());
+        assert_eq!(
+            Err(ThreadMapLockError::PoisonedRetiredLock),
+            tm.take_retired()
+        );
+
+        // The accumulator is drained together with the values.
+        let retired_sum = (0..NTHREADS).sum::<i32>();
+        assert_eq!(
+            HashMap::from([
+                (ValueKey::Thread(thread::current().id()), NTHREADS),
+                (ValueKey::Retired, retired_sum),
+            ]),
+            tm.drain_poisoned().unwrap()
+        );
+
+        tm.clear_poison().unwrap();
+        assert!(!tm.is_poisoned().unwrap());
+        assert_eq!(None, tm.take_retired().unwrap());
+        assert_eq!(0, tm.fold_values(0, |z, v| z + v).unwrap());
+    }
+
+    #[test]
+    fn test_take_replace_remove_reset() {
+        let tm: ThreadMapX<i32> = ThreadMapX::from_thread_fn(|info| info.index() as i32 * 100);
+
+        thread::scope(|s| {
+            let tm = &tm;
+            for i in 0..NTHREADS {
+                s.spawn(move || {
+                    assert_eq!(None, tm.take());
+                    tm.set(i);
+                    assert_eq!(i, tm.replace(i + 1));
+                    assert_eq!(Some(i + 1), tm.take());
+                    assert_eq!(None, tm.take());
+
+                    tm.set(i);
+                    tm.remove();
+                    assert_eq!(None, tm.take());
+
+                    tm.set(i);
+                    tm.reset();
+                    assert_ne!(i, tm.get());
+                    assert_eq!(0, tm.get() % 100);
+                    tm.remove();
+                });
+            }
+        });
+
+        assert!(tm.drain().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_remove_on_thread_exit() {
+        let tm: Arc<ThreadMapX<i32>> = Arc::new(ThreadMapX::default().remove_on_thread_exit());
+
+        let handles = (0..NTHREADS)
+            .map(|i| {
+                let tm = tm.clone();
+                thread::spawn(move || tm.set(i))
+            })
+            .collect::<Vec<_>>();
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        tm.set(NTHREADS);
+        let probed = tm.probe().unwrap();
+        assert_eq!(
+            HashMap::from([(ValueKey::Thread(thread::current().id()), NTHREADS)]),
+            probed
+        );
+    }
+
+    #[test]
+    fn test_on_thread_exit() {
+        let exited = Arc::new(Mutex::new(HashMap::new()));
+        let tm: Arc<ThreadMapX<i32>> = {
+            let exited = exited.clone();
+            Arc::new(ThreadMapX::default().on_thread_exit(move |tid, v| {
+                exited.lock().unwrap().insert(tid, v);
+            }))
+        };
+
+        let handles = (0..NTHREADS)
+            .map(|i| {
+                let tm = tm.clone();
+                thread::spawn(move || {
+                    // Re-registration after `take` must not cause the value to be reported twice.
+                    tm.set(i);
+                    tm.take();
+                    tm.set(i);
+                })
+            })
+            .collect::<Vec<_>>();
+        let tids = handles
+            .into_iter()
+            .map(|h| {
+                let tid = h.thread().id();
+                h.join().unwrap();
+                tid
+            })
+            .collect::<Vec<_>>();
+
+        assert!(tm.drain().unwrap().is_empty());
+        let exited = exited.lock().unwrap();
+        assert_eq!(NTHREADS as usize, exited.len());
+        for (i, tid) in tids.iter().enumerate() {
+            assert_eq!(Some(&(i as i32)), exited.get(tid));
+        }
+    }
+
+    #[test]
+    fn test_retire_on_thread_exit() {
+        let tm: Arc<ThreadMapX<i32>> =
+            Arc::new(ThreadMapX::default().retire_on_thread_exit(|acc, v| *acc += v));
+
+        let handles = (0..NTHREADS)
+            .map(|i| {
+                let tm = tm.clone();
+                thread::spawn(move || tm.set(i))
+            })
+            .collect::<Vec<_>>();
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        tm.set(NTHREADS);
+        let retired_sum = (0..NTHREADS).sum::<i32>();
+        assert_eq!(Some(retired_sum), tm.retired().unwrap());
+        let expected = HashMap::from([
+            (ValueKey::Thread(thread::current().id()), NTHREADS),
+            (ValueKey::Retired, retired_sum),
+        ]);
+        assert_eq!(expected, tm.probe().unwrap());
+        let folded = tm
+            .fold(HashMap::new(), |mut z, (key, v)| {
+                *z.entry(key).or_insert(0) += v;
+                z
+            })
+            .unwrap();
+        assert_eq!(expected, folded);
+        assert_eq!(
+            retired_sum + NTHREADS,
+            tm.fold_values(0, |z, v| z + v).unwrap()
+        );
+
+        assert_eq!(Some(retired_sum), tm.take_retired().unwrap());
+        assert_eq!(
+            HashMap::from([(ValueKey::Thread(thread::current().id()), NTHREADS)]),
+            tm.probe().unwrap()
+        );
+        assert_eq!(None, tm.retired().unwrap());
+        assert_eq!(NTHREADS, tm.fold_values(0, |z, v| z + v).unwrap());
+    }
+
+    #[test]
+    fn test_with_thread() {
+        let tm: ThreadMapX<i32> = ThreadMapX::default();
+        let barrier = Barrier::new(2);
+
+        thread::scope(|s| {
+            let tm = &tm;
+            let barrier = &barrier;
+            let worker = s.spawn(move || {
+                tm.set(1);
+                barrier.wait(); // value set
+                barrier.wait(); // value inspected and reset by supervisor
+                tm.get()
+            });
+            let tid = worker.thread().id();
+
+            barrier.wait();
+            assert!(tm.contains(tid).unwrap());
+            assert!(!tm.contains(thread::current().id()).unwrap());
+            assert_eq!(Some(1), tm.with_thread(tid, |v| *v).unwrap());
+            assert_eq!(Some(()), tm.with_thread_mut(tid, |v| *v = 42).unwrap());
+            assert_eq!(
+                None,
+                tm.with_thread(thread::current().id(), |v| *v).unwrap()
+            );
+            barrier.wait();
+
+            assert_eq!(42, worker.join().unwrap());
+        });
+    }
+
+    #[test]
+    fn test_debug() {
+        let tm: ThreadMapX<i32> = ThreadMapX::default();
+        tm.set(42);
+        let tid = thread::current().id();
+
+        let out = format!("{:?}", tm.debug_snapshot().unwrap());
+        assert_eq!(format!("{{{tid:?}: 42}}"), out);
+    }
+
+    #[test]
+    fn test_reentrancy() {
+        fn is_reentrant<T>(res: Result<T, ThreadMapLockError>) -> bool {
+            matches!(res, Err(ThreadMapLockError::Reentrant))
+        }
+
+        let tm: ThreadMapX<i32> = ThreadMapX::default();
+        let other: ThreadMapX<i32> = ThreadMapX::default();
+
+        // Per-thread methods within per-thread methods, first on a new and then on an existing value.
+        assert!(is_reentrant(tm.with_mut(|_| tm.try_with_mut(|v| *v += 1))));
+        assert!(is_reentrant(tm.with_mut(|_| tm.try_with_mut(|v| *v += 1))));
+        assert!(is_reentrant(tm.with(|_| tm.try_get())));
+        assert!(is_reentrant(tm.with_mut(|_| tm.try_set(1))));
+        assert!(is_reentrant(tm.with(|_| tm.try_take())));
+
+        // Sweeps and per-thread methods within each other.
+        assert!(is_reentrant(tm.with(|_| tm.fold_values(0, |z, v| z + v))));
+        assert!(is_reentrant(tm.with(|_| tm.drain())));
+        assert!(is_reentrant(
+            tm.with(|_| tm.contains(thread::current().id()))
+        ));
+        assert!(is_reentrant(
+            tm.fold_values(Ok(()), |_, _| tm.try_set(1)).unwrap()
+        ));
+        assert!(is_reentrant(
+            tm.fold(Ok(None), |_, _| tm
+                .with_thread(thread::current().id(), |v| *v))
+                .unwrap()
+        ));
+        assert!(is_reentrant(
+            tm.fold_values(Ok(0), |_, _| tm.fold_values(0, |z, v| z + v))
+                .unwrap()
+        ));
+        assert!(is_reentrant(
+            tm.with_thread_mut(thread::current().id(), |_| tm.probe())
+                .unwrap()
+                .unwrap()
+        ));
+
+        // Methods while a debug snapshot is alive.
+        let snapshot = tm.debug_snapshot().unwrap();
+        assert!(is_reentrant(tm.try_get()));
+        assert!(is_reentrant(tm.debug_snapshot().map(|_| ())));
+        drop(snapshot);
+
+        // Nesting with a different instance is fine.
+        assert_eq!(
+            1,
+            tm.with(|_| other.with_mut(|v| {
+                *v += 1;
+                *v
+            }))
+        );
+
+        // The instance is still usable.
+        assert!(!tm.is_poisoned().unwrap());
+        assert_eq!(0, tm.get());
+        tm.set(2);
+        assert_eq!(2, tm.fold_values(0, |z, v| z + v).unwrap());
+
+        // Panicking methods.
+        let res = catch_unwind(AssertUnwindSafe(|| tm.with(|_| tm.get())));
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_first_access_not_under_write_lock() {
+        let barrier = Arc::new(Barrier::new(2));
+        let tm: ThreadMapX<i32> = {
+            let barrier = barrier.clone();
+            ThreadMapX::from_fn(move || {
+                barrier.wait(); // initializer running
+                barrier.wait(); // other thread done
+                1
+            })
+        };
+
+        thread::scope(|s| {
+            let tm = &tm;
+            let barrier = &barrier;
+            s.spawn(move || tm.with_mut(|v| *v += 1));
+
+            barrier.wait();
+            // Would deadlock if the initializer ran under the object-level write lock.
+            assert_eq!(0, tm.fold_values(0, |z, v| z + v).unwrap());
+            barrier.wait();
+        });
+
+        assert_eq!(2, tm.fold_values(0, |z, v| z + v).unwrap());
+    }
+
+    #[test]
+    fn test_closure_not_under_object_lock() {
+        let tm: ThreadMapX<i32> = ThreadMapX::default();
+        let barrier = Barrier::new(2);
+
+        thread::scope(|s| {
+            let tm = &tm;
+            let barrier = &barrier;
+            let worker = s.spawn(move || {
+                tm.with_mut(|v| {
+                    barrier.wait(); // closure running
+                    barrier.wait(); // other threads registered and drain started
+                    *v += 1;
+                })
+            });
+            let tid = worker.thread().id();
+
+            barrier.wait();
+            // Would deadlock if the worker's closure held the object-level read lock.
+            tm.set(1);
+            assert_eq!(Some(1), tm.take());
+            let drainer = s.spawn(move || tm.drain().unwrap());
+            while tm.contains(tid).unwrap() {
+                thread::yield_now();
+            }
+            barrier.wait();
+
+            assert_eq!(HashMap::from([(tid, 1)]), drainer.join().unwrap());
+        });
+
+        assert!(tm.drain().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_sweep_not_under_object_lock() {
+        let tm: ThreadMapX<i32> = ThreadMapX::default();
+        tm.set(10);
+        let barrier = Barrier::new(2);
+
+        thread::scope(|s| {
+            let tm = &tm;
+            let barrier = &barrier;
+            s.spawn(move || {
+                tm.with_mut(|v| {
+                    barrier.wait(); // closure running
+                    barrier.wait(); // other thread registered
+                    *v += 1;
+                })
+            });
+
+            barrier.wait();
+            let sweeper = s.spawn(move || tm.fold_values(0, |z, v| z + v).unwrap());
+            thread::sleep(Duration::from_millis(10));
+            // Would deadlock if the sweep held the object-level read lock while waiting for the worker's lock.
+            s.spawn(move || tm.set(100)).join().unwrap();
+            barrier.wait();
+
+            // The value registered after the sweep started is included only if the sweeper started late.
+            assert!([11, 111].contains(&sweeper.join().unwrap()));
+        });
+
+        assert_eq!(111, tm.fold_values(0, |z, v| z + v).unwrap());
+    }
+
+    #[test]
+    fn test_slot_cache() {
+        // More maps than the cache holds, accessed alternately.
+        let tms = (0..NTHREADS)
+            .map(|_| ThreadMapX::default())
+            .collect::<Vec<_>>();
+        for _ in 0..NITER {
+            for (i, tm) in tms.iter().enumerate() {
+                tm.with_mut(|v: &mut i32| *v += i as i32);
+            }
+        }
+        for (i, tm) in tms.iter().enumerate() {
+            assert_eq!(i as i32 * NITER, tm.get());
+        }
+
+        // Cached locations of values removed by other threads, or by the current thread, are not used.
+        let tm = &tms[1];
+        thread::scope(|s| {
+            s.spawn(|| tm.drain().unwrap());
+        });
+        assert_eq!(0, tm.get());
+        tm.set(2);
+        assert_eq!(Some(2), tm.take());
+        assert_eq!(0, tm.get());
+    }
+
+    #[test]
+    fn test_current() {
+        fn add(tm: &ThreadMapX<i32>, i: i32) -> Result<i32, ThreadMapLockError> {
+            let mut v = tm.try_current_mut()?;
+            *v += i;
+            Ok(*v)
+        }
+
+        let tm: ThreadMapX<i32> = ThreadMapX::default();
+
+        thread::scope(|s| {
+            let tm = &tm;
+            for i in 0..NTHREADS {
+                s.spawn(move || {
+                    for _ in 0..NITER {
+                        add(tm, i).unwrap();
+                    }
+                    assert_eq!(i * NITER, *tm.current());
+                });
+            }
+        });
+
+        let expected_sum = (0..NTHREADS).map(|i| i * NITER).sum::<i32>();
+        assert_eq!(expected_sum, tm.fold_values(0, |z, v| z + v).unwrap());
+
+        // Other methods of the same instance are reentrant while a guard is alive.
+        let guard = tm.current();
+        assert_eq!("0", format!("{guard:?}"));
+        assert_eq!(Err(ThreadMapLockError::Reentrant), tm.try_get());
+        assert!(matches!(
+            tm.try_current_mut(),
+            Err(ThreadMapLockError::Reentrant)
+        ));
+        drop(guard);
+        assert_eq!(1, add(&tm, 1).unwrap());
+    }
 }
@@ -1,4 +1,6 @@
 #!/bin/bash
 
+cargo nextest run --lib --bins --examples --tests
 cargo nextest run --lib --bins --examples --tests --all-features
+cargo test --doc
 cargo test --doc --all-features